@@ -1,30 +1,76 @@
-use std::{fs, path::PathBuf, thread};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    thread,
+    time::Duration,
+};
 
 use crate::{
-    control::{DownloadStatus, RunningStatus},
-    helper,
-    settings::Subscription,
+    control::{self, ClashError, ClashErrorKind, DownloadStatus, StartEvent, StartStage},
+    controller::{self, DelayResult},
+    helper, logger,
+    profile::ImportMode,
+    settings::{self, Settings, Subscription},
 };
 
 use super::control::ControlRuntime;
 
 use rand::{distributions::Alphanumeric, Rng};
+use serde::Serialize;
 use usdpl_back::core::serdes::Primitive;
 
 pub const VERSION: &'static str = env!("CARGO_PKG_VERSION");
 pub const NAME: &'static str = env!("CARGO_PKG_NAME");
 
+/// 返回给前端的错误，`kind` 为 `ClashErrorKind` 的 snake_case 名称
+#[derive(Debug, Serialize)]
+pub struct ApiError {
+    pub kind: ClashErrorKind,
+    pub message: String,
+}
+
+impl From<&ClashError> for ApiError {
+    fn from(e: &ClashError) -> Self {
+        Self {
+            kind: e.kind,
+            message: e.message.clone(),
+        }
+    }
+}
+
+/// 把错误编码为 json 字符串返回
+fn error_primitive(e: &ClashError) -> Primitive {
+    match serde_json::to_string(&ApiError::from(e)) {
+        Ok(x) => x.into(),
+        Err(e) => {
+            log::error!("Error while serializing data structures");
+            log::error!("Error message: {}", e);
+            String::new().into()
+        }
+    }
+}
+
+fn missing_param(message: &str) -> Primitive {
+    error_primitive(&ClashError::new(ClashErrorKind::Default, message))
+}
+
+// 校验订阅时的超时时间（秒），保证前端能及时得到结果
+const PROBE_TIMEOUT: u64 = 5;
+// get_clash_logs 默认与最多返回的行数
+const CLASH_LOG_DEFAULT_LINES: usize = 200;
+const CLASH_LOG_MAX_LINES: usize = 2000;
+// 只读取日志末尾的这部分内容
+const CLASH_LOG_MAX_BYTES: u64 = 512 * 1024;
+
+/// 返回 [是否运行, 意外退出的信息]，后者为 json 编码的 `CoreCrash`，没有时为 `null`
 pub fn get_clash_status(runtime: &ControlRuntime) -> impl Fn(Vec<Primitive>) -> Vec<Primitive> {
     let runtime_settings = runtime.settings_clone();
+    let core_crash = runtime.core_crash_clone();
     move |_| {
-        let mut lock = match runtime_settings.write() {
-            Ok(x) => x,
-            Err(e) => {
-                log::error!("get_enable failed to acquire settings read lock: {}", e);
-                return vec![];
-            }
-        };
+        let mut lock = control::write_lock(&runtime_settings, "settings");
         let is_clash_running = helper::is_clash_running();
+        let crash = serde_json::to_string(&*control::read_lock(&core_crash, "core_crash"))
+            .unwrap_or_else(|_| "null".to_string());
         if !is_clash_running && lock.enable
         //Clash 不在后台但设置里却表示打开
         {
@@ -32,95 +78,458 @@ pub fn get_clash_status(runtime: &ControlRuntime) -> impl Fn(Vec<Primitive>) ->
             log::debug!(
                 "Error occurred while Clash is not running in background but settings defined running."
             );
-            return vec![is_clash_running.into()];
+            return vec![is_clash_running.into(), crash.into()];
         }
         log::debug!("get_enable() success");
         log::info!("get clash status with {}", is_clash_running);
-        vec![is_clash_running.into()]
+        vec![is_clash_running.into(), crash.into()]
     }
 }
 
-pub fn set_clash_status(runtime: &ControlRuntime) -> impl Fn(Vec<Primitive>) -> Vec<Primitive> {
+/// 返回 json 编码的全部设置
+pub fn get_settings(runtime: &ControlRuntime) -> impl Fn(Vec<Primitive>) -> Vec<Primitive> {
     let runtime_settings = runtime.settings_clone();
-    let runtime_state = runtime.state_clone();
-    let clash = runtime.clash_state_clone();
-    let running_status = runtime.running_status_clone();
+    move |_| {
+        let settings = serde_json::to_string(&*control::read_lock(&runtime_settings, "settings"));
+        match settings {
+            Ok(x) => vec![x.into()],
+            Err(e) => {
+                log::error!("Error while serializing data structures");
+                log::error!("Error message: {}", e);
+                vec![]
+            }
+        }
+    }
+}
+
+/// 返回 json 编码的设置字段描述，供通用的设置界面使用
+pub fn get_settings_schema() -> impl Fn(Vec<Primitive>) -> Vec<Primitive> {
+    |_| match serde_json::to_string(&settings::settings_schema()) {
+        Ok(x) => vec![x.into()],
+        Err(e) => {
+            log::error!("Error occurred while serializing settings schema: {}", e);
+            vec![]
+        }
+    }
+}
+
+/// 参数为 json 编码的全部设置，检查通过后替换并保存
+pub fn update_settings(runtime: &ControlRuntime) -> impl Fn(Vec<Primitive>) -> Vec<Primitive> {
+    let runtime = runtime.clone();
+    move |params| {
+        let settings = match params.first() {
+            Some(Primitive::Json(x)) | Some(Primitive::String(x)) => {
+                serde_json::from_str::<Settings>(x)
+            }
+            _ => {
+                log::error!("update_settings: missing settings");
+                return vec![false.into(), missing_param("missing settings")];
+            }
+        };
+        let settings = match settings {
+            Ok(x) => x,
+            Err(e) => {
+                log::error!("update_settings: invalid settings: {}", e);
+                return vec![
+                    false.into(),
+                    error_primitive(&ClashError::new(
+                        ClashErrorKind::ConfigFormatError,
+                        e.to_string(),
+                    )),
+                ];
+            }
+        };
+        match runtime.update_settings(settings) {
+            Ok(_) => vec![true.into()],
+            Err(e) => {
+                log::error!("update_settings failed: {}", e);
+                vec![false.into(), error_primitive(&e)]
+            }
+        }
+    }
+}
+
+/// 返回 NotRunning / Unresponsive / Healthy
+pub fn get_clash_health(runtime: &ControlRuntime) -> impl Fn(Vec<Primitive>) -> Vec<Primitive> {
+    let runtime = runtime.clone();
+    move |_| vec![runtime.clash_health().to_string().into()]
+}
+
+#[derive(Serialize)]
+struct VersionInfo {
+    /// 获取失败时为 `None`
+    core: Option<String>,
+    backend: &'static str,
+}
+
+/// 同时返回核心和后端的版本
+pub fn get_core_version(runtime: &ControlRuntime) -> impl Fn(Vec<Primitive>) -> Vec<Primitive> {
+    let runtime = runtime.clone();
+    move |_| {
+        let core = match runtime.core_version() {
+            Ok(x) => Some(x),
+            Err(e) => {
+                log::error!("get_core_version failed: {}", e);
+                None
+            }
+        };
+        let info = VersionInfo {
+            core,
+            backend: VERSION,
+        };
+        match serde_json::to_string(&info) {
+            Ok(x) => vec![x.into()],
+            Err(e) => {
+                log::error!("Error while serializing data structures");
+                log::error!("Error message: {}", e);
+                vec![]
+            }
+        }
+    }
+}
+
+/// 根据 external-controller 与 secret 生成面板地址
+pub fn get_dashboard_url(runtime: &ControlRuntime) -> impl Fn(Vec<Primitive>) -> Vec<Primitive> {
+    let runtime = runtime.clone();
+    move |_| vec![runtime.controller().dashboard_url().into()]
+}
+
+/// 启动在后台进行，立即返回，启动结果通过 `get_running_status` 查询；关闭仍然同步完成
+pub fn set_clash_status(runtime: &ControlRuntime) -> impl Fn(Vec<Primitive>) -> Vec<Primitive> {
+    let runtime = runtime.clone();
     move |params| {
         if let Some(Primitive::Bool(enabled)) = params.get(0) {
-            let mut settings = match runtime_settings.write() {
-                Ok(x) => x,
-                Err(e) => {
-                    log::error!("set_enable failed to acquire settings write lock: {}", e);
-                    return vec![false.into()];
-                }
-            };
             log::info!("set clash status to {}", enabled);
-            if settings.enable != *enabled {
-                let mut clash = match clash.write() {
-                    Ok(x) => x,
-                    Err(e) => {
-                        log::error!("set_enable failed to acquire state write lock: {}", e);
-                        return vec![false.into()];
-                    }
-                };
-                let mut run_status = match running_status.write() {
-                    Ok(x) => x,
-                    Err(e) => {
-                        log::error!("set_enable failed to acquire run status write lock: {}", e);
-                        return vec![false.into()];
-                    }
-                };
-                *run_status = RunningStatus::Loading;
-                // 有些时候第一次没有选择订阅
-                if settings.current_sub == "" {
-                    log::info!("no profile provided, try to use first profile.");
-                    if let Some(sub) = settings.subscriptions.get(0) {
-                        settings.current_sub = sub.path.clone();
-                    } else {
-                        log::error!("no profile provided.");
-                        *run_status = RunningStatus::Failed;
-                        return vec![false.into()];
+            if *enabled {
+                //第二个参数为 true 时跳过网络检查，用于离线启动
+                let skip_preflight = matches!(params.get(1), Some(Primitive::Bool(true)));
+                if !skip_preflight {
+                    if let Err(e) = runtime.preflight_network_check() {
+                        return vec![false.into(), error_primitive(&e)];
                     }
                 }
-                if *enabled {
-                    match clash.run(&settings.current_sub, settings.skip_proxy) {
-                        Ok(_) => (),
-                        Err(e) => {
-                            log::error!("Run clash error: {}", e);
-                            *run_status = RunningStatus::Failed;
-                            return vec![false.into()];
-                        }
-                    }
-                } else {
-                    // Disable Clash
-                    match clash.stop() {
-                        Ok(_) => {
-                            log::info!("successfully disable clash");
-                        }
-                        Err(e) => {
-                            log::error!("Disable clash error: {}", e);
-                            *run_status = RunningStatus::Failed;
-                            return vec![false.into()];
-                        }
-                    }
+                if !runtime.spawn_start() {
+                    log::info!("Clash is already enabled or starting.");
                 }
-                settings.enable = *enabled;
-                let mut state = match runtime_state.write() {
-                    Ok(x) => x,
-                    Err(e) => {
-                        log::error!("set_enable failed to acquire state write lock: {}", e);
-                        *run_status = RunningStatus::Failed;
-                        return vec![];
+                return vec![true.into()];
+            }
+            match runtime.set_clash_enable(false, &|_| {}) {
+                Ok(_) => vec![false.into()],
+                Err(e) => vec![false.into(), error_primitive(&e)],
+            }
+        } else {
+            vec![false.into(), missing_param("missing enabled")]
+        }
+    }
+}
+
+/// 在后台启动 Clash，返回可用于 `get_start_progress` 查询的操作 id
+pub fn start_clash_verbose(runtime: &ControlRuntime) -> impl Fn(Vec<Primitive>) -> Vec<Primitive> {
+    let runtime = runtime.clone();
+    move |_| {
+        let start_progress = runtime.start_progress_clone();
+        let id = match start_progress.write() {
+            Ok(mut x) => x.begin(),
+            Err(e) => {
+                log::error!(
+                    "start_clash_verbose() failed to acquire progress write lock: {}",
+                    e
+                );
+                return vec![];
+            }
+        };
+        let runtime = runtime.clone();
+        thread::spawn(move || {
+            let report = |event: StartEvent| match start_progress.write() {
+                Ok(mut x) => x.push(id, event),
+                Err(e) => {
+                    log::error!(
+                        "start_clash_verbose() failed to acquire progress write lock: {}",
+                        e
+                    );
+                }
+            };
+            if let Err(e) = runtime.set_clash_enable(true, &report) {
+                report(StartEvent::new(StartStage::Failed, e.message, None));
+                return;
+            }
+            report(StartEvent::new(
+                StartStage::WaitController,
+                "Waiting for controller",
+                None,
+            ));
+            if runtime.controller().wait_ready(Duration::from_secs(10)) {
+                report(StartEvent::new(StartStage::Done, "Clash is running", None));
+            } else {
+                report(StartEvent::new(
+                    StartStage::Failed,
+                    "Clash controller did not respond",
+                    None,
+                ));
+            }
+        });
+        vec![(id as f64).into()]
+    }
+}
+
+pub fn get_start_progress(runtime: &ControlRuntime) -> impl Fn(Vec<Primitive>) -> Vec<Primitive> {
+    let start_progress = runtime.start_progress_clone();
+    move |params| {
+        if let Some(Primitive::F64(id)) = params.first() {
+            match start_progress.read() {
+                Ok(x) => match x.get(*id as u64).map(serde_json::to_string) {
+                    Some(Ok(x)) => vec![x.into()],
+                    Some(Err(e)) => {
+                        log::error!("Error while serializing data structures");
+                        log::error!("Error message: {}", e);
+                        vec![]
                     }
-                };
-                state.dirty = true;
-                *run_status = RunningStatus::Success;
-                drop(run_status);
-                log::debug!("set_enable({}) success", enabled);
+                    None => vec![],
+                },
+                Err(_) => {
+                    log::error!("Error occured while get_start_progress()");
+                    vec![]
+                }
             }
-            vec![(*enabled).into()]
         } else {
-            return vec![false.into()];
+            vec![]
+        }
+    }
+}
+
+/// 重启 Clash，返回重启后 Clash 是否在运行，失败时第二个返回值为 `ApiError`
+pub fn restart_clash(runtime: &ControlRuntime) -> impl Fn(Vec<Primitive>) -> Vec<Primitive> {
+    let runtime = runtime.clone();
+    move |_| match runtime.restart_clash() {
+        Ok(x) => vec![x.into()],
+        Err(e) => {
+            log::error!("Restart clash error: {}", e);
+            vec![false.into(), error_primitive(&e)]
+        }
+    }
+}
+
+/// 切换代理模式，失败时第二个返回值为 json 编码的 `ApiError`
+pub fn set_proxy_mode(runtime: &ControlRuntime) -> impl Fn(Vec<Primitive>) -> Vec<Primitive> {
+    let runtime = runtime.clone();
+    move |params| {
+        let mode = match params.first() {
+            Some(Primitive::String(x)) => x.clone(),
+            _ => {
+                log::error!("set_proxy_mode: missing mode");
+                return vec![false.into(), missing_param("missing mode")];
+            }
+        };
+        match runtime.set_proxy_mode(&mode) {
+            Ok(_) => vec![true.into()],
+            Err(e) => {
+                log::error!("Set proxy mode error: {}", e);
+                vec![false.into(), error_primitive(&e)]
+            }
+        }
+    }
+}
+
+/// 返回 json 编码的代理组，失败时第二个返回值为 json 编码的 `ApiError`
+pub fn get_proxies(runtime: &ControlRuntime) -> impl Fn(Vec<Primitive>) -> Vec<Primitive> {
+    let runtime = runtime.clone();
+    move |_| match runtime.get_proxies() {
+        Ok(groups) => match serde_json::to_string(&groups) {
+            Ok(x) => vec![true.into(), x.into()],
+            Err(e) => {
+                log::error!("Error while serializing data structures");
+                log::error!("Error message: {}", e);
+                vec![
+                    false.into(),
+                    error_primitive(&ClashError::new(ClashErrorKind::InnerError, e.to_string())),
+                ]
+            }
+        },
+        Err(e) => {
+            log::error!("Get proxies error: {}", e);
+            vec![false.into(), error_primitive(&e)]
+        }
+    }
+}
+
+/// 返回 json 编码的 `{up, down}`，单位为字节每秒
+pub fn get_traffic(runtime: &ControlRuntime) -> impl Fn(Vec<Primitive>) -> Vec<Primitive> {
+    let runtime = runtime.clone();
+    move |_| match runtime.get_traffic() {
+        Ok(traffic) => match serde_json::to_string(&traffic) {
+            Ok(x) => vec![true.into(), x.into()],
+            Err(e) => {
+                log::error!("Error while serializing data structures");
+                log::error!("Error message: {}", e);
+                vec![
+                    false.into(),
+                    error_primitive(&ClashError::new(ClashErrorKind::InnerError, e.to_string())),
+                ]
+            }
+        },
+        Err(e) => {
+            log::error!("Get traffic error: {}", e);
+            vec![false.into(), error_primitive(&e)]
+        }
+    }
+}
+
+/// 返回来源订阅、运行配置路径和生成时间
+pub fn get_active_config_info(
+    runtime: &ControlRuntime,
+) -> impl Fn(Vec<Primitive>) -> Vec<Primitive> {
+    let runtime = runtime.clone();
+    move |_| match runtime.active_config_info() {
+        Ok(info) => match serde_json::to_string(&info) {
+            Ok(x) => vec![true.into(), x.into()],
+            Err(e) => {
+                log::error!("Error while serializing data structures");
+                log::error!("Error message: {}", e);
+                vec![
+                    false.into(),
+                    error_primitive(&ClashError::new(ClashErrorKind::InnerError, e.to_string())),
+                ]
+            }
+        },
+        Err(e) => {
+            log::error!("get_active_config_info failed: {}", e);
+            vec![false.into(), error_primitive(&e)]
+        }
+    }
+}
+
+/// 按匹配顺序返回生效中的规则
+pub fn get_rules(runtime: &ControlRuntime) -> impl Fn(Vec<Primitive>) -> Vec<Primitive> {
+    let runtime = runtime.clone();
+    move |_| match runtime.get_rules() {
+        Ok(rules) => match serde_json::to_string(&rules) {
+            Ok(x) => vec![true.into(), x.into()],
+            Err(e) => {
+                log::error!("Error while serializing data structures");
+                log::error!("Error message: {}", e);
+                vec![
+                    false.into(),
+                    error_primitive(&ClashError::new(ClashErrorKind::InnerError, e.to_string())),
+                ]
+            }
+        },
+        Err(e) => {
+            log::error!("get_rules failed: {}", e);
+            vec![false.into(), error_primitive(&e)]
+        }
+    }
+}
+
+/// 返回 json 编码的活动连接列表
+pub fn get_connections(runtime: &ControlRuntime) -> impl Fn(Vec<Primitive>) -> Vec<Primitive> {
+    let runtime = runtime.clone();
+    move |_| match runtime.get_connections() {
+        Ok(connections) => match serde_json::to_string(&connections) {
+            Ok(x) => vec![true.into(), x.into()],
+            Err(e) => {
+                log::error!("Error while serializing data structures");
+                log::error!("Error message: {}", e);
+                vec![
+                    false.into(),
+                    error_primitive(&ClashError::new(ClashErrorKind::InnerError, e.to_string())),
+                ]
+            }
+        },
+        Err(e) => {
+            log::error!("Get connections error: {}", e);
+            vec![false.into(), error_primitive(&e)]
+        }
+    }
+}
+
+/// 关闭连接，参数为连接 id
+pub fn close_connection(runtime: &ControlRuntime) -> impl Fn(Vec<Primitive>) -> Vec<Primitive> {
+    let runtime = runtime.clone();
+    move |params| {
+        let id = match params.first() {
+            Some(Primitive::String(x)) => x.clone(),
+            _ => {
+                log::error!("close_connection: missing id");
+                return vec![false.into(), missing_param("missing id")];
+            }
+        };
+        match runtime.close_connection(&id) {
+            Ok(_) => vec![true.into()],
+            Err(e) => {
+                log::error!("Close connection error: {}", e);
+                vec![false.into(), error_primitive(&e)]
+            }
+        }
+    }
+}
+
+/// 选择代理组中的节点，参数为组名和节点名，失败时第二个返回值为 json 编码的 `ApiError`
+pub fn select_proxy(runtime: &ControlRuntime) -> impl Fn(Vec<Primitive>) -> Vec<Primitive> {
+    let runtime = runtime.clone();
+    move |params| {
+        let (group, node) = match (params.first(), params.get(1)) {
+            (Some(Primitive::String(group)), Some(Primitive::String(node))) => (group, node),
+            _ => {
+                log::error!("select_proxy: missing group or node");
+                return vec![false.into(), missing_param("missing group or node")];
+            }
+        };
+        match runtime.select_proxy(group, node) {
+            Ok(_) => vec![true.into()],
+            Err(e) => {
+                log::error!("Select proxy error: {}", e);
+                vec![false.into(), error_primitive(&e)]
+            }
+        }
+    }
+}
+
+/// 测试节点延迟，参数为节点名、测试地址和超时（毫秒），后两个可省略
+/// 返回 `["Ok", 延迟]`、`["Timeout"]`、`["Unreachable"]`，访问 Clash 失败时返回 `["Error", ApiError]`
+pub fn test_proxy_delay(runtime: &ControlRuntime) -> impl Fn(Vec<Primitive>) -> Vec<Primitive> {
+    let runtime = runtime.clone();
+    move |params| {
+        let node = match params.first() {
+            Some(Primitive::String(x)) => x.clone(),
+            _ => {
+                log::error!("test_proxy_delay: missing node");
+                return vec!["Error".into(), missing_param("missing node")];
+            }
+        };
+        let test_url = match params.get(1) {
+            Some(Primitive::String(x)) if !x.is_empty() => x.clone(),
+            _ => controller::DEFAULT_DELAY_TEST_URL.to_string(),
+        };
+        let timeout = match params.get(2) {
+            Some(Primitive::F64(x)) if *x > 0.0 => *x as u64,
+            _ => controller::DEFAULT_DELAY_TIMEOUT,
+        };
+        match runtime.test_proxy_delay(&node, &test_url, timeout) {
+            Ok(DelayResult::Delay(x)) => vec!["Ok".into(), (x as f64).into()],
+            Ok(DelayResult::Timeout) => vec!["Timeout".into()],
+            Ok(DelayResult::Unreachable) => vec!["Unreachable".into()],
+            Err(e) => {
+                log::error!("Test proxy delay error: {}", e);
+                vec!["Error".into(), error_primitive(&e)]
+            }
+        }
+    }
+}
+
+/// 切换订阅时热重载配置，返回是否成功以及采用的方式（Hot / Restart）
+pub fn reload_clash_config(runtime: &ControlRuntime) -> impl Fn(Vec<Primitive>) -> Vec<Primitive> {
+    let runtime = runtime.clone();
+    move |params| {
+        let config_path = match params.first() {
+            Some(Primitive::String(x)) => Some(x.clone()),
+            _ => None,
+        };
+        match runtime.reload_clash_config(config_path) {
+            Ok(x) => vec![true.into(), x.to_string().into()],
+            Err(e) => {
+                log::error!("Reload clash config error: {}", e);
+                vec![false.into(), error_primitive(&e)]
+            }
         }
     }
 }
@@ -139,24 +548,58 @@ pub fn reset_network() -> impl Fn(Vec<Primitive>) -> Vec<Primitive> {
     }
 }
 
+/// 返回 json 编码的 `RuntimeInfo`，核心未运行时失败
+pub fn get_runtime_info(runtime: &ControlRuntime) -> impl Fn(Vec<Primitive>) -> Vec<Primitive> {
+    let runtime = runtime.clone();
+    move |_| match runtime.runtime_info() {
+        Ok(x) => match serde_json::to_string(&x) {
+            Ok(x) => vec![true.into(), x.into()],
+            Err(e) => vec![
+                false.into(),
+                error_primitive(&ClashError::new(ClashErrorKind::InnerError, e.to_string())),
+            ],
+        },
+        Err(e) => {
+            log::debug!("get_runtime_info failed: {}", e);
+            vec![false.into(), error_primitive(&e)]
+        }
+    }
+}
+
+/// 复原遗留的 DNS 设置，成功时返回 json 编码的 `DnsRepair`
+pub fn repair_dns(runtime: &ControlRuntime) -> impl Fn(Vec<Primitive>) -> Vec<Primitive> {
+    let runtime = runtime.clone();
+    move |_| match runtime.repair_dns() {
+        Ok(x) => match serde_json::to_string(&x) {
+            Ok(x) => vec![true.into(), x.into()],
+            Err(e) => vec![
+                false.into(),
+                error_primitive(&ClashError::new(ClashErrorKind::InnerError, e.to_string())),
+            ],
+        },
+        Err(e) => {
+            log::error!("repair_dns failed: {}", e);
+            vec![false.into(), error_primitive(&e)]
+        }
+    }
+}
+
 pub fn download_sub(runtime: &ControlRuntime) -> impl Fn(Vec<Primitive>) -> Vec<Primitive> {
-    let download_status = runtime.downlaod_status_clone();
+    let runtime = runtime.clone();
+    let download_status = runtime.download_status_clone();
     let runtime_state = runtime.state_clone();
     let runtime_setting = runtime.settings_clone();
     move |params| {
         if let Some(Primitive::String(url)) = params.get(0) {
             match download_status.write() {
                 Ok(mut x) => {
-                    let path = match runtime_state.read() {
-                        Ok(x) => x.home.as_path().join(".config/tomoon/subs/"),
-                        Err(e) => {
-                            log::error!("download_sub() faild to acquire state read {}", e);
-                            return vec![];
-                        }
-                    };
-                    *x = DownloadStatus::Downloading;
+                    let path = control::read_lock(&runtime_state, "state")
+                        .home
+                        .join(".config/tomoon/subs/");
+                    *x = DownloadStatus::downloading();
                     //新线程复制准备
                     let url = url.clone();
+                    let options = runtime.download_options();
                     let download_status = download_status.clone();
                     let runtime_setting = runtime_setting.clone();
                     let runtime_state = runtime_state.clone();
@@ -190,7 +633,9 @@ pub fn download_sub(runtime: &ControlRuntime) -> impl Fn(Vec<Primitive>) -> Vec<
                                     }
                                 };
                                 if !helper::check_yaml(&file_content) {
-                                    log::error!("The downloaded subscription is not a legal profile.");
+                                    log::error!(
+                                        "The downloaded subscription is not a legal profile."
+                                    );
                                     update_status(DownloadStatus::Error);
                                     return;
                                 }
@@ -219,28 +664,11 @@ pub fn download_sub(runtime: &ControlRuntime) -> impl Fn(Vec<Primitive>) -> Vec<
                                 log::info!("Download profile successfully.");
                                 update_status(DownloadStatus::Success);
                                 //存入设置
-                                match runtime_setting.write() {
-                                    Ok(mut x) => {
-                                        x.subscriptions
-                                            .push(Subscription::new(path.to_string(), url.clone()));
-                                        let mut state = match runtime_state.write() {
-                                            Ok(x) => x,
-                                            Err(e) => {
-                                                log::error!("set_enable failed to acquire state write lock: {}", e);
-                                                update_status(DownloadStatus::Error);
-                                                return;
-                                            }
-                                        };
-                                        state.dirty = true;
-                                    }
-                                    Err(e) => {
-                                        log::error!(
-                                        "download_sub() faild to acquire runtime_setting write {}",
-                                        e
-                                    );
-                                        update_status(DownloadStatus::Error);
-                                    }
-                                }
+                                let mut x = control::write_lock(&runtime_setting, "settings");
+                                x.subscriptions
+                                    .push(Subscription::new(path.to_string(), url.clone()));
+                                let mut state = control::write_lock(&runtime_state, "state");
+                                state.dirty = true;
                             } else {
                                 log::error!("Cannt found file {}", local_file.to_str().unwrap());
                                 update_status(DownloadStatus::Error);
@@ -248,15 +676,30 @@ pub fn download_sub(runtime: &ControlRuntime) -> impl Fn(Vec<Primitive>) -> Vec<
                             }
                             // 是一个链接
                         } else {
-                            match minreq::get(url.clone())
-                                .with_header("User-Agent", format!("ToMoonClash/{}",env!("CARGO_PKG_VERSION")))
-                                .with_timeout(15)
-                                .send()
-                            {
+                            match helper::get_request(&url, &options).send() {
                                 Ok(x) => {
-                                    let response = x.as_str().unwrap();
-                                    if !helper::check_yaml(&String::from(response)) {
-                                        log::error!("The downloaded subscription is not a legal profile.");
+                                    if let Err(e) =
+                                        helper::check_status(x.status_code, &x.reason_phrase)
+                                    {
+                                        log::error!("Failed while downloading sub: {}", e);
+                                        update_status(DownloadStatus::Failed);
+                                        return;
+                                    }
+                                    let response = match helper::response_text(x) {
+                                        Ok(x) => x,
+                                        Err(e) => {
+                                            log::error!(
+                                                "Error occurred while parsing response: {}",
+                                                e
+                                            );
+                                            update_status(DownloadStatus::Error);
+                                            return;
+                                        }
+                                    };
+                                    if !helper::check_yaml(&response) {
+                                        log::error!(
+                                            "The downloaded subscription is not a legal profile."
+                                        );
                                         update_status(DownloadStatus::Error);
                                         return;
                                     }
@@ -285,28 +728,11 @@ pub fn download_sub(runtime: &ControlRuntime) -> impl Fn(Vec<Primitive>) -> Vec<
                                     log::info!("Download profile successfully.");
                                     update_status(DownloadStatus::Success);
                                     //存入设置
-                                    match runtime_setting.write() {
-                                        Ok(mut x) => {
-                                            x.subscriptions
-                                                .push(Subscription::new(path.to_string(), url));
-                                            let mut state = match runtime_state.write() {
-                                                Ok(x) => x,
-                                                Err(e) => {
-                                                    log::error!("set_enable failed to acquire state write lock: {}", e);
-                                                    update_status(DownloadStatus::Error);
-                                                    return;
-                                                }
-                                            };
-                                            state.dirty = true;
-                                        }
-                                        Err(e) => {
-                                            log::error!(
-                                        "download_sub() faild to acquire runtime_setting write {}",
-                                        e
-                                    );
-                                            update_status(DownloadStatus::Error);
-                                        }
-                                    }
+                                    let mut x = control::write_lock(&runtime_setting, "settings");
+                                    x.subscriptions
+                                        .push(Subscription::new(path.to_string(), url));
+                                    let mut state = control::write_lock(&runtime_state, "state");
+                                    state.dirty = true;
                                 }
                                 Err(e) => {
                                     log::error!("Failed while downloading sub.");
@@ -328,8 +754,187 @@ pub fn download_sub(runtime: &ControlRuntime) -> impl Fn(Vec<Primitive>) -> Vec<
     }
 }
 
-pub fn get_download_status(runtime: &ControlRuntime) -> impl Fn(Vec<Primitive>) -> Vec<Primitive> {
-    let download_status = runtime.downlaod_status_clone();
+/// 在后台下载订阅，进度通过 `get_download_status` 查询
+pub fn add_subscription(runtime: &ControlRuntime) -> impl Fn(Vec<Primitive>) -> Vec<Primitive> {
+    let runtime = runtime.clone();
+    move |params| {
+        let url = match params.first() {
+            Some(Primitive::String(x)) => x.clone(),
+            _ => {
+                log::error!("add_subscription: missing url");
+                return vec![false.into()];
+            }
+        };
+        let runtime = runtime.clone();
+        thread::spawn(move || {
+            if let Err(e) = runtime.add_subscription(&url) {
+                log::error!("add_subscription failed: {}", e);
+            }
+        });
+        vec![true.into()]
+    }
+}
+
+/// 导入配置文本，参数为名称和 yaml 内容，成功时返回保存的路径
+pub fn add_config_from_text(runtime: &ControlRuntime) -> impl Fn(Vec<Primitive>) -> Vec<Primitive> {
+    let runtime = runtime.clone();
+    move |params| {
+        let (name, text) = match (params.first(), params.get(1)) {
+            (Some(Primitive::String(name)), Some(Primitive::String(text))) => (name, text),
+            _ => {
+                log::error!("add_config_from_text: missing name or text");
+                return vec![false.into(), missing_param("missing name or text")];
+            }
+        };
+        match runtime.add_config_from_text(name, text) {
+            Ok(x) => vec![true.into(), x.into()],
+            Err(e) => {
+                log::error!("add_config_from_text failed: {}", e);
+                vec![false.into(), error_primitive(&e)]
+            }
+        }
+    }
+}
+
+/// 返回 json 编码的订阅列表，包括 id、路径和链接
+pub fn list_subscriptions(runtime: &ControlRuntime) -> impl Fn(Vec<Primitive>) -> Vec<Primitive> {
+    let runtime = runtime.clone();
+    move |_| match serde_json::to_string(&runtime.list_subscriptions()) {
+        Ok(x) => vec![x.into()],
+        Err(e) => {
+            log::error!("Error while serializing data structures");
+            log::error!("Error message: {}", e);
+            vec![]
+        }
+    }
+}
+
+pub fn delete_subscription(runtime: &ControlRuntime) -> impl Fn(Vec<Primitive>) -> Vec<Primitive> {
+    let runtime = runtime.clone();
+    move |params| match params.first() {
+        Some(Primitive::String(id)) => match runtime.delete_subscription(id) {
+            Ok(_) => vec![true.into()],
+            Err(e) => {
+                log::error!("delete_subscription failed: {}", e);
+                vec![false.into(), error_primitive(&e)]
+            }
+        },
+        _ => {
+            log::error!("delete_subscription: missing id");
+            vec![false.into(), missing_param("missing id")]
+        }
+    }
+}
+
+/// 导出设置和订阅，成功时返回压缩包路径
+pub fn export_profile(runtime: &ControlRuntime) -> impl Fn(Vec<Primitive>) -> Vec<Primitive> {
+    let runtime = runtime.clone();
+    move |_| match runtime.export_profile() {
+        Ok(x) => vec![true.into(), x.to_string_lossy().to_string().into()],
+        Err(e) => {
+            log::error!("export_profile failed: {}", e);
+            vec![false.into(), error_primitive(&e)]
+        }
+    }
+}
+
+/// 参数为压缩包路径和是否替换现有设置，默认合并
+pub fn import_profile(runtime: &ControlRuntime) -> impl Fn(Vec<Primitive>) -> Vec<Primitive> {
+    let runtime = runtime.clone();
+    move |params| {
+        let path = match params.first() {
+            Some(Primitive::String(x)) => x,
+            _ => {
+                log::error!("import_profile: missing path");
+                return vec![false.into(), missing_param("missing path")];
+            }
+        };
+        let mode = match params.get(1) {
+            Some(Primitive::Bool(true)) => ImportMode::Replace,
+            _ => ImportMode::Merge,
+        };
+        match runtime.import_profile(Path::new(path), mode) {
+            Ok(_) => vec![true.into()],
+            Err(e) => {
+                log::error!("import_profile failed: {}", e);
+                vec![false.into(), error_primitive(&e)]
+            }
+        }
+    }
+}
+
+/// 恢复初始状态，参数为是否同时删除订阅，默认保留
+pub fn reset_all(runtime: &ControlRuntime) -> impl Fn(Vec<Primitive>) -> Vec<Primitive> {
+    let runtime = runtime.clone();
+    move |params| {
+        let delete_subscriptions = matches!(params.first(), Some(Primitive::Bool(true)));
+        match runtime.reset_all(delete_subscriptions) {
+            Ok(_) => vec![true.into()],
+            Err(e) => {
+                log::error!("reset_all failed: {}", e);
+                vec![false.into(), error_primitive(&e)]
+            }
+        }
+    }
+}
+
+/// 参数为订阅 id 和更新间隔（秒），不传间隔时使用全局默认值
+pub fn set_subscription_interval(
+    runtime: &ControlRuntime,
+) -> impl Fn(Vec<Primitive>) -> Vec<Primitive> {
+    let runtime = runtime.clone();
+    move |params| {
+        let id = match params.first() {
+            Some(Primitive::String(x)) => x,
+            _ => {
+                log::error!("set_subscription_interval: missing id");
+                return vec![false.into(), missing_param("missing id")];
+            }
+        };
+        let interval = match params.get(1) {
+            Some(Primitive::F64(x)) if *x >= 0.0 => Some(*x as u64),
+            _ => None,
+        };
+        match runtime.set_subscription_interval(id, interval) {
+            Ok(_) => vec![true.into()],
+            Err(e) => {
+                log::error!("set_subscription_interval failed: {}", e);
+                vec![false.into(), error_primitive(&e)]
+            }
+        }
+    }
+}
+
+/// 校验订阅链接是否可用，确认后再调用 `download_sub` 保存
+pub fn probe_sub(runtime: &ControlRuntime) -> impl Fn(Vec<Primitive>) -> Vec<Primitive> {
+    let runtime = runtime.clone();
+    move |params| {
+        if let Some(Primitive::String(url)) = params.first() {
+            let options = helper::DownloadOptions {
+                timeout: PROBE_TIMEOUT,
+                ..runtime.download_options()
+            };
+            let probe = helper::probe_subscription(url, &options);
+            log::info!("Probed subscription, valid: {}", probe.valid);
+            match serde_json::to_string(&probe) {
+                Ok(x) => vec![x.into()],
+                Err(e) => {
+                    log::error!("Error while serializing data structures");
+                    log::error!("Error message: {}", e);
+                    vec![]
+                }
+            }
+        } else {
+            vec![]
+        }
+    }
+}
+
+/// 下载订阅的状态，`get_download_status` 为旧的名称
+pub fn get_subscription_download_status(
+    runtime: &ControlRuntime,
+) -> impl Fn(Vec<Primitive>) -> Vec<Primitive> {
+    let download_status = runtime.download_status_clone();
     move |_| {
         match download_status.read() {
             Ok(x) => {
@@ -337,20 +942,68 @@ pub fn get_download_status(runtime: &ControlRuntime) -> impl Fn(Vec<Primitive>)
                 return vec![status.into()];
             }
             Err(_) => {
-                log::error!("Error occured while get_download_status()");
+                log::error!("Error occured while get_subscription_download_status()");
             }
         }
         return vec![];
     }
 }
 
+#[derive(Serialize)]
+struct DownloadProgress {
+    status: String,
+    received: u64,
+    total: Option<u64>,
+}
+
+/// 返回当前下载的字节数与总大小，未知总大小时 `total` 为 null
+pub fn get_download_progress(
+    runtime: &ControlRuntime,
+) -> impl Fn(Vec<Primitive>) -> Vec<Primitive> {
+    let download_status = runtime.download_status_clone();
+    move |_| {
+        let progress = match download_status.read() {
+            Ok(x) => match *x {
+                DownloadStatus::Downloading { received, total } => DownloadProgress {
+                    status: x.to_string(),
+                    received,
+                    total,
+                },
+                _ => DownloadProgress {
+                    status: x.to_string(),
+                    received: 0,
+                    total: None,
+                },
+            },
+            Err(_) => {
+                log::error!("Error occured while get_download_progress()");
+                return vec![];
+            }
+        };
+        match serde_json::to_string(&progress) {
+            Ok(x) => vec![x.into()],
+            Err(e) => {
+                log::error!("Error while serializing data structures");
+                log::error!("Error message: {}", e);
+                vec![]
+            }
+        }
+    }
+}
+
+/// 返回运行状态，启动失败时第二个返回值为失败原因
 pub fn get_running_status(runtime: &ControlRuntime) -> impl Fn(Vec<Primitive>) -> Vec<Primitive> {
     let running_status = runtime.running_status_clone();
+    let last_error = runtime.last_error_clone();
     move |_| {
         match running_status.read() {
             Ok(x) => {
                 let status = x.to_string();
-                return vec![status.into()];
+                let mut result = vec![status.into()];
+                if let Ok(Some(e)) = last_error.read().as_deref() {
+                    result.push(e.clone().into());
+                }
+                return result;
             }
             Err(_) => {
                 log::error!("Error occured while get_download_status()");
@@ -360,31 +1013,78 @@ pub fn get_running_status(runtime: &ControlRuntime) -> impl Fn(Vec<Primitive>) -
     }
 }
 
+pub fn get_providers_status(runtime: &ControlRuntime) -> impl Fn(Vec<Primitive>) -> Vec<Primitive> {
+    let providers_status = runtime.providers_status_clone();
+    move |_| match providers_status.read() {
+        Ok(x) => match serde_json::to_string(&*x) {
+            Ok(x) => vec![x.into()],
+            Err(e) => {
+                log::error!("Error while serializing data structures");
+                log::error!("Error message: {}", e);
+                vec![]
+            }
+        },
+        Err(_) => {
+            log::error!("Error occured while get_providers_status()");
+            vec![]
+        }
+    }
+}
+
+pub fn get_active_interface(runtime: &ControlRuntime) -> impl Fn(Vec<Primitive>) -> Vec<Primitive> {
+    let active_interface = runtime.active_interface_clone();
+    move |_| match active_interface.read() {
+        Ok(x) => vec![x.clone().unwrap_or_default().into()],
+        Err(_) => {
+            log::error!("Error occured while get_active_interface()");
+            vec![]
+        }
+    }
+}
+
+/// 预览改写后的配置，不写入运行配置，参数为配置路径，为空时使用当前订阅，返回生成的 yaml
+pub fn preview_config(runtime: &ControlRuntime) -> impl Fn(Vec<Primitive>) -> Vec<Primitive> {
+    let runtime = runtime.clone();
+    let runtime_settings = runtime.settings_clone();
+    move |params| {
+        let config_path = match params.first() {
+            Some(Primitive::String(x)) if !x.is_empty() => x.clone(),
+            _ => control::read_lock(&runtime_settings, "settings")
+                .current_sub
+                .clone(),
+        };
+        match runtime.preview_config(&config_path) {
+            Ok(x) => vec![true.into(), x.into()],
+            Err(e) => {
+                log::error!("preview_config failed: {}", e);
+                vec![false.into(), error_primitive(&e)]
+            }
+        }
+    }
+}
+
+/// 返回运行中的 mixed-port，未运行或没有设置时返回空
+pub fn get_mixed_port(runtime: &ControlRuntime) -> impl Fn(Vec<Primitive>) -> Vec<Primitive> {
+    let runtime = runtime.clone();
+    move |_| match runtime.active_mixed_port() {
+        Some(x) => vec![(x as f64).into()],
+        None => vec![],
+    }
+}
+
 pub fn get_sub_list(runtime: &ControlRuntime) -> impl Fn(Vec<Primitive>) -> Vec<Primitive> {
     let runtime_setting = runtime.settings_clone();
     move |_| {
-        match runtime_setting.read() {
-            Ok(x) => {
-                match serde_json::to_string(&x.subscriptions) {
-                    Ok(x) => {
-                        //返回 json 编码的订阅
-                        return vec![x.into()];
-                    }
-                    Err(e) => {
-                        log::error!("Error while serializing data structures");
-                        log::error!("Error message: {}", e);
-                        return vec![];
-                    }
-                };
-            }
+        let x = control::read_lock(&runtime_setting, "settings");
+        match serde_json::to_string(&x.subscriptions) {
+            //返回 json 编码的订阅
+            Ok(x) => vec![x.into()],
             Err(e) => {
-                log::error!(
-                    "download_sub() faild to acquire runtime_setting write {}",
-                    e
-                );
+                log::error!("Error while serializing data structures");
+                log::error!("Error message: {}", e);
+                vec![]
             }
         }
-        return vec![];
     }
 }
 
@@ -393,38 +1093,26 @@ pub fn delete_sub(runtime: &ControlRuntime) -> impl Fn(Vec<Primitive>) -> Vec<Pr
     let runtime_state = runtime.state_clone();
     move |params| {
         if let Some(Primitive::F64(id)) = params.get(0) {
-            match runtime_setting.write() {
-                Ok(mut x) => {
-                    if let Some(item) = x.subscriptions.get(*id as usize) {
-                        match fs::remove_file(item.path.as_str()) {
-                            Ok(_) => {}
-                            Err(e) => {
-                                log::error!("delete file error: {}", e);
-                                return vec![];
-                            }
-                        }
-                    }
-                    if let Some(item) = x.subscriptions.get(*id as usize) {
-                        if x.current_sub == item.path {
-                            x.current_sub = "".to_string();
-                        }
-                        x.subscriptions.remove(*id as usize);
+            let mut x = control::write_lock(&runtime_setting, "settings");
+            if let Some(item) = x.subscriptions.get(*id as usize) {
+                match fs::remove_file(item.path.as_str()) {
+                    Ok(_) => {}
+                    Err(e) => {
+                        log::error!("delete file error: {}", e);
+                        return vec![];
                     }
-                    //log::info!("delete {:?}", x.subscriptions.get(*id as usize).unwrap());
-                    drop(x);
-                    let mut state = match runtime_state.write() {
-                        Ok(x) => x,
-                        Err(e) => {
-                            log::error!("set_enable failed to acquire state write lock: {}", e);
-                            return vec![];
-                        }
-                    };
-                    state.dirty = true;
                 }
-                Err(e) => {
-                    log::error!("delete_sub() faild to acquire runtime_setting write {}", e);
+            }
+            if let Some(item) = x.subscriptions.get(*id as usize) {
+                if x.current_sub == item.path {
+                    x.current_sub = "".to_string();
                 }
+                x.subscriptions.remove(*id as usize);
             }
+            //log::info!("delete {:?}", x.subscriptions.get(*id as usize).unwrap());
+            drop(x);
+            let mut state = control::write_lock(&runtime_state, "state");
+            state.dirty = true;
         }
         return vec![];
     }
@@ -437,25 +1125,12 @@ pub fn set_sub(runtime: &ControlRuntime) -> impl Fn(Vec<Primitive>) -> Vec<Primi
     move |params: Vec<Primitive>| {
         if let Some(Primitive::String(path)) = params.get(0) {
             //更新到配置文件中
-            match runtime_setting.write() {
-                Ok(mut x) => {
-                    x.current_sub = (*path).clone();
-                    let mut state = match runtime_state.write() {
-                        Ok(x) => x,
-                        Err(e) => {
-                            log::error!("set_sub failed to acquire state write lock: {}", e);
-                            return vec![];
-                        }
-                    };
-                    state.dirty = true;
-                    drop(x);
-                    drop(state);
-                }
-                Err(e) => {
-                    log::error!("get_enable failed to acquire settings read lock: {}", e);
-                    return vec![];
-                }
-            };
+            let mut x = control::write_lock(&runtime_setting, "settings");
+            x.current_sub = (*path).clone();
+            let mut state = control::write_lock(&runtime_state, "state");
+            state.dirty = true;
+            drop(x);
+            drop(state);
             // //更新到当前内存中
             // match runtime_clash.write() {
             //     Ok(mut x) => {
@@ -474,72 +1149,138 @@ pub fn set_sub(runtime: &ControlRuntime) -> impl Fn(Vec<Primitive>) -> Vec<Primi
 pub fn update_subs(runtime: &ControlRuntime) -> impl Fn(Vec<Primitive>) -> Vec<Primitive> {
     let runtime_update_status = runtime.update_status_clone();
     let runtime_setting = runtime.settings_clone();
+    let runtime = runtime.clone();
     move |_| {
         if let Ok(mut x) = runtime_update_status.write() {
-            *x = DownloadStatus::Downloading;
+            *x = DownloadStatus::downloading();
             drop(x);
-            if let Ok(v) = runtime_setting.write() {
-                let subs = v.subscriptions.clone();
-                drop(v);
-                let runtime_update_status = runtime_update_status.clone();
-                thread::spawn(move || {
-                    for i in subs {
-                        //是一个本地文件
-                        if helper::get_file_path(i.url.clone()).is_some() {
-                            continue;
-                        }
-                        thread::spawn(move || {
-                            match minreq::get(i.url.clone())
-                            .with_header("User-Agent", format!("ToMoonClash/{}",env!("CARGO_PKG_VERSION")))
-                            .with_timeout(15)
-                            .send() {
-                                Ok(response) => {
-                                    let response = match response.as_str() {
-                                        Ok(x) => x,
-                                        Err(_) => {
-                                            log::error!("Error occurred while parsing response.");
-                                            return;
-                                        }
-                                    };
-                                    if !helper::check_yaml(&response.to_string()) {
-                                        log::error!("The downloaded subscription is not a legal profile.");
-                                        return;
-                                    }
-                                    match fs::write(i.path.clone(), response) {
-                                        Ok(_) => {
-                                            log::info!("Subscription {} updated.", i.path);
-                                        }
-                                        Err(e) => {
-                                            log::error!(
+            let subs = control::read_lock(&runtime_setting, "settings")
+                .subscriptions
+                .clone();
+            let options = runtime.download_options();
+            let runtime_update_status = runtime_update_status.clone();
+            thread::spawn(move || {
+                for i in subs {
+                    //是一个本地文件
+                    if helper::get_file_path(i.url.clone()).is_some() {
+                        continue;
+                    }
+                    let options = options.clone();
+                    thread::spawn(move || match helper::get_request(&i.url, &options).send() {
+                        Ok(response) => {
+                            if let Err(e) =
+                                helper::check_status(response.status_code, &response.reason_phrase)
+                            {
+                                log::error!("Error occurred while updating sub {}: {}", i.url, e);
+                                return;
+                            }
+                            let response = match helper::response_text(response) {
+                                Ok(x) => x,
+                                Err(e) => {
+                                    log::error!("Error occurred while parsing response: {}", e);
+                                    return;
+                                }
+                            };
+                            if !helper::check_yaml(&response) {
+                                log::error!("The downloaded subscription is not a legal profile.");
+                                return;
+                            }
+                            match fs::write(i.path.clone(), response) {
+                                Ok(_) => {
+                                    log::info!("Subscription {} updated.", i.path);
+                                }
+                                Err(e) => {
+                                    log::error!(
                                         "Error occurred while write to file in update_subs(). {}",
                                         e
                                     );
-                                            return;
-                                        }
-                                    }
-                                },
-                                Err(e) => {
-                                    log::error!("Error occurred while download sub {}", i.url);
-                                    log::error!("Error Message : {}", e);
+                                    return;
                                 }
                             }
-                        });
-                    }
-                    //下载执行完毕
-                    if let Ok(mut x) = runtime_update_status.write() {
-                        *x = DownloadStatus::Success;
-                    } else {
-                        log::error!(
-                            "Error occurred while acquire runtime_update_status write lock."
-                        );
-                    }
-                });
-            }
+                        }
+                        Err(e) => {
+                            log::error!("Error occurred while download sub {}", i.url);
+                            log::error!("Error Message : {}", e);
+                        }
+                    });
+                }
+                //下载执行完毕
+                if let Ok(mut x) = runtime_update_status.write() {
+                    *x = DownloadStatus::Success;
+                } else {
+                    log::error!("Error occurred while acquire runtime_update_status write lock.");
+                }
+            });
         }
         return vec![];
     }
 }
 
+/// 在后台下载并替换 Clash 核心，进度通过 `get_core_update_status` 查询
+pub fn update_clash_core(runtime: &ControlRuntime) -> impl Fn(Vec<Primitive>) -> Vec<Primitive> {
+    let runtime = runtime.clone();
+    move |params| {
+        let (url, sha256) = match (params.first(), params.get(1)) {
+            (Some(Primitive::String(url)), Some(Primitive::String(sha256))) => {
+                (url.clone(), sha256.clone())
+            }
+            _ => {
+                log::error!("update_clash_core: missing download url or sha256");
+                return vec![false.into()];
+            }
+        };
+        let runtime = runtime.clone();
+        thread::spawn(move || {
+            if let Err(e) = runtime.update_core(&url, &sha256) {
+                log::error!("update_clash_core failed: {}", e);
+            }
+        });
+        vec![true.into()]
+    }
+}
+
+/// 在后台下载 GeoIP / GeoSite 数据库，进度通过 `get_geo_update_status` 读取
+pub fn update_geo_database(runtime: &ControlRuntime) -> impl Fn(Vec<Primitive>) -> Vec<Primitive> {
+    let runtime = runtime.clone();
+    move |_| {
+        let runtime = runtime.clone();
+        thread::spawn(move || {
+            if let Err(e) = runtime.update_geo_database() {
+                log::error!("update_geo_database failed: {}", e);
+            }
+        });
+        vec![true.into()]
+    }
+}
+
+pub fn get_geo_update_status(
+    runtime: &ControlRuntime,
+) -> impl Fn(Vec<Primitive>) -> Vec<Primitive> {
+    let geo_status = runtime.geo_status_clone();
+    move |_| match geo_status.read() {
+        Ok(x) => vec![x.to_string().into()],
+        Err(_) => {
+            log::error!("Error occured while get_geo_update_status()");
+            vec![]
+        }
+    }
+}
+
+/// 更新 Clash 核心的状态，与订阅更新分开
+pub fn get_core_update_status(
+    runtime: &ControlRuntime,
+) -> impl Fn(Vec<Primitive>) -> Vec<Primitive> {
+    let core_status = runtime.core_status_clone();
+    move |_| match core_status.read() {
+        Ok(x) => vec![x.to_string().into()],
+        Err(_) => {
+            log::error!("Error occured while get_core_update_status()");
+            vec![]
+        }
+    }
+}
+
+/// 更新订阅的状态
 pub fn get_update_status(runtime: &ControlRuntime) -> impl Fn(Vec<Primitive>) -> Vec<Primitive> {
     let update_status = runtime.update_status_clone();
     move |_| {
@@ -556,21 +1297,102 @@ pub fn get_update_status(runtime: &ControlRuntime) -> impl Fn(Vec<Primitive>) ->
     }
 }
 
-pub fn create_debug_log() -> impl Fn(Vec<Primitive>) -> Vec<Primitive> {
+/// 返回 Clash 核心日志的最后若干行，json 编码
+pub fn get_clash_logs() -> impl Fn(Vec<Primitive>) -> Vec<Primitive> {
+    |params| {
+        let lines = match params.first() {
+            Some(Primitive::F64(x)) if *x >= 1.0 => *x as usize,
+            _ => CLASH_LOG_DEFAULT_LINES,
+        }
+        .min(CLASH_LOG_MAX_LINES);
+        let logs = match helper::tail_file(
+            Path::new("/tmp/tomoon.clash.log"),
+            lines,
+            CLASH_LOG_MAX_BYTES,
+        ) {
+            Ok(x) => x,
+            Err(e) => {
+                log::error!("Error occurred while reading clash log: {}", e);
+                Vec::new()
+            }
+        };
+        match serde_json::to_string(&logs) {
+            Ok(x) => vec![x.into()],
+            Err(e) => {
+                log::error!("Error while serializing data structures");
+                log::error!("Error message: {}", e);
+                vec![]
+            }
+        }
+    }
+}
+
+/// 读取后端日志文件的最后几行，参数与 `get_clash_logs` 相同
+pub fn get_backend_logs() -> impl Fn(Vec<Primitive>) -> Vec<Primitive> {
+    |params| {
+        let lines = match params.first() {
+            Some(Primitive::F64(x)) if *x >= 1.0 => *x as usize,
+            _ => CLASH_LOG_DEFAULT_LINES,
+        }
+        .min(CLASH_LOG_MAX_LINES);
+        let logs = match helper::tail_file(&logger::log_path(), lines, CLASH_LOG_MAX_BYTES) {
+            Ok(x) => x,
+            Err(e) => {
+                log::error!("Error occurred while reading backend log: {}", e);
+                Vec::new()
+            }
+        };
+        match serde_json::to_string(&logs) {
+            Ok(x) => vec![x.into()],
+            Err(e) => {
+                log::error!("Error while serializing data structures");
+                log::error!("Error message: {}", e);
+                vec![]
+            }
+        }
+    }
+}
+
+/// 返回内存中最近的后端日志（不包括 Clash 核心日志）
+pub fn get_backend_log(runtime: &ControlRuntime) -> impl Fn(Vec<Primitive>) -> Vec<Primitive> {
+    let log_buffer = runtime.log_buffer_clone();
+    move |_| {
+        let records = match log_buffer.read() {
+            Ok(x) => x.records(),
+            Err(_) => {
+                // 日志锁异常时不能再写日志
+                return vec![];
+            }
+        };
+        match serde_json::to_string(&records) {
+            Ok(x) => vec![x.into()],
+            Err(e) => {
+                log::error!("Error while serializing data structures");
+                log::error!("Error message: {}", e);
+                vec![]
+            }
+        }
+    }
+}
+
+pub fn create_debug_log(runtime: &ControlRuntime) -> impl Fn(Vec<Primitive>) -> Vec<Primitive> {
     //let update_status = runtime.update_status_clone();
+    let runtime_state = runtime.state_clone();
     move |_| {
         let running_status = format!(
             "Clash status : {}, SmartDNS status: {} \n",
             helper::is_clash_running(),
             helper::is_samrtdns_running()
         );
-        let tomoon_config = match fs::read_to_string("/home/deck/.config/tomoon/tomoon.json") {
+        let settings_path =
+            control::settings_path(&control::read_lock(&runtime_state, "state").home);
+        let tomoon_config = match fs::read_to_string(settings_path) {
             Ok(x) => x,
             Err(e) => {
                 format!("can not get Tomoon config, error message: {} \n", e)
             }
         };
-        let tomoon_log = match fs::read_to_string("/tmp/tomoon.log") {
+        let tomoon_log = match fs::read_to_string(logger::log_path()) {
             Ok(x) => x,
             Err(e) => {
                 format!("can not get Tomoon log, error message: {} \n", e)
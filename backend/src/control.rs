@@ -1,23 +1,110 @@
+use std::collections::{HashMap, VecDeque};
 use std::fmt::Display;
+use std::io::Read;
+use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf};
 use std::process::{Child, Command};
-use std::sync::{Arc, RwLock};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
 
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::{error, fs, thread};
 
+use serde::{Deserialize, Serialize};
 use serde_yaml::{Mapping, Value};
+use sha2::{Digest, Sha256};
 
-use super::helper;
-use super::settings::{Settings, State};
+use super::controller::{Connection, Controller, DelayResult, ProxyGroup, Rule, Traffic};
+use super::helper::{self, DownloadOptions};
+use super::logger::{self, LogBuffer};
+use super::profile::{self, ImportMode};
+use super::settings::{
+    default_current_sub, default_dns_listen, CoreType, DnsOverride, GeoDatabase, ProxyMode,
+    Settings, State, Subscription, TunStack,
+};
+use super::subscription::{self, SubscriptionEntry};
 
+const SETTINGS_PATH_ENV: &str = "TOMOON_CONFIG";
+// 保留最近几次启动操作的进度
+const MAX_START_OPERATIONS: usize = 8;
+const MAX_START_EVENTS: usize = 64;
+// 网卡切换检测的轮询间隔与需要保持稳定的次数
+const INTERFACE_POLL_INTERVAL: Duration = Duration::from_secs(2);
+const INTERFACE_STABLE_POLLS: u32 = 3;
+// 检查设置是否需要保存的间隔
+const SETTINGS_SAVE_INTERVAL: Duration = Duration::from_millis(1000);
+// Rule Provider 下载重试的初始等待时间，之后每次翻倍
+const PROVIDER_RETRY_DELAY: Duration = Duration::from_secs(1);
+// 同时下载 Rule Provider 的线程数
+const PROVIDER_DOWNLOAD_WORKERS: usize = 4;
+// 下载 Clash 核心的超时时间（秒）
+const CORE_DOWNLOAD_TIMEOUT: u64 = 300;
+// 开启 allow-lan 但订阅没有设置代理端口时使用的 mixed-port
+const DEFAULT_MIXED_PORT: u64 = 7890;
+// /tmp 在重启后会被清空，用来判断是否为开机后第一次运行
+const BOOT_MARKER_PATH: &str = "/tmp/tomoon.started";
+// 自动启动前的等待时间，每次失败后翻倍
+const AUTO_START_DELAY: Duration = Duration::from_secs(5);
+// 连续失败达到该次数后不再自动启动
+const AUTO_START_MAX_FAILURES: u32 = 3;
+const CLASH_LOG_PATH: &str = "/tmp/tomoon.clash.log";
+// 启动后等待该时间再检查核心是否已经退出
+const CORE_START_CHECK_DELAY: Duration = Duration::from_millis(500);
+// 开启 IPv6 时 TUN 网卡的地址
+const TUN_INET6_ADDRESS: &str = "fdfe:dcba:9877::1/126";
+// 检查日志是否需要轮转的间隔
+const LOG_ROTATE_INTERVAL: Duration = Duration::from_secs(30);
+// 启动前检查网络的超时时间
+const PREFLIGHT_TIMEOUT: Duration = Duration::from_millis(1500);
+// 核心启动失败时附带的日志行数
+const CORE_EXIT_LOG_LINES: usize = 20;
+// 开启 bypass_lan 时直连的地址段
+const LAN_CIDRS: [&str; 4] = [
+    "192.168.0.0/16",
+    "10.0.0.0/8",
+    "172.16.0.0/12",
+    "127.0.0.0/8",
+];
+// 订阅更新失败后的重试间隔
+const SUBSCRIPTION_RETRY_DELAY: Duration = Duration::from_secs(600);
+// 发送 SIGTERM 后等待核心退出的时间，超时后强制结束
+const CORE_STOP_TIMEOUT: Duration = Duration::from_secs(2);
+// 没有订阅时使用的配置，所有连接直连，用于确认 TUN 和 DNS 可以正常工作
+const FALLBACK_CONFIG: &str = "proxies: []\nproxy-groups: []\nrules:\n  - MATCH,DIRECT\n";
+// 检查核心进程是否意外退出的间隔
+const CORE_MONITOR_INTERVAL: Duration = Duration::from_secs(3);
+// 该时间内连续退出达到次数后不再自动重启
+const CRASH_RESTART_WINDOW: Duration = Duration::from_secs(600);
+const CRASH_RESTART_MAX: u32 = 3;
+// 修复 DNS 后用来确认解析正常的域名和超时时间
+const DNS_CHECK_HOST: &str = "www.baidu.com:443";
+const DNS_CHECK_TIMEOUT: Duration = Duration::from_secs(3);
+
+#[derive(Clone)]
 pub struct ControlRuntime {
     settings: Arc<RwLock<Settings>>,
     state: Arc<RwLock<State>>,
     clash_state: Arc<RwLock<Clash>>,
-    downlaod_status: Arc<RwLock<DownloadStatus>>,
+    download_status: Arc<RwLock<DownloadStatus>>,
+    /// `update_subs` 与后台定时更新订阅的状态
     update_status: Arc<RwLock<DownloadStatus>>,
+    /// 更新 Clash 核心的状态
+    core_status: Arc<RwLock<DownloadStatus>>,
+    geo_status: Arc<RwLock<DownloadStatus>>,
     running_status: Arc<RwLock<RunningStatus>>,
+    providers_status: Arc<RwLock<ProvidersStatus>>,
+    start_progress: Arc<RwLock<StartProgress>>,
+    active_interface: Arc<RwLock<Option<String>>>,
+    /// 最近一次启动失败的原因，启动成功后清空
+    last_error: Arc<RwLock<Option<String>>>,
+    /// 核心在运行中意外退出的信息，下次手动启动后清空
+    core_crash: Arc<RwLock<Option<CoreCrash>>>,
+    /// 正在启动 Clash，防止并发调用启动两个核心
+    starting: Arc<AtomicBool>,
+    log_buffer: Arc<RwLock<LogBuffer>>,
+    /// 核心 `-v` 的结果，核心路径或修改时间变化后重新获取
+    core_version: Arc<RwLock<Option<(PathBuf, SystemTime, String)>>>,
 }
 
 #[derive(Debug)]
@@ -28,20 +115,175 @@ pub enum RunningStatus {
     None,
 }
 
+/// `repair_dns` 的结果
+#[derive(Debug, Clone, Serialize)]
+pub struct DnsRepair {
+    /// 从备份恢复了 resolv.conf
+    pub restored_backup: bool,
+    /// 没有备份且 resolv.conf 只指向本机，写入了默认的 DNS
+    pub regenerated: bool,
+    /// 修复后可以解析 `DNS_CHECK_HOST`
+    pub resolved: bool,
+}
+
+/// 核心在运行中意外退出的信息
+#[derive(Debug, Clone, Serialize)]
+pub struct CoreCrash {
+    /// 退出码，被信号结束时为空
+    pub code: Option<i32>,
+    pub signal: Option<i32>,
+    /// 核心日志的最后几行
+    pub log: Vec<String>,
+    /// 退出的时间（Unix 时间戳，秒）
+    pub time: u64,
+    /// 是否已经自动重启
+    pub restarted: bool,
+}
+
 #[derive(Debug)]
 pub enum DownloadStatus {
-    Downloading,
+    /// 服务器没有返回 `Content-Length` 时 `total` 为 `None`
+    Downloading {
+        received: u64,
+        total: Option<u64>,
+    },
     Failed,
     Success,
     Error,
     None,
 }
 
+/// 后台 Rule Provider 检查的时间记录（Unix 时间戳，秒）
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct ProvidersStatus {
+    pub last_check: Option<u64>,
+    pub last_update: Option<u64>,
+}
+
+/// 当前使用的配置，`generated_at` 为运行配置的修改时间（Unix 时间戳，秒），尚未生成时为 `None`
+#[derive(Debug, Clone, Serialize)]
+pub struct ActiveConfigInfo {
+    /// 配置不在订阅列表中时为 `None`
+    pub subscription: Option<SubscriptionEntry>,
+    pub config_path: String,
+    pub running_config: String,
+    pub generated_at: Option<u64>,
+}
+
+/// 核心实际使用的运行参数，合并订阅和覆盖后可能与设置不同
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct RuntimeInfo {
+    pub mode: Option<String>,
+    pub mixed_port: Option<u16>,
+    pub tun_enable: bool,
+    pub tun_stack: Option<String>,
+    /// `dns.listen`，没有启用 DNS 时为 `None`
+    pub dns_listen: Option<String>,
+}
+
+impl RuntimeInfo {
+    /// `running` 为 external-controller 的 `GET /configs`，`generated` 为生成的运行配置
+    /// 模式和端口可能在运行时修改，以前者为准，`/configs` 中没有的 TUN 和 DNS 参数来自后者
+    pub fn new(running: &Value, generated: &Value) -> Self {
+        let str_of = |x: Option<&Value>| x.and_then(|x| x.as_str()).map(|x| x.to_string());
+        let tun = running.get("tun").or_else(|| generated.get("tun"));
+        let dns = generated
+            .get("dns")
+            .filter(|x| x.get("enable").and_then(|x| x.as_bool()).unwrap_or(false));
+        Self {
+            mode: str_of(running.get("mode")),
+            mixed_port: config_port(running, "mixed-port"),
+            tun_enable: tun
+                .and_then(|x| x.get("enable"))
+                .and_then(|x| x.as_bool())
+                .unwrap_or(false),
+            tun_stack: str_of(tun.and_then(|x| x.get("stack"))),
+            dns_listen: str_of(dns.and_then(|x| x.get("listen"))),
+        }
+    }
+}
+
+/// 启动 Clash 过程中的各个阶段
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum StartStage {
+    ParseConfig,
+    DownloadProviders,
+    WriteConfig,
+    SpawnCore,
+    WaitController,
+    Done,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StartEvent {
+    pub stage: StartStage,
+    pub message: String,
+    pub percent: Option<u8>,
+    pub time: u64,
+}
+
+impl StartEvent {
+    pub fn new<S: Into<String>>(stage: StartStage, message: S, percent: Option<u8>) -> Self {
+        Self {
+            stage,
+            message: message.into(),
+            percent,
+            time: unix_timestamp(),
+        }
+    }
+}
+
+/// 按启动操作 id 记录的进度事件，只保留最近的几次操作
+#[derive(Debug, Default)]
+pub struct StartProgress {
+    next_id: u64,
+    operations: VecDeque<(u64, Vec<StartEvent>)>,
+}
+
+impl StartProgress {
+    pub fn begin(&mut self) -> u64 {
+        self.next_id += 1;
+        if self.operations.len() >= MAX_START_OPERATIONS {
+            self.operations.pop_front();
+        }
+        self.operations.push_back((self.next_id, Vec::new()));
+        self.next_id
+    }
+
+    pub fn push(&mut self, id: u64, event: StartEvent) {
+        if let Some((_, events)) = self.operations.iter_mut().find(|(x, _)| *x == id) {
+            if events.len() >= MAX_START_EVENTS {
+                events.remove(0);
+            }
+            events.push(event);
+        }
+    }
+
+    pub fn get(&self, id: u64) -> Option<&Vec<StartEvent>> {
+        self.operations
+            .iter()
+            .find(|(x, _)| *x == id)
+            .map(|(_, events)| events)
+    }
+}
+
 impl std::fmt::Display for DownloadStatus {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "{:?}", self)
-        // or, alternatively:
-        // fmt::Debug::fmt(self, f)
+        match self {
+            // 保持前端使用的状态名不变
+            Self::Downloading { .. } => write!(f, "Downloading"),
+            _ => write!(f, "{:?}", self),
+        }
+    }
+}
+
+impl DownloadStatus {
+    pub fn downloading() -> Self {
+        Self::Downloading {
+            received: 0,
+            total: None,
+        }
     }
 }
 
@@ -53,30 +295,100 @@ impl std::fmt::Display for RunningStatus {
     }
 }
 
+/// 重新加载配置时实际采用的方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReloadMethod {
+    /// 通过 external-controller 热重载
+    Hot,
+    /// 热重载失败，重启了 Clash
+    Restart,
+}
+
+impl std::fmt::Display for ReloadMethod {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+/// 停止核心时实际采用的方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopMethod {
+    /// 收到 SIGTERM 后自行退出，TUN 网卡已被清理
+    Graceful,
+    /// 超时未退出，使用 SIGKILL 结束
+    Forced,
+    /// 核心没有运行
+    NotRunning,
+}
+
+/// 启动结束（包括失败）时清除 `ControlRuntime.starting`
+struct StartGuard(Arc<AtomicBool>);
+
+impl Drop for StartGuard {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+}
+
+/// 核心的健康状态，进程存在不代表 external-controller 能够响应
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClashHealth {
+    NotRunning,
+    /// 进程存在但 external-controller 没有响应，可能正在启动或已经卡住
+    Unresponsive,
+    Healthy,
+}
+
+impl std::fmt::Display for ClashHealth {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
 // pub struct DownloadStatus {
 
 // }
 
 impl ControlRuntime {
-    pub fn new() -> Self {
+    pub fn new(log_buffer: Arc<RwLock<LogBuffer>>) -> Self {
         let new_state = State::new();
         let settings_p = settings_path(&new_state.home);
+        log::info!("Using settings file {}", settings_p.display());
         //TODO: Clash 路径
-        let clash = Clash::default();
-        let download_status = DownloadStatus::None;
+        let settings = super::settings::Settings::open(settings_p).unwrap_or_default();
+        let mut clash = Clash::default();
+        //恢复上次使用的配置，文件已被删除时使用默认配置
+        if settings.current_config.is_file() {
+            clash.config = settings.current_config.clone();
+        } else {
+            log::warn!(
+                "Stored config {} not found, using {}",
+                settings.current_config.display(),
+                clash.config.display()
+            );
+        }
+        let download_status = Arc::new(RwLock::new(DownloadStatus::None));
+        clash.download_status = download_status.clone();
         let update_status = DownloadStatus::None;
         let running_status = RunningStatus::None;
+        let providers_status = ProvidersStatus::default();
         Self {
-            settings: Arc::new(RwLock::new(
-                super::settings::Settings::open(settings_p)
-                    .unwrap_or_default()
-                    .into(),
-            )),
+            settings: Arc::new(RwLock::new(settings)),
             state: Arc::new(RwLock::new(new_state)),
             clash_state: Arc::new(RwLock::new(clash)),
-            downlaod_status: Arc::new(RwLock::new(download_status)),
+            download_status,
             update_status: Arc::new(RwLock::new(update_status)),
+            core_status: Arc::new(RwLock::new(DownloadStatus::None)),
+            geo_status: Arc::new(RwLock::new(DownloadStatus::None)),
             running_status: Arc::new(RwLock::new(running_status)),
+            providers_status: Arc::new(RwLock::new(providers_status)),
+            start_progress: Arc::new(RwLock::new(StartProgress::default())),
+            active_interface: Arc::new(RwLock::new(helper::get_primary_interface())),
+            last_error: Arc::new(RwLock::new(None)),
+            core_crash: Arc::new(RwLock::new(None)),
+            starting: Arc::new(AtomicBool::new(false)),
+            log_buffer,
+            core_version: Arc::new(RwLock::new(None)),
         }
     }
 
@@ -88,550 +400,3461 @@ impl ControlRuntime {
         self.state.clone()
     }
 
-    pub fn clash_state_clone(&self) -> Arc<RwLock<Clash>> {
-        self.clash_state.clone()
-    }
-
-    pub fn downlaod_status_clone(&self) -> Arc<RwLock<DownloadStatus>> {
-        self.downlaod_status.clone()
+    pub fn download_status_clone(&self) -> Arc<RwLock<DownloadStatus>> {
+        self.download_status.clone()
     }
 
     pub fn update_status_clone(&self) -> Arc<RwLock<DownloadStatus>> {
         self.update_status.clone()
     }
 
-    pub fn running_status_clone(&self) -> Arc<RwLock<RunningStatus>> {
-        self.running_status.clone()
+    pub fn core_status_clone(&self) -> Arc<RwLock<DownloadStatus>> {
+        self.core_status.clone()
     }
 
-    pub fn run(&self) -> thread::JoinHandle<()> {
-        let runtime_settings = self.settings_clone();
-        let runtime_state = self.state_clone();
-
-        //health check
-        //当程序上次异常退出时的处理
-        if let Ok(mut v) = runtime_settings.write() {
-            if !helper::is_clash_running() && v.enable {
-                v.enable = false;
-                drop(v);
-                //刷新网卡
-                match helper::reset_system_network() {
-                    Ok(_) => {}
-                    Err(e) => {
-                        log::error!("runtime failed to acquire settings write lock: {}", e);
-                    }
-                }
-            }
-        }
-
-        //保存复原脚本
-        if !Path::new("/home/deck/tomoon_recover.sh").exists() {
-            let recover_script = r#"sudo chattr -i /etc/resolv.conf
-sudo systemctl stop systemd-resolved
-sudo chmod a+w /etc/NetworkManager/conf.d/dns.conf
-sudo echo -e "[main]\ndns=auto"  > /etc/NetworkManager/conf.d/dns.conf
-sudo nmcli general reload"#;
-            match fs::write("/home/deck/tomoon_recover.sh", recover_script) {
-                Ok(_) => {
-                    log::info!("Write recover script to /home/deck/tomoon_recover.sh");
-                }
-                Err(e) => {
-                    log::error!(
-                        "Error occurred while writing recover script, Error msg: {}",
-                        e.to_string()
-                    );
-                }
-            }
-        }
-
-        //save config
-        thread::spawn(move || {
-            let sleep_duration = Duration::from_millis(1000);
-            loop {
-                //let start_time = Instant::now();
-                {
-                    // save to file
-                    let state = match runtime_state.read() {
-                        Ok(x) => x,
-                        Err(e) => {
-                            log::error!("runtime failed to acquire state read lock: {}", e);
-                            continue;
-                        }
-                    };
-                    if state.dirty {
-                        // save settings to file
-                        let settings = match runtime_settings.read() {
-                            Ok(x) => x,
-                            Err(e) => {
-                                log::error!("runtime failed to acquire settings read lock: {}", e);
-                                continue;
-                            }
-                        };
-                        let settings_json: Settings = settings.clone().into();
-                        if let Err(e) = settings_json.save(settings_path(&state.home)) {
-                            log::error!(
-                                "SettingsJson.save({}) error: {}",
-                                settings_path(&state.home).display(),
-                                e
-                            );
-                        }
-                        //Self::on_set_enable(&settings, &state);
-                        drop(state);
-                        let mut state = match runtime_state.write() {
-                            Ok(x) => x,
-                            Err(e) => {
-                                log::error!("runtime failed to acquire state write lock: {}", e);
-                                continue;
-                            }
-                        };
-                        state.dirty = false;
-                    }
-                }
-                thread::sleep(sleep_duration);
-            }
-        })
+    pub fn geo_status_clone(&self) -> Arc<RwLock<DownloadStatus>> {
+        self.geo_status.clone()
     }
-}
 
-fn settings_path<P: AsRef<std::path::Path>>(home: P) -> std::path::PathBuf {
-    home.as_ref().join(".config/tomoon/tomoon.json")
-}
+    pub fn running_status_clone(&self) -> Arc<RwLock<RunningStatus>> {
+        self.running_status.clone()
+    }
 
-fn get_current_working_dir() -> std::io::Result<std::path::PathBuf> {
-    std::env::current_dir()
-}
+    pub fn providers_status_clone(&self) -> Arc<RwLock<ProvidersStatus>> {
+        self.providers_status.clone()
+    }
 
-pub struct Clash {
-    pub path: std::path::PathBuf,
-    pub config: std::path::PathBuf,
-    pub instence: Option<Child>,
-    pub smartdns_instence: Option<Child>,
-}
+    pub fn start_progress_clone(&self) -> Arc<RwLock<StartProgress>> {
+        self.start_progress.clone()
+    }
 
-#[derive(Debug, PartialEq, Eq)]
-pub enum ClashErrorKind {
-    ConfigFormatError,
-    ConfigNotFound,
-    RuleProviderDownloadError,
-    NetworkError,
-    CpDbError,
-    InnerError,
-    Default,
-}
+    pub fn active_interface_clone(&self) -> Arc<RwLock<Option<String>>> {
+        self.active_interface.clone()
+    }
 
-#[derive(Debug)]
-pub struct ClashError {
-    pub Message: String,
-    pub ErrorKind: ClashErrorKind,
-}
+    pub fn last_error_clone(&self) -> Arc<RwLock<Option<String>>> {
+        self.last_error.clone()
+    }
 
-impl error::Error for ClashError {}
+    pub fn core_crash_clone(&self) -> Arc<RwLock<Option<CoreCrash>>> {
+        self.core_crash.clone()
+    }
 
-impl Display for ClashError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "Error Kind: {:?}, Error Message: {})",
-            self.ErrorKind, self.Message
-        )
+    /// 标记开始启动，已经有启动在进行时返回 `None`，返回值释放时清除标记
+    fn try_begin_start(&self) -> Option<StartGuard> {
+        self.starting
+            .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+            .ok()
+            .map(|_| StartGuard(self.starting.clone()))
     }
-}
 
-impl ClashError {
-    pub fn new() -> Self {
-        Self {
-            Message: "".to_string(),
-            ErrorKind: ClashErrorKind::Default,
+    fn set_last_error(&self, error: Option<String>) {
+        match self.last_error.write() {
+            Ok(mut x) => *x = error,
+            Err(e) => {
+                log::error!("failed to acquire last_error write lock: {}", e);
+            }
         }
     }
-}
 
-impl Default for Clash {
-    fn default() -> Self {
-        Self {
-            path: get_current_working_dir().unwrap().join("bin/core/clash"),
-            config: get_current_working_dir()
-                .unwrap()
-                .join("bin/core/config.yaml"),
-            instence: None,
-            smartdns_instence: None,
+    /// 下载订阅与 Rule Provider 时使用的请求参数
+    /// 开启 `download_via_proxy` 且 Clash 正在运行时经过本机的代理端口
+    pub fn download_options(&self) -> DownloadOptions {
+        let (mut options, via_proxy) = match self.settings.read() {
+            Ok(x) => (DownloadOptions::from_settings(&x), x.download_via_proxy),
+            Err(e) => {
+                log::error!(
+                    "download_options() failed to acquire settings read lock: {}",
+                    e
+                );
+                (DownloadOptions::from_settings(&Settings::default()), false)
+            }
+        };
+        if via_proxy {
+            let running = read_lock(&self.clash_state, "clash").instence.is_some();
+            options.proxy = running.then(local_proxy).flatten();
         }
+        options
     }
-}
 
-impl Clash {
-    pub fn run(&mut self, config_path: &String, skip_proxy: bool) -> Result<(), ClashError> {
-        //没有 Country.mmdb
-        let country_db_path = "/root/.config/clash/Country.mmdb";
-        if let Some(parent) = PathBuf::from(country_db_path).parent() {
-            if let Err(e) = std::fs::create_dir_all(parent) {
-                log::error!("Failed while creating /root/.config/clash dir.");
-                log::error!("Error Message:{}", e);
-                return Err(ClashError {
-                    ErrorKind: ClashErrorKind::CpDbError,
-                    Message: "Error occurred while creating /root/.config/clash dir.".to_string(),
-                });
+    pub fn log_buffer_clone(&self) -> Arc<RwLock<LogBuffer>> {
+        self.log_buffer.clone()
+    }
+
+    /// 重启 Clash，没有运行时直接启动，返回重启后 Clash 是否在运行
+    pub fn restart_clash(&self) -> Result<bool, ClashError> {
+        let _guard = match self.try_begin_start() {
+            Some(x) => x,
+            None => {
+                log::warn!("Clash is already starting, skip restarting.");
+                return Ok(helper::is_clash_running());
+            }
+        };
+        let mut settings = self.settings.write()?;
+        let mut clash = self.clash_state.write()?;
+        let mut run_status = self.running_status.write()?;
+        *run_status = RunningStatus::Loading;
+        if clash.instence.is_some() {
+            if let Err(e) = clash.stop() {
+                log::error!("Error occurred while stopping Clash: {}", e);
             }
+        } else {
+            log::info!("Clash is not running, starting fresh.");
+            helper::kill_stray_cores();
         }
-        let new_country_db_path = get_current_working_dir()
-            .unwrap()
-            .join("bin/core/Country.mmdb");
-        if !PathBuf::from(country_db_path).is_file() {
-            match fs::copy(new_country_db_path, country_db_path) {
-                Ok(_) => {
-                    log::info!("cp Country.mmdb to .clash dir");
+        let home = read_lock(&self.state, "state").home.clone();
+        let profile = select_profile(&mut settings, &home);
+        //启动时会下载订阅和 Provider，先释放设置和运行状态的锁，前端查询状态时不会阻塞
+        let snapshot = settings.clone();
+        drop(run_status);
+        drop(settings);
+        let result = profile.and_then(|profile| clash.run(&profile, &snapshot, &|_| {}));
+        let current_config = clash.config.clone();
+        drop(clash);
+        let mut settings = write_lock(&self.settings, "settings");
+        let mut run_status = write_lock(&self.running_status, "running_status");
+        settings.enable = result.is_ok();
+        match self.state.write() {
+            Ok(mut x) => x.dirty = true,
+            Err(e) => {
+                log::error!("restart_clash failed to acquire state write lock: {}", e);
+            }
+        }
+        if let Err(e) = result {
+            log::error!("Run clash error: {}", e);
+            *run_status = RunningStatus::Failed;
+            self.set_last_error(Some(e.message.clone()));
+            return Err(e);
+        }
+        self.set_last_error(None);
+        settings.current_config = current_config;
+        *run_status = RunningStatus::Success;
+        log::info!("Clash restarted.");
+        Ok(helper::is_clash_running())
+    }
+
+    /// 切换到新的配置文件并让正在运行的 Clash 重新加载
+    pub fn reload_clash_config(
+        &self,
+        config_path: Option<String>,
+    ) -> Result<ReloadMethod, ClashError> {
+        let mut settings = self.settings.write()?;
+        if !settings.enable {
+            return Err(ClashError {
+                message: "Clash is not running".to_string(),
+                kind: ClashErrorKind::InnerError,
+            });
+        }
+        let mut clash = self.clash_state.write()?;
+        let controller = Controller::from_settings(&settings);
+        //重新加载成功后才保存新的配置，失败时保留原来的订阅
+        let path = config_path.unwrap_or_else(|| settings.current_sub.clone());
+        let result = clash.reload_config(&path, &settings, &controller);
+        if result.is_ok() && settings.current_sub != path {
+            settings.current_sub = path;
+            match self.state.write() {
+                Ok(mut x) => x.dirty = true,
+                Err(e) => {
+                    log::error!(
+                        "reload_clash_config failed to acquire state write lock: {}",
+                        e
+                    );
                 }
+            }
+        }
+        if result.is_err() && !helper::is_clash_running() {
+            //重启失败，Clash 已经不在运行
+            settings.enable = false;
+            match self.state.write() {
+                Ok(mut x) => x.dirty = true,
                 Err(e) => {
-                    log::info!("Error occurred while coping Country.mmdb");
-                    return Err(ClashError {
-                        Message: e.to_string(),
-                        ErrorKind: ClashErrorKind::CpDbError,
-                    });
+                    log::error!(
+                        "reload_clash_config failed to acquire state write lock: {}",
+                        e
+                    );
                 }
             }
         }
-        self.update_config_path(config_path);
-        // 修改配置文件为推荐配置
-        match self.change_config(skip_proxy) {
-            Ok(_) => (),
+        result
+    }
+
+    /// 切换代理模式并保存到设置中，Clash 未运行时返回 `NotRunning`
+    pub fn set_proxy_mode(&self, mode: &str) -> Result<(), ClashError> {
+        let mode = ProxyMode::from_str(mode).map_err(|e| ClashError {
+            message: e,
+            kind: ClashErrorKind::ConfigFormatError,
+        })?;
+        let mut settings = self.settings.write()?;
+        if !settings.enable || !helper::is_clash_running() {
+            return Err(ClashError {
+                message: "Clash is not running".to_string(),
+                kind: ClashErrorKind::NotRunning,
+            });
+        }
+        Controller::from_settings(&settings).set_mode(mode)?;
+        settings.proxy_mode = mode;
+        match self.state.write() {
+            Ok(mut x) => x.dirty = true,
             Err(e) => {
-                return Err(ClashError {
-                    Message: e.to_string(),
-                    ErrorKind: ClashErrorKind::ConfigFormatError,
-                });
+                log::error!("set_proxy_mode failed to acquire state write lock: {}", e);
             }
         }
-        //在 clash 启动前修改 DNS
-        //先结束 systemd-resolve ，否则会因为端口占用启动失败
-        match helper::set_system_network() {
+        log::info!("Proxy mode changed to {}", mode.as_str());
+        Ok(())
+    }
+
+    /// 下载新的 Clash 核心并替换 `bin/core/clash`，下载进度写入 `core_status`
+    pub fn update_core(&self, download_url: &str, expected_sha256: &str) -> Result<(), ClashError> {
+        let update_status = |status: DownloadStatus| match self.core_status.write() {
+            Ok(mut x) => *x = status,
+            Err(e) => {
+                log::error!(
+                    "update_core() failed to acquire core_status write lock: {}",
+                    e
+                );
+            }
+        };
+        update_status(DownloadStatus::downloading());
+        let result = self.replace_core(download_url, expected_sha256, &|received, total| {
+            update_status(DownloadStatus::Downloading { received, total })
+        });
+        match result {
             Ok(_) => {
-                log::info!("Successfully set network status");
+                log::info!("Clash core updated from {}", download_url);
+                update_status(DownloadStatus::Success);
+                Ok(())
             }
             Err(e) => {
-                log::error!("Error occurred while setting system network: {}", e);
-                return Err(ClashError {
-                    Message: e.to_string(),
-                    ErrorKind: ClashErrorKind::NetworkError,
-                });
+                log::error!("Update Clash core error: {}", e);
+                update_status(DownloadStatus::Error);
+                Err(e)
             }
         }
+    }
 
-        //log::info!("Pre-setting network");
-        //TODO: 未修改的 unwarp
-        let run_config = get_current_working_dir()
-            .unwrap()
-            .join("bin/core/running_config.yaml");
-        let outputs = fs::File::create("/tmp/tomoon.clash.log").unwrap();
-        let errors = outputs.try_clone().unwrap();
-
-        let smartdns_path = get_current_working_dir()
-            .unwrap()
-            .join("bin/smartdns/smartdns");
-
-        let smartdns_config_path = get_current_working_dir()
-            .unwrap()
-            .join("bin/smartdns/config.conf");
-
-        // let smartdns_outputs = fs::File::create("/tmp/tomoon.smartdns.log").unwrap();
-        // let smartdns_errors = outputs.try_clone().unwrap();
-
-        // 启动 SmartDNS 作为 DNS 上游
-        let smart_dns = Command::new(smartdns_path)
-            .arg("-c")
-            .arg(smartdns_config_path)
-            .arg("-f")
-            // .stdout(smartdns_outputs)
-            // .stderr(smartdns_errors)
-            .spawn();
-
-        let clash = Command::new(self.path.clone())
-            .arg("-f")
-            .arg(run_config)
-            .stdout(outputs)
-            .stderr(errors)
-            .spawn();
-        let clash: Result<Child, ClashError> = match clash {
-            Ok(x) => Ok(x),
+    /// 下载设置中的 GeoIP / GeoSite 数据库到 `bin/core/`，全部下载成功后才替换文件
+    /// 进度写入 `geo_status`
+    pub fn update_geo_database(&self) -> Result<(), ClashError> {
+        let update_status = |status: DownloadStatus| match self.geo_status.write() {
+            Ok(mut x) => *x = status,
             Err(e) => {
-                log::error!("run Clash failed: {}", e);
-                //TODO: 开启 Clash 的错误处理
-                return Err(ClashError::new());
+                log::error!(
+                    "update_geo_database() failed to acquire geo_status lock: {}",
+                    e
+                );
             }
         };
-        self.instence = Some(clash.unwrap());
-        self.smartdns_instence = Some(smart_dns.unwrap());
-        Ok(())
+        let databases = self.settings.read()?.geo_databases.clone();
+        update_status(DownloadStatus::downloading());
+        let result = download_geo_databases(&databases, &|received, total| {
+            update_status(DownloadStatus::Downloading { received, total })
+        });
+        match result {
+            Ok(_) => {
+                log::info!("Geo databases updated.");
+                update_status(DownloadStatus::Success);
+                Ok(())
+            }
+            Err(e) => {
+                log::error!("Update geo databases error: {}", e);
+                update_status(DownloadStatus::Error);
+                Err(e)
+            }
+        }
     }
 
-    pub fn stop(&mut self) -> Result<(), Box<dyn error::Error>> {
-        let instance = self.instence.as_mut();
-        match instance {
-            Some(x) => {
-                x.kill()?;
-                x.wait()?;
+    fn replace_core(
+        &self,
+        download_url: &str,
+        expected_sha256: &str,
+        on_progress: &dyn Fn(u64, Option<u64>),
+    ) -> Result<(), ClashError> {
+        let core_type = self.settings.read()?.core_type;
+        let core_path = install_path(core_type.core_path())?;
+        let temp_path = core_path.with_extension("download");
+        let body = download_core(download_url, on_progress)?;
+        verify_checksum(&body, expected_sha256)?;
+        save_core(&body, &temp_path)?;
+        if let Err(e) = verify_core(&temp_path) {
+            let _ = fs::remove_file(&temp_path);
+            return Err(e);
+        }
 
-                // 复原 DNS
-                //弃用，因为可能是在中途换过 WiFi 导致原有 DNS 失效
-                // Command::new("chattr")
-                //     .arg("-i")
-                //     .arg("/etc/resolv.conf")
-                //     .spawn()
-                //     .unwrap()
-                //     .wait()
-                //     .unwrap();
-                // fs::copy("./resolv.conf.bk", "/etc/resolv.conf")?;
+        let mut clash = self.clash_state.write()?;
+        // 必须先停止 Clash 再替换
+        let was_running = clash.instence.is_some();
+        if was_running {
+            clash.stop()?;
+        }
+        let result = swap_core(&temp_path, &core_path);
+        if result.is_err() {
+            let _ = fs::remove_file(&temp_path);
+        }
+        //新核心可能不是 Meta，重新检测
+        clash.meta_core = None;
+        drop(clash);
+        //替换失败时旧的核心已经放回原处，同样需要恢复运行
+        if was_running {
+            self.restart_clash()?;
+        }
+        result
+    }
 
-                //直接重置网络
-                helper::reset_system_network()?;
+    /// 下载订阅并保存到设置中，进度写入 `download_status`，返回订阅 id
+    pub fn add_subscription(&self, url: &str) -> Result<String, ClashError> {
+        let update_status = |status: DownloadStatus| match self.download_status.write() {
+            Ok(mut x) => *x = status,
+            Err(e) => {
+                log::error!(
+                    "add_subscription failed to acquire download_status write lock: {}",
+                    e
+                );
             }
-            None => {
-                //Not launch Clash yet...
-                log::error!("Error occurred while disabling Clash: Not launch Clash yet");
+        };
+        let home = self.state.read()?.home.clone();
+        update_status(DownloadStatus::downloading());
+        let path = match subscription::save_subscription(&home, url, &self.download_options()) {
+            Ok(x) => x,
+            Err(e) => {
+                log::error!("Failed while downloading sub {}: {}", url, e);
+                update_status(if e.kind == ClashErrorKind::NetworkError {
+                    DownloadStatus::Failed
+                } else {
+                    DownloadStatus::Error
+                });
+                return Err(e);
             }
         };
-        let smartdns_instance = self.smartdns_instence.as_mut();
-        match smartdns_instance {
-            Some(x) => {
-                x.kill()?;
-                x.wait()?;
+        let path = path.to_string_lossy().to_string();
+        let mut settings = self.settings.write().map_err(|e| {
+            update_status(DownloadStatus::Error);
+            ClashError::from(e)
+        })?;
+        //重复添加同一个链接时只更新文件
+        if !settings.subscriptions.iter().any(|x| x.path == path) {
+            settings
+                .subscriptions
+                .push(Subscription::new(path, url.to_string()));
+        }
+        drop(settings);
+        match self.state.write() {
+            Ok(mut x) => x.dirty = true,
+            Err(e) => {
+                log::error!("add_subscription failed to acquire state write lock: {}", e);
             }
-            None => {
-                log::error!("Error occurred while disabling SmartDNS : Not launch SmartDNS yet");
+        }
+        log::info!("Download profile successfully.");
+        update_status(DownloadStatus::Success);
+        Ok(subscription::subscription_id(url))
+    }
+
+    /// 保存直接导入的配置文本，与订阅一起出现在 `list_subscriptions` 中，返回保存的路径
+    pub fn add_config_from_text(&self, name: &str, text: &str) -> Result<String, ClashError> {
+        let home = self.state.read()?.home.clone();
+        let path = subscription::save_config_text(&home, name, text)?;
+        let path = path.to_string_lossy().to_string();
+        let mut settings = self.settings.write()?;
+        //使用本地文件链接，update_subs 会跳过
+        if !settings.subscriptions.iter().any(|x| x.path == path) {
+            settings
+                .subscriptions
+                .push(Subscription::new(path.clone(), format!("file://{}", path)));
+        }
+        drop(settings);
+        match self.state.write() {
+            Ok(mut x) => x.dirty = true,
+            Err(e) => {
+                log::error!(
+                    "add_config_from_text failed to acquire state write lock: {}",
+                    e
+                );
             }
-        };
-        Ok(())
+        }
+        log::info!("Imported config {} to {}", name, path);
+        Ok(path)
     }
 
-    pub fn update_config_path(&mut self, path: &String) {
-        self.config = std::path::PathBuf::from((*path).clone());
+    /// 返回按设置改写后的配置，不写入运行配置也不下载 Provider，不影响正在运行的 Clash
+    pub fn preview_config(&self, config_path: &str) -> Result<String, ClashError> {
+        let settings = self.settings.read()?.clone();
+        let mut clash = Clash::default();
+        clash.preview_config(&config_path.to_string(), &settings)
     }
 
-    pub fn change_config(&self, skip_proxy: bool) -> Result<(), Box<dyn error::Error>> {
-        let path = self.config.clone();
-        let config = fs::read_to_string(path)?;
-        let mut yaml: serde_yaml::Value = serde_yaml::from_str(config.as_str())?;
-        let yaml = yaml.as_mapping_mut().unwrap();
+    /// 运行中的 `mixed-port`，来自生成的运行配置，未运行或没有设置时返回 `None`
+    pub fn active_mixed_port(&self) -> Option<u16> {
+        match self.clash_state.read() {
+            Ok(x) if x.instence.is_some() => {}
+            _ => return None,
+        }
+        let config = fs::read_to_string(running_config_path().ok()?).ok()?;
+        let yaml: Value = serde_yaml::from_str(&config).ok()?;
+        config_port(&yaml, "mixed-port")
+    }
 
-        //修改 WebUI
+    /// 运行中的核心实际使用的模式、端口、TUN 和 DNS 参数
+    pub fn runtime_info(&self) -> Result<RuntimeInfo, ClashError> {
+        if read_lock(&self.clash_state, "clash").instence.is_none() {
+            return Err(ClashError::new(
+                ClashErrorKind::NotRunning,
+                "Clash is not running.",
+            ));
+        }
+        let running = self.controller().configs()?;
+        let config = fs::read_to_string(running_config_path()?)?;
+        let generated: Value = serde_yaml::from_str(&config)?;
+        Ok(RuntimeInfo::new(&running, &generated))
+    }
 
-        match yaml.get_mut("external-controller") {
-            Some(x) => {
-                *x = Value::String(String::from("127.0.0.1:9090"));
+    pub fn list_subscriptions(&self) -> Vec<SubscriptionEntry> {
+        match self.settings.read() {
+            Ok(x) => x
+                .subscriptions
+                .iter()
+                .map(SubscriptionEntry::from)
+                .collect(),
+            Err(e) => {
+                log::error!(
+                    "list_subscriptions failed to acquire settings read lock: {}",
+                    e
+                );
+                Vec::new()
             }
+        }
+    }
+
+    /// 当前配置的来源订阅与运行配置的生成时间
+    pub fn active_config_info(&self) -> Result<ActiveConfigInfo, ClashError> {
+        let config_path = self
+            .clash_state
+            .read()?
+            .config
+            .to_string_lossy()
+            .to_string();
+        let subscription = self
+            .settings
+            .read()?
+            .subscriptions
+            .iter()
+            .find(|x| x.path == config_path)
+            .map(SubscriptionEntry::from);
+        let running_config = running_config_path()?;
+        let generated_at = fs::metadata(&running_config)
+            .and_then(|x| x.modified())
+            .ok()
+            .and_then(|x| x.duration_since(UNIX_EPOCH).ok())
+            .map(|x| x.as_secs());
+        Ok(ActiveConfigInfo {
+            subscription,
+            config_path,
+            running_config: running_config.to_string_lossy().to_string(),
+            generated_at,
+        })
+    }
+
+    /// 设置订阅的自动更新间隔，`None` 表示使用全局默认值
+    pub fn set_subscription_interval(
+        &self,
+        id: &str,
+        interval: Option<u64>,
+    ) -> Result<(), ClashError> {
+        let mut settings = self.settings.write()?;
+        let index = match subscription::find_subscription(&settings.subscriptions, id) {
+            Some(x) => x,
             None => {
-                yaml.insert(
-                    Value::String(String::from("external-controller")),
-                    Value::String(String::from("127.0.0.1:9090")),
+                return Err(ClashError {
+                    message: format!("subscription {} not found", id),
+                    kind: ClashErrorKind::ConfigNotFound,
+                });
+            }
+        };
+        settings.subscriptions[index].update_interval = interval;
+        drop(settings);
+        match self.state.write() {
+            Ok(mut x) => x.dirty = true,
+            Err(e) => {
+                log::error!(
+                    "set_subscription_interval failed to acquire state write lock: {}",
+                    e
                 );
             }
         }
+        Ok(())
+    }
 
-        //修改 test.steampowered.com
-        //这个域名用于 Steam Deck 网络连接验证，可以直连
-        if let Some(x) = yaml.get_mut("rules") {
-            let rules = x.as_sequence_mut().unwrap();
-            rules.insert(
-                0,
-                Value::String(String::from("DOMAIN,test.steampowered.com,DIRECT")),
-            );
-
-            if skip_proxy {
-                rules.insert(
-                0,
-                Value::String(String::from("DOMAIN-SUFFIX,cm.steampowered.com,DIRECT")),
-            );
+    /// 删除订阅文件并从设置中移除，正在使用的订阅会被取消选择
+    pub fn delete_subscription(&self, id: &str) -> Result<(), ClashError> {
+        let mut settings = self.settings.write()?;
+        let index = match subscription::find_subscription(&settings.subscriptions, id) {
+            Some(x) => x,
+            None => {
+                return Err(ClashError {
+                    message: format!("subscription {} not found", id),
+                    kind: ClashErrorKind::ConfigNotFound,
+                });
+            }
+        };
+        let sub = settings.subscriptions.remove(index);
+        match fs::remove_file(&sub.path) {
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                log::warn!("Subscription file {} already removed.", sub.path);
+            }
+            Err(e) => {
+                log::error!("delete file error: {}", e);
+            }
+        }
+        if settings.current_sub == sub.path {
+            settings.current_sub = String::new();
+        }
+        drop(settings);
+        match self.state.write() {
+            Ok(mut x) => x.dirty = true,
+            Err(e) => {
+                log::error!(
+                    "delete_subscription failed to acquire state write lock: {}",
+                    e
+                );
             }
         }
+        Ok(())
+    }
 
-        //下载 rules-provider
-        if let Some(x) = yaml.get_mut("rule-providers") {
-            let provider = x.as_mapping().unwrap();
-            match self.downlaod_proxy_providers(provider) {
-                Ok(_) => {
-                    log::info!("All rules provider downloaded");
+    /// 恢复到初始状态：停止 Clash、复原网络、删除运行配置和 Rule Provider，设置恢复默认
+    /// 订阅默认保留，`delete_subscriptions` 为 true 时一起删除
+    pub fn reset_all(&self, delete_subscriptions: bool) -> Result<(), ClashError> {
+        let mut settings = self.settings.write()?;
+        let provider_base = {
+            let mut clash = self.clash_state.write()?;
+            if clash.instence.is_some() {
+                if let Err(e) = clash.stop() {
+                    log::error!("Error occurred while stopping Clash: {}", e);
                 }
-                Err(e) => return Err(Box::new(e)),
             }
-        } else {
-            log::info!("no rule-providers found.");
+            clash.provider_dir.clone()
+        };
+        if helper::is_clash_running() {
+            helper::kill_stray_cores();
         }
+        restore_resolv_conf();
+        helper::reset_system_network()
+            .map_err(|e| ClashError::new(ClashErrorKind::InnerError, e.to_string()))?;
 
-        let webui_dir = get_current_working_dir()?.join("bin/core/web");
+        let remove = |path: &Path| {
+            let result = if path.is_dir() {
+                fs::remove_dir_all(path)
+            } else {
+                fs::remove_file(path)
+            };
+            match result {
+                Ok(_) => log::info!("Removed {}", path.display()),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => log::error!("Error occurred while removing {}: {}", path.display(), e),
+            }
+        };
+        if let Ok(x) = running_config_path() {
+            remove(&x);
+        }
+        //Rule Provider 和 Country.mmdb，启动时会重新下载和复制
+        //`/root/.config/clash` 中可能有用户自己的配置，只删除复制进去的 Country.mmdb
+        remove(&provider_base);
+        remove(Path::new("/root/.config/clash/Country.mmdb"));
 
-        match yaml.get_mut("external-ui") {
-            Some(x) => {
-                //TODO: 修改 Web UI 的路径
-                *x = Value::String(String::from(webui_dir.to_str().unwrap()));
+        let mut defaults = Settings::default();
+        if delete_subscriptions {
+            if let Ok(x) = self.state.read() {
+                remove(&subscription::subs_dir(&x.home));
             }
-            None => {
-                yaml.insert(
-                    Value::String(String::from("external-ui")),
-                    Value::String(String::from(webui_dir.to_str().unwrap())),
-                );
+        } else {
+            defaults.subscriptions = std::mem::take(&mut settings.subscriptions);
+            defaults.current_sub = std::mem::take(&mut settings.current_sub);
+        }
+        *settings = defaults;
+        drop(settings);
+
+        if let Ok(mut x) = self.running_status.write() {
+            *x = RunningStatus::None;
+        }
+        self.set_last_error(None);
+        match self.state.write() {
+            Ok(mut x) => x.dirty = true,
+            Err(e) => {
+                log::error!("reset_all failed to acquire state write lock: {}", e);
             }
         }
+        self.flush();
+        log::info!(
+            "Reset all settings, subscriptions deleted: {}",
+            delete_subscriptions
+        );
+        Ok(())
+    }
 
-        //修改 TUN 和 DNS 配置
+    /// 导出设置和订阅，返回压缩包路径
+    pub fn export_profile(&self) -> Result<PathBuf, ClashError> {
+        let home = self.state.read()?.home.clone();
+        profile::export_profile(&*self.settings.read()?, &home)
+    }
 
-        let tun_config = "
-        enable: true
-        stack: system
-        auto-route: true
-        auto-detect-interface: true
-        ";
+    /// 导入 `export_profile` 生成的压缩包，运行时记录的字段保持不变
+    pub fn import_profile(&self, archive: &Path, mode: ImportMode) -> Result<(), ClashError> {
+        let home = self.state.read()?.home.clone();
+        let mut settings = self.settings.write()?;
+        let mut imported = profile::import_profile(archive, &home, &settings, mode)?;
+        imported.enable = settings.enable;
+        imported.current_config = settings.current_config.clone();
+        imported.auto_start_failures = settings.auto_start_failures;
+        *settings = imported;
+        drop(settings);
+        match self.state.write() {
+            Ok(mut x) => x.dirty = true,
+            Err(e) => {
+                log::error!("import_profile failed to acquire state write lock: {}", e);
+            }
+        }
+        self.flush();
+        log::info!("Imported profile from {} ({:?})", archive.display(), mode);
+        Ok(())
+    }
 
-        //部分配置来自 https://www.xkww3n.cyou/2022/02/08/use-clash-dns-anti-dns-hijacking/
+    /// 从 external-controller 读取代理组，Clash 未运行时返回 `NotRunning`
+    pub fn get_proxies(&self) -> Result<Vec<ProxyGroup>, ClashError> {
+        if !helper::is_clash_running() {
+            return Err(ClashError {
+                message: "Clash is not running".to_string(),
+                kind: ClashErrorKind::NotRunning,
+            });
+        }
+        self.controller().proxy_groups()
+    }
 
-        let dns_config = match helper::is_resolve_running() {
-            true => {
-                "
-        enable: true
-        listen: 0.0.0.0:5354
-        enhanced-mode: fake-ip
-        fake-ip-range: 198.18.0.1/16
-        nameserver:
-            - tcp://127.0.0.1:5353
-        "
+    /// 核心版本，运行时从 external-controller 获取，否则读取 `-v` 的输出
+    pub fn core_version(&self) -> Result<String, ClashError> {
+        if helper::is_clash_running() {
+            match self.controller().version() {
+                Ok(x) => {
+                    let version = serde_json::from_str::<serde_json::Value>(&x)
+                        .ok()
+                        .and_then(|x| x["version"].as_str().map(|x| x.to_string()));
+                    if let Some(x) = version {
+                        return Ok(x);
+                    }
+                }
+                Err(e) => log::warn!("Error occurred while querying core version: {}", e),
             }
-            false => {
-                "
-        enable: true
-        listen: 0.0.0.0:53
-        enhanced-mode: fake-ip
-        fake-ip-range: 198.18.0.1/16
-        nameserver:
-            - tcp://127.0.0.1:5353
-        "
+        }
+        let core_type = self
+            .settings
+            .read()
+            .map(|x| x.core_type)
+            .unwrap_or_default();
+        let core_path = install_path(core_type.core_path())?;
+        let modified = fs::metadata(&core_path)
+            .and_then(|x| x.modified())
+            .map_err(|e| ClashError {
+                message: format!("{}: {}", core_path.display(), e),
+                kind: ClashErrorKind::CoreNotFound,
+            })?;
+        if let Ok(x) = self.core_version.read() {
+            if let Some((path, time, version)) = x.as_ref() {
+                if *path == core_path && *time == modified {
+                    return Ok(version.clone());
+                }
             }
-        };
+        }
+        let output = Command::new(&core_path)
+            .arg("-v")
+            .output()
+            .map_err(|e| ClashError {
+                message: format!("Failed to run {}: {}", core_path.display(), e),
+                kind: ClashErrorKind::CoreNotFound,
+            })?;
+        let version =
+            parse_core_version(&String::from_utf8_lossy(&output.stdout)).ok_or(ClashError {
+                message: "Failed to parse the core version".to_string(),
+                kind: ClashErrorKind::InnerError,
+            })?;
+        if let Ok(mut x) = self.core_version.write() {
+            *x = Some((core_path, modified, version.clone()));
+        }
+        Ok(version)
+    }
+
+    pub fn clash_health(&self) -> ClashHealth {
+        if !helper::is_clash_running() {
+            return ClashHealth::NotRunning;
+        }
+        match self.controller().version() {
+            Ok(_) => ClashHealth::Healthy,
+            Err(e) => {
+                log::warn!("Clash is running but the controller is unresponsive: {}", e);
+                ClashHealth::Unresponsive
+            }
+        }
+    }
 
-        let profile_config = "
-        store-selected: true
-        store-fake-ip: false
-        ";
+    /// 当前的上传与下载速度，Clash 未运行时返回 `NotRunning`
+    pub fn get_traffic(&self) -> Result<Traffic, ClashError> {
+        if !helper::is_clash_running() {
+            return Err(ClashError {
+                message: "Clash is not running".to_string(),
+                kind: ClashErrorKind::NotRunning,
+            });
+        }
+        self.controller().traffic()
+    }
 
-        let insert_config = |yaml: &mut Mapping, config: &str, key: &str| {
-            let inner_config: Value = serde_yaml::from_str(config).unwrap();
-            yaml.insert(Value::String(String::from(key)), inner_config);
+    /// 替换全部设置，`enable` 变化时同时启动或关闭 Clash，运行时记录的字段保持不变
+    pub fn update_settings(&self, new_settings: Settings) -> Result<(), ClashError> {
+        new_settings.validate().map_err(|e| ClashError {
+            message: e,
+            kind: ClashErrorKind::ConfigFormatError,
+        })?;
+        let enable = new_settings.enable;
+        let was_enabled = {
+            let mut settings = self.settings.write()?;
+            let was_enabled = settings.enable;
+            let mut new_settings = new_settings;
+            new_settings.enable = was_enabled;
+            new_settings.current_config = settings.current_config.clone();
+            new_settings.auto_start_failures = settings.auto_start_failures;
+            *settings = new_settings;
+            was_enabled
         };
+        match self.state.write() {
+            Ok(mut x) => x.dirty = true,
+            Err(e) => {
+                log::error!("update_settings failed to acquire state write lock: {}", e);
+            }
+        }
+        if enable != was_enabled {
+            self.set_clash_enable(enable, &|_| {})?;
+        }
+        Ok(())
+    }
+
+    /// 生效中的规则，Clash 未运行时返回 `NotRunning`
+    pub fn get_rules(&self) -> Result<Vec<Rule>, ClashError> {
+        if !helper::is_clash_running() {
+            return Err(ClashError {
+                message: "Clash is not running".to_string(),
+                kind: ClashErrorKind::NotRunning,
+            });
+        }
+        self.controller().rules()
+    }
 
-        //开启 tun 模式
-        match yaml.get("tun") {
-            Some(_) => {
-                yaml.remove("tun").unwrap();
-                insert_config(yaml, tun_config, "tun");
+    /// 活动连接列表，Clash 未运行时返回 `NotRunning`
+    pub fn get_connections(&self) -> Result<Vec<Connection>, ClashError> {
+        if !helper::is_clash_running() {
+            return Err(ClashError {
+                message: "Clash is not running".to_string(),
+                kind: ClashErrorKind::NotRunning,
+            });
+        }
+        self.controller().connections()
+    }
+
+    /// 关闭连接，id 不存在时返回 `ConfigNotFound`
+    pub fn close_connection(&self, id: &str) -> Result<(), ClashError> {
+        if !helper::is_clash_running() {
+            return Err(ClashError {
+                message: "Clash is not running".to_string(),
+                kind: ClashErrorKind::NotRunning,
+            });
+        }
+        self.controller().close_connection(id)
+    }
+
+    /// 在代理组中选择节点，Clash 未运行时返回 `NotRunning`
+    pub fn select_proxy(&self, group: &str, node: &str) -> Result<(), ClashError> {
+        if !helper::is_clash_running() {
+            return Err(ClashError {
+                message: "Clash is not running".to_string(),
+                kind: ClashErrorKind::NotRunning,
+            });
+        }
+        self.controller().select_proxy(group, node)?;
+        log::info!("Selected {} in proxy group {}", node, group);
+        Ok(())
+    }
+
+    /// 测试节点延迟，Clash 未运行时返回 `NotRunning`
+    pub fn test_proxy_delay(
+        &self,
+        node: &str,
+        test_url: &str,
+        timeout: u64,
+    ) -> Result<DelayResult, ClashError> {
+        if !helper::is_clash_running() {
+            return Err(ClashError {
+                message: "Clash is not running".to_string(),
+                kind: ClashErrorKind::NotRunning,
+            });
+        }
+        self.controller().proxy_delay(node, test_url, timeout)
+    }
+
+    /// 后端退出前停止 Clash 并复原系统网络，已经停止过时不再重复处理
+    pub fn shutdown(&self) {
+        let mut clash = match self.clash_state.write() {
+            Ok(x) => x,
+            Err(e) => {
+                log::error!("shutdown failed to acquire clash write lock: {}", e);
+                return;
             }
-            None => {
-                insert_config(yaml, tun_config, "tun");
+        };
+        if clash.instence.is_none() {
+            log::info!("Clash is not running, nothing to clean up.");
+            return;
+        }
+        match clash.stop() {
+            Ok(_) => log::info!("Clash stopped and network restored."),
+            Err(e) => {
+                log::error!("Error occurred while stopping Clash on shutdown: {}", e);
+                //仍然尝试复原网络
+                if clash.manage_dns {
+                    if let Err(e) = helper::reset_system_network() {
+                        log::error!("Error occurred while resetting system network: {}", e);
+                    }
+                }
             }
         }
+    }
 
-        match yaml.get("dns") {
-            Some(_) => {
-                //删除 DNS 配置
-                yaml.remove("dns").unwrap();
-                insert_config(yaml, dns_config, "dns");
+    /// 根据当前设置构造 external-controller 客户端
+    pub fn controller(&self) -> Controller {
+        match self.settings.read() {
+            Ok(x) => Controller::from_settings(&x),
+            Err(e) => {
+                log::error!("controller() failed to acquire settings read lock: {}", e);
+                Controller::from_settings(&Settings::default())
             }
-            None => {
-                insert_config(yaml, dns_config, "dns");
+        }
+    }
+
+    /// 启动前确认网络可用，离线时返回 `Offline`，设置中关闭检查或已经启动时跳过
+    pub fn preflight_network_check(&self) -> Result<(), ClashError> {
+        let settings = self.settings.read()?;
+        if !settings.preflight_check || settings.enable {
+            return Ok(());
+        }
+        drop(settings);
+        if helper::check_connectivity(PREFLIGHT_TIMEOUT) {
+            Ok(())
+        } else {
+            log::warn!("Network preflight check failed, no connectivity.");
+            Err(ClashError::new(
+                ClashErrorKind::Offline,
+                "No network connection, Clash may not work",
+            ))
+        }
+    }
+
+    /// 在后台线程启动 Clash，下载订阅和 Rule Provider 时不阻塞调用方
+    /// 结果通过 `running_status` 和 `last_error` 查询，已经启用或正在启动时返回 false
+    pub fn spawn_start(&self) -> bool {
+        let enabled = self.settings.read().map(|x| x.enable).unwrap_or(false);
+        if enabled || self.starting.load(Ordering::SeqCst) {
+            return false;
+        }
+        //调用方返回后立即轮询，先标记为启动中
+        match self.running_status.write() {
+            Ok(mut x) => *x = RunningStatus::Loading,
+            Err(e) => {
+                log::error!("spawn_start failed to acquire run status write lock: {}", e);
             }
         }
+        let runtime = self.clone();
+        thread::spawn(move || {
+            if let Err(e) = runtime.set_clash_enable(true, &|_| {}) {
+                log::error!("Error occurred while starting Clash in background: {}", e);
+            }
+        });
+        true
+    }
 
-        // 保存上次的配置
-        match yaml.get("profile") {
-            Some(_) => {
-                yaml.remove("profile").unwrap();
-                insert_config(yaml, profile_config, "profile");
+    /// 启动或关闭 Clash，并同步 `Settings.enable` 和运行状态
+    pub fn set_clash_enable(
+        &self,
+        enabled: bool,
+        progress: &dyn Fn(StartEvent),
+    ) -> Result<(), ClashError> {
+        let mut settings = self.settings.write()?;
+        if settings.enable == enabled {
+            return Ok(());
+        }
+        let _guard = if enabled {
+            match self.try_begin_start() {
+                Some(x) => Some(x),
+                None => {
+                    log::warn!("Clash is already starting, skip starting again.");
+                    return Ok(());
+                }
             }
-            None => {
-                insert_config(yaml, profile_config, "profile");
+        } else {
+            None
+        };
+        let mut clash = self.clash_state.write()?;
+        //已经在运行时不再启动第二个核心
+        if enabled && clash.instence.is_some() {
+            log::info!("Clash is already running.");
+            settings.enable = true;
+            if let Ok(mut x) = self.state.write() {
+                x.dirty = true;
             }
+            return Ok(());
         }
-
-        let run_config = get_current_working_dir()?.join("bin/core/running_config.yaml");
-
-        let yaml_str = serde_yaml::to_string(&yaml)?;
-        fs::write(run_config, yaml_str)?;
+        if enabled && helper::is_clash_running() {
+            helper::kill_stray_cores();
+        }
+        let mut run_status = self.running_status.write()?;
+        *run_status = RunningStatus::Loading;
+        if enabled {
+            let home = read_lock(&self.state, "state").home.clone();
+            let profile = match select_profile(&mut settings, &home) {
+                Ok(x) => x,
+                Err(e) => {
+                    *run_status = RunningStatus::Failed;
+                    return Err(e);
+                }
+            };
+            //启动时会下载订阅和 Provider，先释放设置和运行状态的锁，前端查询状态时不会阻塞
+            let snapshot = settings.clone();
+            drop(run_status);
+            drop(settings);
+            let result = clash.run(&profile, &snapshot, progress);
+            let current_config = clash.config.clone();
+            drop(clash);
+            settings = write_lock(&self.settings, "settings");
+            run_status = write_lock(&self.running_status, "running_status");
+            if let Err(e) = result {
+                log::error!("Run clash error: {}", e);
+                *run_status = RunningStatus::Failed;
+                self.set_last_error(Some(e.message.clone()));
+                return Err(e);
+            }
+            self.set_last_error(None);
+            *write_lock(&self.core_crash, "core_crash") = None;
+            settings.current_config = current_config;
+            settings.auto_start_failures = 0;
+        } else {
+            // Disable Clash
+            match clash.stop() {
+                Ok(_) => {
+                    log::info!("successfully disable clash");
+                }
+                Err(e) => {
+                    log::error!("Disable clash error: {}", e);
+                    *run_status = RunningStatus::Failed;
+                    return Err(e);
+                }
+            }
+        }
+        settings.enable = enabled;
+        let mut state = match self.state.write() {
+            Ok(x) => x,
+            Err(e) => {
+                log::error!("set_enable failed to acquire state write lock: {}", e);
+                *run_status = RunningStatus::Failed;
+                return Err(ClashError::from(e));
+            }
+        };
+        state.dirty = true;
+        *run_status = RunningStatus::Success;
+        log::debug!("set_enable({}) success", enabled);
         Ok(())
     }
 
-    pub fn downlaod_proxy_providers(&self, yaml: &serde_yaml::Mapping) -> Result<(), ClashError> {
-        for (_, value) in yaml {
-            if let Some(url) = value.get("url") {
-                if let Some(path) = value.get("path") {
-                    //替换有些规则前的 ./
-                    let r = regex::Regex::new(r"^\./").unwrap();
-                    let result = r.replace(path.as_str().unwrap(), "");
-                    let save_path = PathBuf::from("/root/.config/clash/").join(result.to_string());
-                    if !save_path.exists() {
-                        match minreq::get(url.as_str().unwrap())
-                            .with_timeout(30)
-                            .with_header("User-Agent", format!("ToMoonClash/{}",env!("CARGO_PKG_VERSION")))
-                            .send()
-                        {
-                            Ok(response) => {
-                                let response = match response.as_str() {
-                                    Ok(x) => x,
-                                    Err(_) => {
-                                        log::error!("Error occurred while parase Rule Provder.");
-                                        return Err(ClashError {
-                                            ErrorKind: ClashErrorKind::RuleProviderDownloadError,
-                                            Message: String::from(
-                                                "Error occurred while parase Rule Provder.",
-                                            ),
-                                        });
-                                    }
-                                };
-
-                                //保存订阅
-                                if let Some(parent) = save_path.parent() {
-                                    if let Err(e) = std::fs::create_dir_all(parent) {
-                                        log::error!("Failed while creating sub dir.");
-                                        log::error!("Error Message:{}", e);
-                                        return Err(ClashError {
-                                            ErrorKind: ClashErrorKind::RuleProviderDownloadError,
-                                            Message:
-                                                "Error occurred while creating Rule Provder dir."
-                                                    .to_string(),
-                                        });
-                                    }
-                                }
-
-                                match fs::write(save_path.clone(), response) {
-                                    Ok(_) => {
-                                        log::info!(
-                                            "Rule-Provider {} downloaded.",
-                                            save_path.display()
-                                        );
-                                    }
-                                    Err(_) => {
-                                        log::error!(
-                                            "Error occurred while saving Rule Provder. path: {}",
-                                            save_path.clone().to_str().unwrap()
-                                        );
-                                        return Err(ClashError {
-                                            ErrorKind: ClashErrorKind::RuleProviderDownloadError,
-                                            Message:
-                                                "Error occurred while downloading Rule Provder."
-                                                    .to_string(),
-                                        });
-                                    }
-                                }
-                            }
-                            Err(e) => {
-                                let in_msg = e.to_string();
-                                let mut err_msg = String::from("Error occurred while downloading Rule Provder with error message : ");
-                                err_msg.push_str(in_msg.as_str());
-                                return Err(ClashError {
-                                    ErrorKind: ClashErrorKind::RuleProviderDownloadError,
-                                    Message: err_msg,
-                                });
-                            }
-                        }
+    pub fn run(&self) -> thread::JoinHandle<()> {
+        let runtime_settings = self.settings_clone();
+        //插件重载或崩溃后重新运行时不会自动启动
+        let clean_boot = !Path::new(BOOT_MARKER_PATH).exists();
+        if let Err(e) = fs::write(BOOT_MARKER_PATH, "") {
+            log::error!("Failed to write boot marker: {}", e);
+        }
+
+        //health check
+        //当程序上次异常退出时的处理
+        if let Ok(mut v) = runtime_settings.write() {
+            if !helper::is_clash_running() && v.enable {
+                v.enable = false;
+                let manage_dns = v.manage_system_dns;
+                drop(v);
+                //从备份恢复 resolv.conf 并刷新网卡，复原上次遗留的 DNS 设置
+                log::warn!("Clash is enabled but not running, resetting system network.");
+                if manage_dns {
+                    let host = read_lock(&self.clash_state, "clash").host.clone();
+                    if let Err(e) = host.reset_network() {
+                        log::error!("runtime failed to reset system network: {}", e);
                     }
                 }
             }
         }
-        Ok(())
+
+        //保存复原脚本
+        if !Path::new("/home/deck/tomoon_recover.sh").exists() {
+            let recover_script = r#"sudo chattr -i /etc/resolv.conf
+sudo systemctl stop systemd-resolved
+sudo chmod a+w /etc/NetworkManager/conf.d/dns.conf
+sudo echo -e "[main]\ndns=auto"  > /etc/NetworkManager/conf.d/dns.conf
+sudo nmcli general reload"#;
+            match fs::write("/home/deck/tomoon_recover.sh", recover_script) {
+                Ok(_) => {
+                    log::info!("Write recover script to /home/deck/tomoon_recover.sh");
+                }
+                Err(e) => {
+                    log::error!(
+                        "Error occurred while writing recover script, Error msg: {}",
+                        e.to_string()
+                    );
+                }
+            }
+        }
+
+        if clean_boot {
+            self.spawn_auto_start();
+        }
+        self.spawn_provider_check();
+        self.spawn_subscription_update();
+        self.spawn_interface_watch();
+        self.spawn_log_rotation();
+        self.spawn_core_monitor();
+
+        //save config
+        let runtime = self.clone();
+        let mut last_enable = read_lock(&self.settings, "settings").enable;
+        thread::spawn(move || loop {
+            thread::sleep(SETTINGS_SAVE_INTERVAL);
+            //enable 变化时只处理一次
+            let enable = read_lock(&runtime.settings, "settings").enable;
+            if enable != last_enable {
+                last_enable = enable;
+                runtime.on_set_enable(enable);
+            }
+            let dirty = read_lock(&runtime.state, "state").dirty;
+            if dirty {
+                runtime.flush();
+            }
+        })
+    }
+
+    /// 复原上次异常退出后遗留的 DNS 设置，可以重复调用，Clash 正在运行时拒绝修改
+    pub fn repair_dns(&self) -> Result<DnsRepair, ClashError> {
+        if read_lock(&self.clash_state, "clash").instence.is_some() {
+            return Err(ClashError::new(
+                ClashErrorKind::InnerError,
+                "Clash is running, disable it before repairing DNS.",
+            ));
+        }
+        unlock_resolv_conf();
+        let restored_backup = restore_resolv_conf();
+        if let Err(e) = helper::reset_system_network() {
+            log::warn!("Error occurred while resetting system network: {}", e);
+        }
+        //没有备份时只替换指向本机的配置，systemd-resolved 管理的链接保持不变
+        let mut regenerated = false;
+        if !restored_backup && !helper::is_resolv_conf_managed_by_resolved() {
+            let content = fs::read_to_string("/etc/resolv.conf").unwrap_or_default();
+            if content.trim().is_empty() || helper::resolv_conf_points_to_localhost(&content) {
+                fs::write("/etc/resolv.conf", helper::DEFAULT_RESOLV_CONF)?;
+                log::info!("Regenerated /etc/resolv.conf with default nameservers.");
+                regenerated = true;
+            }
+        }
+        let resolved = helper::check_dns_resolution(DNS_CHECK_HOST, DNS_CHECK_TIMEOUT);
+        log::info!(
+            "DNS repaired, restored backup: {}, regenerated: {}, resolved: {}",
+            restored_backup,
+            regenerated,
+            resolved
+        );
+        Ok(DnsRepair {
+            restored_backup,
+            regenerated,
+            resolved,
+        })
+    }
+
+    /// `Settings.enable` 变化后让核心的运行状态与之一致
+    /// 通过 `set_enable` 等接口修改时核心已经启动或停止，这里不会重复处理
+    fn on_set_enable(&self, enable: bool) {
+        if self.starting.load(Ordering::SeqCst) {
+            return;
+        }
+        if enable {
+            let running = read_lock(&self.clash_state, "clash").instence.is_some();
+            if running {
+                return;
+            }
+            log::info!("Settings.enable changed to true, starting Clash.");
+            match self.restart_clash() {
+                Ok(_) => log::info!("Clash started after enable changed."),
+                Err(e) => log::error!("Error occurred while starting Clash: {}", e),
+            }
+            return;
+        }
+        //与 set_enable 相同，先锁设置再锁核心，期间状态可能已经改变
+        let settings = read_lock(&self.settings, "settings");
+        let mut clash = write_lock(&self.clash_state, "clash");
+        if settings.enable || clash.instence.is_none() {
+            return;
+        }
+        log::info!("Settings.enable changed to false, stopping Clash.");
+        match clash.stop() {
+            Ok(x) => log::info!("Clash stopped after enable changed ({:?}).", x),
+            Err(e) => log::error!("Error occurred while stopping Clash: {}", e),
+        }
+        *write_lock(&self.running_status, "running_status") = RunningStatus::None;
+    }
+
+    /// 立即把设置写入文件，内容没有变化时跳过
+    pub fn flush(&self) {
+        let settings = read_lock(&self.settings, "settings");
+        let mut state = write_lock(&self.state, "state");
+        let path = settings_path(&state.home);
+        match settings.save_if_changed(&path, &mut state.saved) {
+            Ok(true) => log::debug!("Settings saved to {}", path.display()),
+            Ok(false) => {}
+            Err(e) => {
+                log::error!("SettingsJson.save({}) error: {}", path.display(), e);
+                return;
+            }
+        }
+        state.dirty = false;
     }
+
+    /// 定期检查核心进程，意外退出时记录退出状态和日志，复原网络并按设置重启
+    fn spawn_core_monitor(&self) -> thread::JoinHandle<()> {
+        let runtime = self.clone();
+        thread::spawn(move || {
+            // (最近一次意外退出的时间, 窗口内的重启次数)
+            let mut restarts: Option<(Instant, u32)> = None;
+            loop {
+                thread::sleep(CORE_MONITOR_INTERVAL);
+                if write_lock(&runtime.clash_state, "clash")
+                    .exit_status()
+                    .is_none()
+                {
+                    continue;
+                }
+                //与 set_enable 相同，先锁设置再锁核心，期间可能已经被停止
+                let mut settings = write_lock(&runtime.settings, "settings");
+                let mut clash = write_lock(&runtime.clash_state, "clash");
+                let status = match clash.exit_status() {
+                    Some(x) => x,
+                    None => continue,
+                };
+                let log =
+                    helper::tail_file(Path::new(CLASH_LOG_PATH), CORE_EXIT_LOG_LINES, 64 * 1024)
+                        .unwrap_or_default();
+                log::error!("Clash exited unexpectedly ({}): {}", status, log.join("\n"));
+                //进程已经退出，只需要结束 SmartDNS 并复原网络
+                if let Err(e) = clash.stop() {
+                    log::error!("Error occurred while cleaning up after Clash exited: {}", e);
+                }
+                drop(clash);
+                settings.enable = false;
+                let restart = settings.restart_on_crash && {
+                    let count = match restarts {
+                        Some((time, count)) if time.elapsed() < CRASH_RESTART_WINDOW => count,
+                        _ => 0,
+                    };
+                    restarts = Some((Instant::now(), count + 1));
+                    count < CRASH_RESTART_MAX
+                };
+                drop(settings);
+                write_lock(&runtime.state, "state").dirty = true;
+                *write_lock(&runtime.running_status, "running_status") = RunningStatus::Failed;
+                runtime.set_last_error(Some(match log.last() {
+                    Some(x) => format!("Clash exited unexpectedly ({}): {}", status, x),
+                    None => format!("Clash exited unexpectedly ({})", status),
+                }));
+                *write_lock(&runtime.core_crash, "core_crash") = Some(CoreCrash {
+                    code: status.code(),
+                    signal: std::os::unix::process::ExitStatusExt::signal(&status),
+                    log,
+                    time: SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .map(|x| x.as_secs())
+                        .unwrap_or_default(),
+                    restarted: restart,
+                });
+                if !restart {
+                    continue;
+                }
+                log::warn!("Restarting Clash after unexpected exit.");
+                match runtime.restart_clash() {
+                    Ok(_) => log::info!("Clash restarted after unexpected exit."),
+                    Err(e) => log::error!("Error occurred while restarting Clash: {}", e),
+                }
+            }
+        })
+    }
+
+    /// 定期轮转后端与 Clash 的日志，避免占满 /tmp
+    fn spawn_log_rotation(&self) -> thread::JoinHandle<()> {
+        let runtime_settings = self.settings_clone();
+        thread::spawn(move || loop {
+            thread::sleep(LOG_ROTATE_INTERVAL);
+            let max_bytes = match runtime_settings.read() {
+                Ok(x) => x.log_rotate_bytes,
+                Err(e) => {
+                    log::error!("log rotation failed to acquire settings read lock: {}", e);
+                    continue;
+                }
+            };
+            for path in [logger::log_path(), PathBuf::from(CLASH_LOG_PATH)] {
+                match helper::rotate_log(&path, max_bytes) {
+                    Ok(true) => log::info!("Rotated log {}", path.display()),
+                    Ok(false) => {}
+                    Err(e) => log::warn!("Failed to rotate log {}: {}", path.display(), e),
+                }
+            }
+        })
+    }
+
+    /// 检测默认路由网卡的切换（如 Wi-Fi 与底座有线网络之间），
+    /// Clash 运行时清空 fake-ip 缓存并重新设置系统网络
+    fn spawn_interface_watch(&self) -> thread::JoinHandle<()> {
+        let runtime_settings = self.settings_clone();
+        let active_interface = self.active_interface_clone();
+        thread::spawn(move || {
+            let mut candidate: Option<String> = None;
+            let mut stable_polls = 0;
+            loop {
+                thread::sleep(INTERFACE_POLL_INTERVAL);
+                let current = helper::get_primary_interface();
+                let active = match active_interface.read() {
+                    Ok(x) => x.clone(),
+                    Err(e) => {
+                        log::error!(
+                            "interface watch failed to acquire interface read lock: {}",
+                            e
+                        );
+                        continue;
+                    }
+                };
+                if current == active {
+                    stable_polls = 0;
+                    continue;
+                }
+                //短时间内多次切换时，等待网卡稳定下来
+                if current != candidate {
+                    candidate = current;
+                    stable_polls = 1;
+                    continue;
+                }
+                stable_polls += 1;
+                if stable_polls < INTERFACE_STABLE_POLLS {
+                    continue;
+                }
+                stable_polls = 0;
+                log::info!(
+                    "Primary interface changed from {:?} to {:?}",
+                    active,
+                    candidate
+                );
+                match active_interface.write() {
+                    Ok(mut x) => *x = candidate.clone(),
+                    Err(e) => {
+                        log::error!(
+                            "interface watch failed to acquire interface write lock: {}",
+                            e
+                        );
+                        continue;
+                    }
+                }
+                let (enable, manage_dns, controller, dns_listen) = match runtime_settings.read() {
+                    Ok(x) => (
+                        x.enable,
+                        x.manage_system_dns,
+                        Controller::from_settings(&x),
+                        DnsConfig::from_settings(&x).listen,
+                    ),
+                    Err(e) => {
+                        log::error!(
+                            "interface watch failed to acquire settings read lock: {}",
+                            e
+                        );
+                        continue;
+                    }
+                };
+                if candidate.is_none() || !enable || !helper::is_clash_running() {
+                    continue;
+                }
+                if let Err(e) = controller.flush_fake_ip() {
+                    log::warn!("Error occurred while flushing fake-ip cache: {}", e);
+                }
+                if !manage_dns {
+                    continue;
+                }
+                match helper::set_system_network(&dns_listen) {
+                    Ok(_) => log::info!("Re-applied network settings after interface change"),
+                    Err(e) => {
+                        log::error!("Error occurred while setting system network: {}", e);
+                    }
+                }
+            }
+        })
+    }
+
+    /// 开机后使用上次的配置启动 Clash，连续失败多次后不再尝试
+    fn spawn_auto_start(&self) -> thread::JoinHandle<()> {
+        let runtime = self.clone();
+        thread::spawn(move || {
+            let (auto_start, failures, config) = match runtime.settings.read() {
+                Ok(x) => (
+                    x.auto_start,
+                    x.auto_start_failures,
+                    x.current_config.clone(),
+                ),
+                Err(e) => {
+                    log::error!("auto start failed to acquire settings read lock: {}", e);
+                    return;
+                }
+            };
+            if !auto_start {
+                return;
+            }
+            if failures >= AUTO_START_MAX_FAILURES {
+                log::warn!(
+                    "Auto start skipped after {} failed attempts, start Clash manually to reset.",
+                    failures
+                );
+                return;
+            }
+            if !config.is_file() {
+                log::warn!("Auto start skipped, config {} not found.", config.display());
+                return;
+            }
+            thread::sleep(AUTO_START_DELAY * 2u32.pow(failures));
+            match runtime.settings.write() {
+                //等待期间用户已经手动启动
+                Ok(x) if x.enable => return,
+                Ok(mut x) => x.current_sub = config.to_string_lossy().to_string(),
+                Err(e) => {
+                    log::error!("auto start failed to acquire settings write lock: {}", e);
+                    return;
+                }
+            }
+            log::info!("Auto starting Clash with {}", config.display());
+            let result = runtime.restart_clash();
+            if let Ok(mut x) = runtime.settings.write() {
+                x.auto_start_failures = match result {
+                    Ok(_) => 0,
+                    Err(_) => failures + 1,
+                };
+            }
+            if let Ok(mut x) = runtime.state.write() {
+                x.dirty = true;
+            }
+        })
+    }
+
+    /// 定期更新到期的订阅，正在使用的订阅有变化时重新加载配置
+    fn spawn_subscription_update(&self) -> thread::JoinHandle<()> {
+        let runtime = self.clone();
+        thread::spawn(move || {
+            let tick = Duration::from_secs(60);
+            let mut failures = HashMap::new();
+            loop {
+                thread::sleep(tick);
+                runtime.update_due_subscriptions(&mut failures);
+            }
+        })
+    }
+
+    /// `failures` 记录更新失败的时间，失败后等待 `SUBSCRIPTION_RETRY_DELAY` 再重试
+    fn update_due_subscriptions(&self, failures: &mut HashMap<String, Instant>) {
+        let (subs, current_sub) = match self.settings.read() {
+            Ok(x) => (
+                x.subscriptions
+                    .iter()
+                    .filter(|s| subscription::is_update_due(s, x.subscription_update_interval))
+                    .filter(|s| match failures.get(&s.path) {
+                        Some(x) => x.elapsed() >= SUBSCRIPTION_RETRY_DELAY,
+                        None => true,
+                    })
+                    .cloned()
+                    .collect::<Vec<_>>(),
+                x.current_sub.clone(),
+            ),
+            Err(e) => {
+                log::error!(
+                    "subscription update failed to acquire settings read lock: {}",
+                    e
+                );
+                return;
+            }
+        };
+        if subs.is_empty() {
+            return;
+        }
+        let options = self.download_options();
+        let update_status = |status: DownloadStatus| match self.update_status.write() {
+            Ok(mut x) => *x = status,
+            Err(e) => {
+                log::error!(
+                    "subscription update failed to acquire update_status lock: {}",
+                    e
+                );
+            }
+        };
+        update_status(DownloadStatus::downloading());
+        let mut failed = false;
+        let mut current_changed = false;
+        for sub in &subs {
+            match subscription::refresh_subscription(sub, &options) {
+                Ok(changed) => {
+                    log::info!("Subscription {} updated, changed: {}", sub.path, changed);
+                    failures.remove(&sub.path);
+                    current_changed |= changed && sub.path == current_sub;
+                }
+                Err(e) => {
+                    //保留原来的文件，稍后再试
+                    log::error!(
+                        "Error occurred while updating subscription {}: {}",
+                        sub.url,
+                        e
+                    );
+                    failures.insert(sub.path.clone(), Instant::now());
+                    failed = true;
+                }
+            }
+        }
+        update_status(if failed {
+            DownloadStatus::Failed
+        } else {
+            DownloadStatus::Success
+        });
+        let enabled = self.settings.read().map(|x| x.enable).unwrap_or(false);
+        if current_changed && enabled {
+            match self.reload_clash_config(None) {
+                Ok(x) => log::info!("Reloaded Clash with updated subscription ({}).", x),
+                Err(e) => log::error!("Error occurred while reloading Clash: {}", e),
+            }
+        }
+    }
+
+    /// 定期检查 Rule Provider 是否过期，有更新且 Clash 正在运行时重新加载配置
+    fn spawn_provider_check(&self) -> thread::JoinHandle<()> {
+        let runtime = self.clone();
+        let runtime_settings = self.settings_clone();
+        let providers_status = self.providers_status_clone();
+        let base = match self.clash_state.read() {
+            Ok(x) => x.provider_dir.clone(),
+            Err(_) => provider_dir(&State::new().home),
+        };
+        thread::spawn(move || {
+            let tick = Duration::from_secs(60);
+            let mut last_check: Option<Instant> = None;
+            loop {
+                thread::sleep(tick);
+                let (enable, interval, controller, keep_connections) = match runtime_settings.read()
+                {
+                    Ok(x) => (
+                        x.enable,
+                        x.provider_check_interval,
+                        Controller::from_settings(&x),
+                        (x.core_type == CoreType::Meta).then_some(x.keep_connections_on_reload),
+                    ),
+                    Err(e) => {
+                        log::error!("provider check failed to acquire settings read lock: {}", e);
+                        continue;
+                    }
+                };
+                if !enable || interval == 0 {
+                    continue;
+                }
+                if let Some(x) = last_check {
+                    if x.elapsed() < Duration::from_secs(interval) {
+                        continue;
+                    }
+                }
+                last_check = Some(Instant::now());
+                let run_config = match running_config_path() {
+                    Ok(x) => x,
+                    Err(e) => {
+                        log::error!("provider check failed to get running config path: {}", e);
+                        continue;
+                    }
+                };
+                let options = runtime.download_options();
+                let updated = match refresh_stale_providers(&run_config, &base, &options) {
+                    Ok(x) => x,
+                    Err(e) => {
+                        log::error!("Error occurred while refreshing providers: {}", e);
+                        false
+                    }
+                };
+                if updated && helper::is_clash_running() {
+                    match controller.reload_config(&run_config, keep_connections) {
+                        Ok(_) => log::info!("Reloaded Clash with updated providers."),
+                        Err(e) => log::error!("Error occurred while reloading Clash: {}", e),
+                    }
+                }
+                match providers_status.write() {
+                    Ok(mut x) => {
+                        let now = unix_timestamp();
+                        x.last_check = Some(now);
+                        if updated {
+                            x.last_update = Some(now);
+                        }
+                    }
+                    Err(e) => {
+                        log::error!("provider check failed to acquire status write lock: {}", e);
+                    }
+                }
+            }
+        })
+    }
+}
+
+/// 去掉 `/etc/resolv.conf` 的只读属性，失败时只记录日志
+fn unlock_resolv_conf() {
+    match Command::new("chattr")
+        .arg("-i")
+        .arg("/etc/resolv.conf")
+        .status()
+    {
+        Ok(x) if x.success() => (),
+        Ok(x) => log::warn!("chattr exited with {}", x),
+        Err(e) => log::warn!("Error occurred while running chattr: {}", e),
+    }
+}
+
+/// 从 `helper::resolv_conf_backup_path` 恢复 DNS 设置，备份不存在时跳过，恢复成功时返回 true
+fn restore_resolv_conf() -> bool {
+    let backup = helper::resolv_conf_backup_path();
+    if !backup.exists() {
+        log::info!("resolv.conf backup not found, skip restoring DNS.");
+        return false;
+    }
+    unlock_resolv_conf();
+    if let Err(e) = fs::copy(&backup, "/etc/resolv.conf") {
+        log::error!("Error occurred while restoring resolv.conf: {}", e);
+        return false;
+    }
+    //恢复后删除，下次启动时重新备份
+    if let Err(e) = fs::remove_file(&backup) {
+        log::warn!("Error occurred while removing resolv.conf backup: {}", e);
+    }
+    true
+}
+
+/// 选择启动时使用的配置，返回配置路径
+/// 有些时候第一次没有选择订阅，此时使用第一个订阅；
+/// 没有订阅且默认配置不存在或为空时使用内置的直连配置，TUN 和 DNS 仍然由 `change_config` 写入
+pub(crate) fn select_profile(settings: &mut Settings, home: &Path) -> Result<String, ClashError> {
+    if settings.current_sub.is_empty() {
+        if let Some(sub) = settings.subscriptions.first() {
+            log::info!("no profile provided, try to use first profile.");
+            settings.current_sub = sub.path.clone();
+        }
+    }
+    let profile = settings.current_sub.clone();
+    if fs::metadata(&profile).is_ok_and(|x| x.len() > 0) {
+        return Ok(profile);
+    }
+    //选择的订阅丢失时仍然报错，避免悄悄变成直连
+    if !profile.is_empty() && profile != default_current_sub() {
+        log::error!("profile {} not found.", profile);
+        return Err(ClashError {
+            message: format!("profile {} not found.", profile),
+            kind: ClashErrorKind::ConfigNotFound,
+        });
+    }
+    let path = fallback_config_path(home);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    helper::write_atomic(&path, FALLBACK_CONFIG)?;
+    log::warn!("no profile provided, using the built-in DIRECT profile.");
+    Ok(path.to_string_lossy().to_string())
+}
+
+/// 内置直连配置的保存路径
+pub(crate) fn fallback_config_path(home: &Path) -> PathBuf {
+    home.join(".config/tomoon/fallback.yaml")
+}
+
+/// 设置文件路径，可通过 `TOMOON_CONFIG` 环境变量覆盖
+pub(crate) fn settings_path<P: AsRef<std::path::Path>>(home: P) -> std::path::PathBuf {
+    match std::env::var_os(SETTINGS_PATH_ENV) {
+        Some(x) if !x.is_empty() => std::path::PathBuf::from(x),
+        _ => home.as_ref().join(".config/tomoon/tomoon.json"),
+    }
+}
+
+pub struct Clash {
+    pub path: std::path::PathBuf,
+    pub config: std::path::PathBuf,
+    pub instence: Option<Child>,
+    pub smartdns_instence: Option<Child>,
+    /// 核心是否为 Clash.Meta，首次启动时检测
+    pub meta_core: Option<bool>,
+    /// Rule Provider 的下载进度，与 `ControlRuntime` 共享
+    pub download_status: Arc<RwLock<DownloadStatus>>,
+    /// Rule Provider 的保存目录，运行配置中的 `path` 会改写为该目录下的绝对路径
+    pub provider_dir: PathBuf,
+    /// 启动和停止时对系统的修改，测试时替换为不修改系统的实现
+    pub host: Arc<dyn HostEnv>,
+    /// 不为空时使用该文件作为核心而不是设置中的核心，用于测试
+    pub core_override: Option<PathBuf>,
+    /// 不为空时使用该文件作为 SmartDNS，用于测试
+    pub smartdns_override: Option<PathBuf>,
+    /// 不为空时把运行配置写到该文件而不是 `running_config_path`，用于测试
+    pub run_config_override: Option<PathBuf>,
+    /// 本次启动是否修改了系统 DNS，停止时据此决定是否复原
+    pub manage_dns: bool,
+}
+
+/// 启动和停止核心时需要修改系统的操作
+pub trait HostEnv: Send + Sync {
+    /// 准备核心需要的 Country.mmdb
+    fn prepare_geo_db(&self) -> Result<(), ClashError>;
+    /// 把 DNS 指向 Clash 实际监听的 `dns_listen`，在核心启动前调用
+    fn set_network(&self, dns_listen: &str) -> Result<(), ClashError>;
+    /// 复原 DNS 和网络，在核心停止后调用
+    fn reset_network(&self) -> Result<(), ClashError>;
+}
+
+/// 实际修改 SteamOS 系统的实现
+pub struct SystemHost;
+
+impl HostEnv for SystemHost {
+    fn prepare_geo_db(&self) -> Result<(), ClashError> {
+        //没有 Country.mmdb
+        let country_db_path = "/root/.config/clash/Country.mmdb";
+        if let Some(parent) = PathBuf::from(country_db_path).parent() {
+            if let Err(e) = std::fs::create_dir_all(parent) {
+                log::error!("Failed while creating /root/.config/clash dir.");
+                log::error!("Error Message:{}", e);
+                return Err(ClashError {
+                    kind: ClashErrorKind::CpDbError,
+                    message: "Error occurred while creating /root/.config/clash dir.".to_string(),
+                });
+            }
+        }
+        let new_country_db_path = install_path("bin/core/Country.mmdb")?;
+        if !PathBuf::from(country_db_path).is_file() {
+            match fs::copy(new_country_db_path, country_db_path) {
+                Ok(_) => {
+                    log::info!("cp Country.mmdb to .clash dir");
+                }
+                Err(e) => {
+                    log::info!("Error occurred while coping Country.mmdb");
+                    return Err(ClashError::new(ClashErrorKind::CpDbError, e.to_string()));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn set_network(&self, dns_listen: &str) -> Result<(), ClashError> {
+        //先结束 systemd-resolve ，否则会因为端口占用启动失败
+        match helper::set_system_network(dns_listen) {
+            Ok(_) => {
+                log::info!("Successfully set network status");
+                Ok(())
+            }
+            Err(e) => {
+                log::error!("Error occurred while setting system network: {}", e);
+                Err(ClashError::new(ClashErrorKind::NetworkError, e.to_string()))
+            }
+        }
+    }
+
+    fn reset_network(&self) -> Result<(), ClashError> {
+        // 复原 DNS，只有备份存在时才恢复
+        restore_resolv_conf();
+
+        //直接重置网络
+        helper::reset_system_network()
+            .map_err(|e| ClashError::new(ClashErrorKind::InnerError, e.to_string()))
+    }
+}
+
+/// 序列化后的名称会返回给前端，修改时需要同步前端
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ClashErrorKind {
+    ConfigFormatError,
+    ConfigNotFound,
+    RuleProviderDownloadError,
+    NetworkError,
+    CpDbError,
+    InnerError,
+    NotRunning,
+    ChecksumMismatch,
+    Timeout,
+    CoreNotFound,
+    CoreExited,
+    Offline,
+    Default,
+}
+
+#[derive(Debug)]
+pub struct ClashError {
+    pub message: String,
+    pub kind: ClashErrorKind,
+}
+
+impl error::Error for ClashError {}
+
+impl Display for ClashError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Error Kind: {:?}, Error Message: {})",
+            self.kind, self.message
+        )
+    }
+}
+
+impl ClashError {
+    pub fn new(kind: ClashErrorKind, message: impl Into<String>) -> Self {
+        Self {
+            message: message.into(),
+            kind,
+        }
+    }
+
+    /// 请求超时映射为 `Timeout`，其他错误使用 `kind`
+    pub fn from_request(e: &minreq::Error, kind: ClashErrorKind) -> Self {
+        Self {
+            message: e.to_string(),
+            kind: if helper::is_timeout(e) {
+                ClashErrorKind::Timeout
+            } else {
+                kind
+            },
+        }
+    }
+}
+
+impl From<std::io::Error> for ClashError {
+    fn from(e: std::io::Error) -> Self {
+        Self::new(ClashErrorKind::InnerError, e.to_string())
+    }
+}
+
+impl<T> From<std::sync::PoisonError<T>> for ClashError {
+    fn from(e: std::sync::PoisonError<T>) -> Self {
+        Self::new(ClashErrorKind::InnerError, e.to_string())
+    }
+}
+
+impl From<serde_yaml::Error> for ClashError {
+    fn from(e: serde_yaml::Error) -> Self {
+        Self::new(ClashErrorKind::ConfigFormatError, e.to_string())
+    }
+}
+
+impl From<minreq::Error> for ClashError {
+    fn from(e: minreq::Error) -> Self {
+        Self::from_request(&e, ClashErrorKind::NetworkError)
+    }
+}
+
+/// 获取读锁，持有锁的线程 panic 后清除中毒标记并继续使用其中的数据
+pub fn read_lock<'a, T>(lock: &'a RwLock<T>, name: &str) -> RwLockReadGuard<'a, T> {
+    lock.read().unwrap_or_else(|e| {
+        log::warn!(
+            "{} lock was poisoned by a panicked thread, recovering.",
+            name
+        );
+        lock.clear_poison();
+        e.into_inner()
+    })
+}
+
+/// 获取写锁，处理方式与 `read_lock` 相同
+pub fn write_lock<'a, T>(lock: &'a RwLock<T>, name: &str) -> RwLockWriteGuard<'a, T> {
+    lock.write().unwrap_or_else(|e| {
+        log::warn!(
+            "{} lock was poisoned by a panicked thread, recovering.",
+            name
+        );
+        lock.clear_poison();
+        e.into_inner()
+    })
+}
+
+impl Default for Clash {
+    fn default() -> Self {
+        Self {
+            path: helper::install_root()
+                .unwrap_or_default()
+                .join("bin/core/clash"),
+            config: helper::install_root()
+                .unwrap_or_default()
+                .join("bin/core/config.yaml"),
+            instence: None,
+            smartdns_instence: None,
+            meta_core: None,
+            download_status: Arc::new(RwLock::new(DownloadStatus::None)),
+            provider_dir: provider_dir(&State::new().home),
+            host: Arc::new(SystemHost),
+            core_override: None,
+            smartdns_override: None,
+            run_config_override: None,
+            manage_dns: false,
+        }
+    }
+}
+
+impl Clash {
+    pub fn run(
+        &mut self,
+        config_path: &String,
+        settings: &Settings,
+        progress: &dyn Fn(StartEvent),
+    ) -> Result<(), ClashError> {
+        //调用方需要先停止已有的核心
+        if self.instence.is_some() {
+            return Err(ClashError {
+                message: "Clash is already running".to_string(),
+                kind: ClashErrorKind::InnerError,
+            });
+        }
+        //刚安装时核心可能还没有下载
+        let core_path = self.core_path(settings)?;
+        ensure_core_executable(&core_path)?;
+        self.host.prepare_geo_db()?;
+        self.generate_config(config_path, settings, progress)?;
+        // 先检查生成的配置，避免 Clash 启动后立即退出
+        self.validate_config()?;
+        //在 clash 启动前修改 DNS，用户自行管理 DNS 时跳过
+        self.manage_dns = settings.manage_system_dns;
+        if self.manage_dns {
+            self.host
+                .set_network(&DnsConfig::from_settings(settings).listen)?;
+        } else {
+            log::info!("manage_system_dns is disabled, leaving system DNS untouched.");
+        }
+        //启动失败时没有记录进程，`stop` 不会复原 DNS，需要在这里复原
+        let result = self.spawn_core(progress);
+        if result.is_err() && self.manage_dns {
+            if let Err(e) = self.host.reset_network() {
+                log::error!("Error occurred while resetting network: {}", e);
+            }
+        }
+        result
+    }
+
+    /// 启动 SmartDNS 和核心，核心启动后立即退出时返回错误
+    fn spawn_core(&mut self, progress: &dyn Fn(StartEvent)) -> Result<(), ClashError> {
+        let run_config = self.run_config_path()?;
+        let outputs = helper::create_append_file(Path::new(CLASH_LOG_PATH))?;
+        let errors = outputs.try_clone()?;
+
+        let smartdns_path = match &self.smartdns_override {
+            Some(x) => x.clone(),
+            None => install_path("bin/smartdns/smartdns")?,
+        };
+
+        let smartdns_config_path = install_path("bin/smartdns/config.conf")?;
+
+        // let smartdns_outputs = fs::File::create("/tmp/tomoon.smartdns.log").unwrap();
+        // let smartdns_errors = outputs.try_clone().unwrap();
+
+        // 启动 SmartDNS 作为 DNS 上游
+        progress(StartEvent::new(
+            StartStage::SpawnCore,
+            "Starting core",
+            None,
+        ));
+        let smart_dns = Command::new(smartdns_path)
+            .arg("-c")
+            .arg(smartdns_config_path)
+            .arg("-f")
+            // .stdout(smartdns_outputs)
+            // .stderr(smartdns_errors)
+            .spawn();
+        //SmartDNS 启动失败时不启动核心，避免留下没有记录的核心进程
+        let mut smart_dns = match smart_dns {
+            Ok(x) => x,
+            Err(e) => {
+                log::error!("run SmartDNS failed: {}", e);
+                return Err(ClashError::new(
+                    ClashErrorKind::InnerError,
+                    format!("Failed to run SmartDNS: {}", e),
+                ));
+            }
+        };
+
+        let clash = Command::new(self.path.clone())
+            .arg("-f")
+            .arg(run_config)
+            .stdout(outputs)
+            .stderr(errors)
+            .spawn();
+        let mut clash = match clash {
+            Ok(x) => x,
+            Err(e) => {
+                log::error!("run Clash failed: {}", e);
+                let _ = smart_dns.kill();
+                let _ = smart_dns.wait();
+                return Err(ClashError {
+                    message: format!("Failed to run {}: {}", self.path.display(), e),
+                    kind: if e.kind() == std::io::ErrorKind::NotFound {
+                        ClashErrorKind::CoreNotFound
+                    } else {
+                        ClashErrorKind::InnerError
+                    },
+                });
+            }
+        };
+        //核心启动后立即退出时（例如端口被占用）不记录进程
+        let started = check_core_started(
+            &mut clash,
+            Path::new(CLASH_LOG_PATH),
+            CORE_START_CHECK_DELAY,
+        );
+        if let Err(e) = started {
+            let _ = smart_dns.kill();
+            let _ = smart_dns.wait();
+            return Err(e);
+        }
+        self.instence = Some(clash);
+        self.smartdns_instence = Some(smart_dns);
+        Ok(())
+    }
+
+    /// 重新生成运行配置并热重载，失败时重启 Clash
+    pub fn reload_config(
+        &mut self,
+        config_path: &String,
+        settings: &Settings,
+        controller: &Controller,
+    ) -> Result<ReloadMethod, ClashError> {
+        self.update_config_path(config_path);
+        if let Err(e) = self.change_config(ConfigOptions::from(settings), &|_| {}) {
+            return Err(ClashError::new(
+                ClashErrorKind::ConfigFormatError,
+                e.to_string(),
+            ));
+        }
+        let run_config = self.run_config_path()?;
+        let keep_connections = self
+            .detect_meta_core()
+            .then_some(settings.keep_connections_on_reload);
+        match controller.reload_config(&run_config, keep_connections) {
+            Ok(_) => {
+                log::info!("Reloaded Clash config {}", config_path);
+                Ok(ReloadMethod::Hot)
+            }
+            Err(e) => {
+                log::warn!("Hot reload failed, restarting Clash: {}", e);
+                if let Err(e) = self.stop() {
+                    log::error!("Error occurred while stopping Clash: {}", e);
+                }
+                self.run(config_path, settings, &|_| {})?;
+                Ok(ReloadMethod::Restart)
+            }
+        }
+    }
+
+    /// 核心进程已经退出时返回退出状态，仍在运行或没有启动时返回 `None`
+    pub fn exit_status(&mut self) -> Option<std::process::ExitStatus> {
+        match self.instence.as_mut()?.try_wait() {
+            Ok(x) => x,
+            Err(e) => {
+                log::warn!("Error occurred while checking Clash process: {}", e);
+                None
+            }
+        }
+    }
+
+    pub fn stop(&mut self) -> Result<StopMethod, ClashError> {
+        let method = match self.instence.take() {
+            Some(mut x) => {
+                let method = terminate_core(&mut x, CORE_STOP_TIMEOUT)?;
+                log::info!("Clash stopped ({:?})", method);
+                if self.manage_dns {
+                    self.host.reset_network()?;
+                }
+                method
+            }
+            None => {
+                //Not launch Clash yet...
+                log::error!("Error occurred while disabling Clash: Not launch Clash yet");
+                StopMethod::NotRunning
+            }
+        };
+        match self.smartdns_instence.take() {
+            Some(mut x) => {
+                if let Err(e) = x.kill() {
+                    log::warn!("Error occurred while killing SmartDNS: {}", e);
+                }
+                x.wait()?;
+            }
+            None => {
+                log::error!("Error occurred while disabling SmartDNS : Not launch SmartDNS yet");
+            }
+        };
+        Ok(method)
+    }
+
+    /// 根据 `config_path` 生成 `running_config.yaml`，不启动核心也不修改系统网络
+    pub fn generate_config(
+        &mut self,
+        config_path: &String,
+        settings: &Settings,
+        progress: &dyn Fn(StartEvent),
+    ) -> Result<PathBuf, ClashError> {
+        self.update_config_path(config_path);
+        //切换核心类型后需要重新检测
+        let core_path = self.core_path(settings)?;
+        if self.path != core_path {
+            self.path = core_path;
+            self.meta_core = None;
+        }
+        // 修改配置文件为推荐配置
+        self.detect_meta_core();
+        let mut options = ConfigOptions::from(settings);
+        //重新加载时核心仍在运行，可以经过它下载 Provider
+        if settings.download_via_proxy && self.instence.is_some() {
+            options.download.proxy = local_proxy();
+        }
+        match self.change_config(options, progress) {
+            Ok(_) => (),
+            Err(e) => {
+                return Err(ClashError::new(
+                    ClashErrorKind::ConfigFormatError,
+                    e.to_string(),
+                ));
+            }
+        }
+        Ok(self.run_config_path()?)
+    }
+
+    /// 与 `generate_config` 相同地改写 `config_path`，只返回生成的 yaml
+    pub fn preview_config(
+        &mut self,
+        config_path: &String,
+        settings: &Settings,
+    ) -> Result<String, ClashError> {
+        self.update_config_path(config_path);
+        self.path = self.core_path(settings)?;
+        self.meta_core = None;
+        let mut options = ConfigOptions::from(settings);
+        options.meta_core |= self.detect_meta_core();
+        let yaml = self
+            .transformed_config(&options)
+            .map_err(|e| ClashError::new(ClashErrorKind::ConfigFormatError, e.to_string()))?;
+        Ok(serde_yaml::to_string(&yaml)?)
+    }
+
+    /// 使用 `-t` 测试运行配置，失败时返回核心输出的错误信息
+    pub fn validate_config(&self) -> Result<(), ClashError> {
+        let run_config = self
+            .run_config_path()
+            .map_err(|e| ClashError::new(ClashErrorKind::ConfigNotFound, e.to_string()))?;
+        let output = Command::new(self.path.clone())
+            .arg("-t")
+            .arg("-f")
+            .arg(run_config)
+            .output()
+            .map_err(|e| {
+                log::error!("Failed to run Clash config test: {}", e);
+                ClashError::from(e)
+            })?;
+        if output.status.success() {
+            return Ok(());
+        }
+        // 部分核心把错误写到 stdout
+        let mut message = String::from_utf8_lossy(&output.stderr).trim().to_string();
+        if message.is_empty() {
+            message = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        }
+        log::error!("Config test failed ({}): {}", output.status, message);
+        Err(ClashError {
+            message,
+            kind: ClashErrorKind::ConfigFormatError,
+        })
+    }
+
+    /// 通过 `-v` 的输出判断核心是否为 Clash.Meta
+    pub fn detect_meta_core(&mut self) -> bool {
+        if let Some(x) = self.meta_core {
+            return x;
+        }
+        let is_meta = match Command::new(self.path.clone()).arg("-v").output() {
+            Ok(x) => {
+                let version = String::from_utf8_lossy(&x.stdout);
+                version.contains("Meta") || version.contains("mihomo")
+            }
+            Err(e) => {
+                log::error!("Error occurred while detecting core version: {}", e);
+                return false;
+            }
+        };
+        log::info!("Detected core type, is Meta: {}", is_meta);
+        self.meta_core = Some(is_meta);
+        is_meta
+    }
+
+    /// 核心使用的运行配置，`run_config_override` 优先
+    fn run_config_path(&self) -> std::io::Result<PathBuf> {
+        match &self.run_config_override {
+            Some(x) => Ok(x.clone()),
+            None => running_config_path(),
+        }
+    }
+
+    /// 启动时使用的核心，`core_override` 优先于设置中的核心类型
+    fn core_path(&self, settings: &Settings) -> Result<PathBuf, ClashError> {
+        match &self.core_override {
+            Some(x) => Ok(x.clone()),
+            None => install_path(settings.core_type.core_path()),
+        }
+    }
+
+    pub fn update_config_path(&mut self, path: &String) {
+        self.config = std::path::PathBuf::from((*path).clone());
+    }
+
+    /// 按 `options` 改写当前订阅，下载 Provider 后写入运行配置
+    pub fn change_config(
+        &self,
+        mut options: ConfigOptions,
+        progress: &dyn Fn(StartEvent),
+    ) -> Result<(), Box<dyn error::Error>> {
+        progress(StartEvent::new(
+            StartStage::ParseConfig,
+            "Parsing config",
+            None,
+        ));
+        options.meta_core |= self.meta_core.unwrap_or(false);
+        let yaml = self.transformed_config(&options)?;
+
+        //下载 rule-providers 和 proxy-providers
+        self.download_providers(
+            &yaml,
+            options.provider_download_attempts,
+            &options.download,
+            progress,
+        )?;
+
+        let run_config = self.run_config_path()?;
+
+        progress(StartEvent::new(
+            StartStage::WriteConfig,
+            "Writing config",
+            None,
+        ));
+        let yaml_str = serde_yaml::to_string(&yaml)?;
+        helper::write_atomic(&run_config, yaml_str)?;
+        Ok(())
+    }
+
+    /// 读取当前订阅并按 `options` 改写，Provider 路径指向 `provider_dir`
+    fn transformed_config(&self, options: &ConfigOptions) -> Result<Value, Box<dyn error::Error>> {
+        let config = fs::read_to_string(&self.config)?;
+        let yaml: serde_yaml::Value = serde_yaml::from_str(config.as_str())?;
+        let mut yaml = transform_config(yaml, options)?;
+        rewrite_provider_paths(&mut yaml, &self.provider_dir)?;
+        Ok(yaml)
+    }
+
+    /// 下载配置中本地还没有的 Rule Provider 和 Proxy Provider
+    pub fn download_providers(
+        &self,
+        yaml: &Value,
+        attempts: u32,
+        options: &DownloadOptions,
+        progress: &dyn Fn(StartEvent),
+    ) -> Result<(), ClashError> {
+        let update_status = |status: DownloadStatus| match self.download_status.write() {
+            Ok(mut x) => *x = status,
+            Err(e) => {
+                log::error!(
+                    "download_providers() faild to acquire download_status write {}",
+                    e
+                );
+            }
+        };
+        let jobs = provider_jobs(yaml, &self.provider_dir)?;
+        if !jobs.is_empty() {
+            update_status(DownloadStatus::downloading());
+            progress(StartEvent::new(
+                StartStage::DownloadProviders,
+                format!("Downloading {} providers", jobs.len()),
+                Some(0),
+            ));
+            let failed = self.download_providers_concurrently(&jobs, attempts, options, progress);
+            if !failed.is_empty() {
+                update_status(DownloadStatus::Failed);
+                return Err(ClashError {
+                    kind: ClashErrorKind::RuleProviderDownloadError,
+                    message: format!(
+                        "Error occurred while downloading providers: {}",
+                        failed.join(", ")
+                    ),
+                });
+            }
+            update_status(DownloadStatus::Success);
+        }
+        log::info!("All providers downloaded");
+        progress(StartEvent::new(
+            StartStage::DownloadProviders,
+            "All providers downloaded",
+            Some(100),
+        ));
+        Ok(())
+    }
+
+    /// 用最多 `PROVIDER_DOWNLOAD_WORKERS` 个线程下载，返回下载失败的 url
+    fn download_providers_concurrently(
+        &self,
+        jobs: &[(String, String, PathBuf)],
+        attempts: u32,
+        options: &DownloadOptions,
+        progress: &dyn Fn(StartEvent),
+    ) -> Vec<String> {
+        let next = &AtomicUsize::new(0);
+        // 每个 Provider 已接收与总字节数，汇总后写入 download_status
+        let received = &Mutex::new(vec![(0u64, None::<u64>); jobs.len()]);
+        let download_status = &self.download_status;
+        let (tx, rx) = mpsc::channel();
+        thread::scope(|s| {
+            for _ in 0..PROVIDER_DOWNLOAD_WORKERS.min(jobs.len()) {
+                let tx = tx.clone();
+                s.spawn(move || loop {
+                    let i = next.fetch_add(1, Ordering::SeqCst);
+                    let (_, url, save_path) = match jobs.get(i) {
+                        Some(x) => x,
+                        None => break,
+                    };
+                    let on_progress = |r: u64, t: Option<u64>| {
+                        if let Ok(mut x) = received.lock() {
+                            x[i] = (r, t);
+                            let status = DownloadStatus::Downloading {
+                                received: x.iter().map(|x| x.0).sum(),
+                                total: x.iter().map(|x| x.1).sum(),
+                            };
+                            if let Ok(mut x) = download_status.write() {
+                                *x = status;
+                            }
+                        }
+                    };
+                    let result = download_provider_with_retry(
+                        url,
+                        save_path,
+                        attempts,
+                        options,
+                        &on_progress,
+                    );
+                    if tx.send((i, result)).is_err() {
+                        break;
+                    }
+                });
+            }
+            drop(tx);
+            let mut failed = Vec::new();
+            for (done, (i, result)) in rx.iter().enumerate() {
+                let (name, url, _) = &jobs[i];
+                match result {
+                    Ok(_) => progress(StartEvent::new(
+                        StartStage::DownloadProviders,
+                        format!("Downloaded rule provider {}", name),
+                        Some(((done + 1) * 100 / jobs.len()) as u8),
+                    )),
+                    Err(e) => {
+                        log::error!("Download Rule Provider {} failed: {}", name, e);
+                        progress(StartEvent::new(
+                            StartStage::DownloadProviders,
+                            format!("Failed to download rule provider {}", name),
+                            Some(((done + 1) * 100 / jobs.len()) as u8),
+                        ));
+                        failed.push(url.clone());
+                    }
+                }
+            }
+            failed
+        })
+    }
+}
+
+/// 写入运行配置的 dns 部分
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct DnsConfig {
+    pub enable: bool,
+    pub listen: String,
+    pub enhanced_mode: String,
+    pub fake_ip_range: String,
+    /// 关闭时不写入，与之前生成的配置一致
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub ipv6: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fake_ip_range6: Option<String>,
+    pub nameserver: Vec<String>,
+}
+
+impl DnsConfig {
+    pub fn from_settings(settings: &Settings) -> Self {
+        //systemd-resolved 占用了 53 端口
+        let listen =
+            if settings.dns_listen == default_dns_listen() && helper::uses_systemd_resolved() {
+                String::from("0.0.0.0:5354")
+            } else {
+                settings.dns_listen.clone()
+            };
+        Self {
+            enable: true,
+            listen,
+            enhanced_mode: settings.dns_enhanced_mode.clone(),
+            fake_ip_range: settings.fake_ip_range.clone(),
+            ipv6: settings.ipv6,
+            fake_ip_range6: if settings.ipv6 {
+                Some(settings.fake_ip_range6.clone())
+            } else {
+                None
+            },
+            nameserver: settings.dns_nameserver.clone(),
+        }
+    }
+}
+
+/// 生成运行配置需要的选项，由 `Settings` 和运行环境得到
+#[derive(Debug)]
+pub struct ConfigOptions {
+    pub external_controller: String,
+    pub secret: String,
+    pub mixed_port: Option<u16>,
+    pub max_download_kbps: Option<u32>,
+    pub max_upload_kbps: Option<u32>,
+    pub allow_lan: bool,
+    pub skip_proxy: bool,
+    pub bypass_lan: bool,
+    pub direct_domains: Vec<String>,
+    pub core_type: CoreType,
+    /// 核心是否支持 Clash.Meta 的配置
+    pub meta_core: bool,
+    pub tun_stack: Option<TunStack>,
+    pub unified_delay: bool,
+    pub tcp_concurrent: bool,
+    /// 内置面板的目录，`None` 时保留订阅中的 `external-ui`
+    pub webui_dir: Option<PathBuf>,
+    pub tun_enabled: bool,
+    pub excluded_interfaces: Vec<String>,
+    pub ipv6: bool,
+    pub dns: DnsConfig,
+    pub dns_override: DnsOverride,
+    pub proxy_mode: ProxyMode,
+    /// 用户的 yaml 片段，最后合并
+    pub config_override: Option<String>,
+    /// 下载 Provider 的参数，`transform_config` 不使用
+    pub download: DownloadOptions,
+    pub provider_download_attempts: u32,
+}
+
+impl ConfigOptions {
+    pub fn from_settings(settings: &Settings) -> Self {
+        Self {
+            external_controller: settings.external_controller.clone(),
+            secret: settings.secret.clone(),
+            mixed_port: settings.mixed_port,
+            max_download_kbps: settings.max_download_kbps,
+            max_upload_kbps: settings.max_upload_kbps,
+            allow_lan: settings.allow_lan,
+            skip_proxy: settings.skip_proxy,
+            bypass_lan: settings.bypass_lan,
+            direct_domains: settings.direct_domains.clone(),
+            core_type: settings.core_type,
+            meta_core: settings.core_type == CoreType::Meta,
+            tun_stack: settings.tun_stack,
+            unified_delay: settings.unified_delay,
+            tcp_concurrent: settings.tcp_concurrent,
+            webui_dir: settings.use_bundled_dashboard.then(|| {
+                helper::install_root()
+                    .unwrap_or_default()
+                    .join("bin/core/web")
+            }),
+            tun_enabled: settings.tun_enabled,
+            excluded_interfaces: settings.excluded_interfaces.clone(),
+            ipv6: settings.ipv6,
+            dns: DnsConfig::from_settings(settings),
+            dns_override: settings.dns_override,
+            proxy_mode: settings.proxy_mode,
+            config_override: settings.config_override.clone(),
+            download: DownloadOptions::from_settings(settings),
+            provider_download_attempts: settings.provider_download_attempts,
+        }
+    }
+}
+
+impl From<&Settings> for ConfigOptions {
+    fn from(settings: &Settings) -> Self {
+        Self::from_settings(settings)
+    }
+}
+
+/// 把订阅改写为运行配置，不读写文件也不下载 Rule Provider
+pub fn transform_config(input: Value, opts: &ConfigOptions) -> Result<Value, ClashError> {
+    let mut input = match helper::wrap_proxy_list(&input) {
+        Some(x) => {
+            log::info!("The config is a bare proxy list, wrapped into a Proxy group.");
+            x
+        }
+        None => input,
+    };
+    let yaml = match input.as_mapping_mut() {
+        Some(x) => x,
+        None => {
+            return Err(ClashError {
+                message: String::from(
+                    "The config root must be a mapping with proxies and rules, \
+                     or a list of proxies.",
+                ),
+                kind: ClashErrorKind::ConfigFormatError,
+            });
+        }
+    };
+
+    //修改 WebUI
+
+    match yaml.get_mut("external-controller") {
+        Some(x) => {
+            *x = Value::String(opts.external_controller.clone());
+        }
+        None => {
+            yaml.insert(
+                Value::String(String::from("external-controller")),
+                Value::String(opts.external_controller.clone()),
+            );
+        }
+    }
+
+    // 设置了密码时覆盖订阅中的 secret，否则保留订阅自带的
+    if !opts.secret.is_empty() {
+        yaml.insert(
+            Value::String(String::from("secret")),
+            Value::String(opts.secret.clone()),
+        );
+    }
+
+    if let Some(port) = opts.mixed_port {
+        yaml.insert(
+            Value::String(String::from("mixed-port")),
+            Value::Number(port.into()),
+        );
+    }
+
+    //局域网共享，关闭时显式写入 false 避免订阅自带的配置生效
+    yaml.insert(
+        Value::String(String::from("allow-lan")),
+        Value::Bool(opts.allow_lan),
+    );
+    if opts.allow_lan {
+        yaml.insert(
+            Value::String(String::from("bind-address")),
+            Value::String(String::from("*")),
+        );
+        //其他设备需要一个可以连接的端口
+        let has_port = ["mixed-port", "port", "socks-port"]
+            .iter()
+            .any(|x| yaml.get(*x).and_then(|x| x.as_u64()).unwrap_or(0) != 0);
+        if !has_port {
+            yaml.insert(
+                Value::String(String::from("mixed-port")),
+                Value::Number(DEFAULT_MIXED_PORT.into()),
+            );
+        }
+    }
+
+    //依次插入局域网、Steam 下载和直连域名的规则，直连域名默认包含 test.steampowered.com
+    let rules = yaml
+        .entry(Value::String(String::from("rules")))
+        .or_insert_with(|| Value::Sequence(Vec::new()));
+    let rules = match rules.as_sequence_mut() {
+        Some(x) => x,
+        None => {
+            return Err(ClashError {
+                message: String::from("rules is not a sequence"),
+                kind: ClashErrorKind::ConfigFormatError,
+            });
+        }
+    };
+    let mut direct_rules: Vec<Value> = Vec::new();
+    if opts.bypass_lan {
+        for cidr in LAN_CIDRS {
+            direct_rules.push(Value::String(format!("IP-CIDR,{},DIRECT,no-resolve", cidr)));
+        }
+    }
+    if opts.skip_proxy {
+        direct_rules.push(Value::String(String::from(
+            "DOMAIN-SUFFIX,cm.steampowered.com,DIRECT",
+        )));
+    }
+    for domain in &opts.direct_domains {
+        direct_rules.push(Value::String(format!("DOMAIN,{},DIRECT", domain)));
+    }
+    //已有相同的规则时不再插入，重复处理同一份配置时结果不变
+    let mut existing: Vec<String> = rules
+        .iter()
+        .filter_map(|x| x.as_str())
+        .map(rule_key)
+        .collect();
+    direct_rules.retain(|x| {
+        let key = rule_key(x.as_str().unwrap_or_default());
+        if existing.contains(&key) {
+            return false;
+        }
+        existing.push(key);
+        true
+    });
+    rules.splice(0..0, direct_rules);
+
+    // Meta 核心的延迟与并发优化，只在用户开启时覆盖
+    let meta_tuning = [
+        ("unified-delay", opts.unified_delay),
+        ("tcp-concurrent", opts.tcp_concurrent),
+    ];
+    for (key, enabled) in meta_tuning {
+        if !enabled {
+            continue;
+        }
+        if !opts.meta_core {
+            log::warn!("{} is only supported by Clash.Meta, skipped.", key);
+            continue;
+        }
+        yaml.insert(Value::String(String::from(key)), Value::Bool(true));
+    }
+
+    if let Some(webui_dir) = &opts.webui_dir {
+        match webui_dir.to_str() {
+            Some(x) => {
+                yaml.insert(
+                    Value::String(String::from("external-ui")),
+                    Value::String(x.to_string()),
+                );
+            }
+            //路径不是 UTF-8 时无法写入 yaml
+            None => log::warn!(
+                "Web UI path {} is not valid UTF-8, external-ui unchanged.",
+                webui_dir.display()
+            ),
+        }
+    }
+
+    //修改 TUN 和 DNS 配置
+
+    let tun_stack = match opts.tun_stack {
+        Some(x) if x.supported_by(opts.meta_core) => x.as_str(),
+        Some(x) => {
+            log::warn!(
+                "TUN stack {} is not supported by {:?}, using {}.",
+                x.as_str(),
+                opts.core_type,
+                opts.core_type.tun_stack()
+            );
+            opts.core_type.tun_stack()
+        }
+        None => opts.core_type.tun_stack(),
+    };
+    let tun_config = format!(
+        "
+    enable: true
+    stack: {}
+    auto-route: true
+    auto-detect-interface: true
+    ",
+        tun_stack
+    );
+
+    //部分配置来自 https://www.xkww3n.cyou/2022/02/08/use-clash-dns-anti-dns-hijacking/
+
+    //无效的地址段会让核心启动失败，提前给出明确的错误
+    if opts.dns_override != DnsOverride::Keep {
+        let ranges = [
+            ("fake_ip_range", Some(&opts.dns.fake_ip_range)),
+            ("fake_ip_range6", opts.dns.fake_ip_range6.as_ref()),
+        ];
+        for (key, range) in ranges {
+            if let Some(range) = range {
+                helper::parse_cidr(range).map_err(|e| ClashError {
+                    message: format!("Invalid {}: {}", key, e),
+                    kind: ClashErrorKind::ConfigFormatError,
+                })?;
+            }
+        }
+    }
+
+    let dns_config = serde_yaml::to_value(&opts.dns)
+        .map_err(|e| ClashError::new(ClashErrorKind::InnerError, e.to_string()))?;
+
+    let profile_config = "
+    store-selected: true
+    store-fake-ip: false
+    ";
+
+    //已有的键直接覆盖，Mapping::remove 会打乱其余键的顺序
+    let insert_config = |yaml: &mut Mapping, config: &str, key: &str| {
+        let inner_config: Value = serde_yaml::from_str(config).unwrap();
+        yaml.insert(Value::String(String::from(key)), inner_config);
+    };
+
+    //开启 tun 模式
+    //关闭时显式写入 enable: false，避免订阅自带的 tun 配置生效
+    //DNS 与 set_system_network 不受影响，系统 DNS 仍然指向 Clash，
+    //此时只有通过代理端口连接的应用才能正常使用 fake-ip
+    let tun_config = if opts.tun_enabled {
+        tun_config.as_str()
+    } else {
+        "
+    enable: false
+    "
+    };
+    insert_config(yaml, tun_config, "tun");
+
+    //IPv6，关闭时保留订阅的配置
+    if opts.ipv6 {
+        yaml.insert(Value::String(String::from("ipv6")), Value::Bool(true));
+        if opts.tun_enabled {
+            if let Some(Value::Mapping(tun)) = yaml.get_mut("tun") {
+                tun.insert(
+                    Value::String(String::from("inet6-address")),
+                    Value::Sequence(vec![Value::String(String::from(TUN_INET6_ADDRESS))]),
+                );
+            }
+        }
+    }
+
+    //限速只能写到 Meta 的 Hysteria 节点上，核心没有全局限速
+    //TUN 接管的流量同样按节点限速，DIRECT 连接不受影响
+    if opts.max_download_kbps.is_some() || opts.max_upload_kbps.is_some() {
+        if opts.meta_core {
+            apply_bandwidth_limit(yaml, opts.max_download_kbps, opts.max_upload_kbps);
+        } else {
+            log::warn!("Bandwidth limit is only supported by Clash.Meta, ignored.");
+        }
+    }
+
+    //其他 VPN 的网卡不经过 TUN
+    if opts.tun_enabled && !opts.excluded_interfaces.is_empty() {
+        if !opts.meta_core {
+            log::warn!("exclude-interface is only supported by Clash.Meta, skipped.");
+        } else if let Some(Value::Mapping(tun)) = yaml.get_mut("tun") {
+            tun.insert(
+                Value::String(String::from("exclude-interface")),
+                Value::Sequence(
+                    opts.excluded_interfaces
+                        .iter()
+                        .map(|x| Value::String(x.clone()))
+                        .collect(),
+                ),
+            );
+        }
+    }
+
+    match (opts.dns_override, yaml.get_mut("dns")) {
+        (DnsOverride::Keep, _) => {
+            log::info!("Keep the DNS config from subscription.");
+        }
+        (DnsOverride::Merge, Some(Value::Mapping(user_dns))) => {
+            //保留订阅的 nameserver 等配置，只确保 DNS 能被系统使用
+            if let Value::Mapping(dns) = dns_config {
+                for (key, value) in dns {
+                    let required = matches!(
+                        key.as_str(),
+                        Some("enable") | Some("enhanced-mode") | Some("listen")
+                    );
+                    if required || !user_dns.contains_key(&key) {
+                        user_dns.insert(key, value);
+                    }
+                }
+            }
+        }
+        _ => {
+            //覆盖订阅的 DNS 配置
+            yaml.insert(Value::String(String::from("dns")), dns_config);
+        }
+    }
+
+    yaml.insert(
+        Value::String(String::from("mode")),
+        Value::String(opts.proxy_mode.as_str().to_string()),
+    );
+
+    // 保存上次的配置
+    insert_config(yaml, profile_config, "profile");
+
+    //Meta 核心开启 sniffer，不覆盖订阅自带的配置
+    let sniffer_config = "
+    enable: true
+    sniff:
+      HTTP:
+        ports: [80, 8080-8880]
+        override-destination: true
+      TLS:
+        ports: [443, 8443]
+    ";
+    if opts.core_type == CoreType::Meta && !yaml.contains_key("sniffer") {
+        insert_config(yaml, sniffer_config, "sniffer");
+    }
+
+    //用户片段，在所有修改之后合并
+    if let Some(config_override) = opts.config_override.as_deref() {
+        if !config_override.trim().is_empty() {
+            let overlay: Value = serde_yaml::from_str(config_override).map_err(|e| ClashError {
+                message: format!("config_override is not valid yaml: {}", e),
+                kind: ClashErrorKind::ConfigFormatError,
+            })?;
+            if !overlay.is_mapping() {
+                return Err(ClashError {
+                    message: String::from("config_override must be a yaml mapping"),
+                    kind: ClashErrorKind::ConfigFormatError,
+                });
+            }
+            helper::merge_yaml(&mut input, overlay);
+        }
+    }
+
+    Ok(input)
+}
+
+/// 把限速写入 Hysteria / Hysteria2 节点，订阅中更低的值保持不变
+fn apply_bandwidth_limit(yaml: &mut Mapping, download: Option<u32>, upload: Option<u32>) {
+    let proxies = match yaml.get_mut("proxies") {
+        Some(Value::Sequence(x)) => x,
+        _ => return,
+    };
+    let mut skipped = 0;
+    for proxy in proxies.iter_mut().filter_map(|x| x.as_mapping_mut()) {
+        match proxy.get("type").and_then(|x| x.as_str()) {
+            Some("hysteria") | Some("hysteria2") => {}
+            _ => {
+                skipped += 1;
+                continue;
+            }
+        }
+        for (key, limit) in [("down", download), ("up", upload)] {
+            let limit = match limit {
+                Some(x) => x,
+                None => continue,
+            };
+            let current = proxy.get(key).and_then(helper::parse_bandwidth_kbps);
+            if current.is_none_or(|x| x > limit as u64) {
+                proxy.insert(
+                    Value::String(String::from(key)),
+                    Value::String(format!("{} Kbps", limit)),
+                );
+            }
+        }
+    }
+    if skipped > 0 {
+        log::warn!(
+            "Bandwidth limit only applies to hysteria proxies, {} proxies are not limited.",
+            skipped
+        );
+    }
+}
+
+/// Rule Provider 的下载地址，没有 `url` 或不是 http / https 链接时返回 `None`
+fn provider_url<'a>(name: &str, provider: &'a Value) -> Result<Option<&'a str>, ClashError> {
+    let url = match provider.get("url") {
+        Some(Value::String(x)) => x.as_str(),
+        Some(_) => {
+            return Err(ClashError {
+                message: format!("url of rule provider {} is not a string", name),
+                kind: ClashErrorKind::ConfigFormatError,
+            });
+        }
+        None => return Ok(None),
+    };
+    if !helper::is_http_url(url) {
+        log::warn!("Skip rule provider {} with unsupported url: {}", name, url);
+        return Ok(None);
+    }
+    Ok(Some(url))
+}
+
+/// 规则比较时忽略空格和大小写，`DOMAIN, a.com ,direct` 与 `DOMAIN,a.com,DIRECT` 相同
+fn rule_key(rule: &str) -> String {
+    rule.split(',')
+        .map(|x| x.trim().to_lowercase())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// 安装目录下的路径，安装目录无法确定时返回 `InnerError`
+fn install_path<P: AsRef<Path>>(path: P) -> Result<PathBuf, ClashError> {
+    helper::install_root()
+        .map(|x| x.join(path))
+        .map_err(|e| ClashError::new(ClashErrorKind::InnerError, e.to_string()))
+}
+
+/// 运行配置中的 `secret`，可能来自设置或订阅，没有时返回 `None`
+pub fn running_config_secret() -> Option<String> {
+    let config = fs::read_to_string(running_config_path().ok()?).ok()?;
+    let yaml: Value = serde_yaml::from_str(&config).ok()?;
+    config_secret(&yaml)
+}
+
+pub fn config_secret(yaml: &Value) -> Option<String> {
+    yaml.get("secret")
+        .and_then(|x| x.as_str())
+        .filter(|x| !x.is_empty())
+        .map(|x| x.to_string())
+}
+
+/// 用 `temp_path` 替换 `core_path`，旧的核心保留为 `.bak`，替换失败时放回原处
+pub(crate) fn swap_core(temp_path: &Path, core_path: &Path) -> Result<(), ClashError> {
+    let backup_path = core_path.with_extension("bak");
+    let backed_up = core_path.exists();
+    if backed_up {
+        fs::rename(core_path, &backup_path).map_err(|e| ClashError {
+            message: format!("Failed to back up Clash core: {}", e),
+            kind: ClashErrorKind::InnerError,
+        })?;
+    }
+    if let Err(e) = fs::rename(temp_path, core_path) {
+        if backed_up {
+            if let Err(e) = fs::rename(&backup_path, core_path) {
+                log::error!("Failed to restore Clash core from backup: {}", e);
+            }
+        }
+        return Err(ClashError {
+            message: format!("Failed to replace Clash core: {}", e),
+            kind: ClashErrorKind::InnerError,
+        });
+    }
+    Ok(())
+}
+
+/// 生成的运行配置路径
+pub fn running_config_path() -> std::io::Result<PathBuf> {
+    Ok(helper::install_root()?.join("bin/core/running_config.yaml"))
+}
+
+/// 配置中可以作为 HTTP 代理使用的端口，优先使用 mixed-port
+pub fn proxy_port(yaml: &Value) -> Option<u16> {
+    ["mixed-port", "port"]
+        .iter()
+        .find_map(|key| config_port(yaml, key))
+}
+
+/// 配置中的端口，没有设置或为 0 时返回 `None`
+fn config_port(yaml: &Value, key: &str) -> Option<u16> {
+    yaml.get(key)
+        .and_then(|x| x.as_u64())
+        .and_then(|x| u16::try_from(x).ok())
+        .filter(|x| *x != 0)
+}
+
+/// 正在运行的 Clash 的本机代理地址，运行配置没有代理端口时返回 `None`
+fn local_proxy() -> Option<String> {
+    let config = fs::read_to_string(running_config_path().ok()?).ok()?;
+    let yaml: Value = serde_yaml::from_str(&config).ok()?;
+    match proxy_port(&yaml) {
+        Some(port) => Some(format!("127.0.0.1:{}", port)),
+        None => {
+            log::info!("Running config has no mixed-port or port, downloading directly.");
+            None
+        }
+    }
+}
+
+/// Rule Provider 的保存目录，只存放 ToMoon 下载的文件，不与用户自己的 `~/.config/clash` 混用
+pub fn provider_dir(home: &Path) -> PathBuf {
+    home.join(".config/tomoon/providers")
+}
+
+/// 需要缓存到本地的 Provider，两种 Provider 的 `url` / `path` 格式相同
+const PROVIDER_KEYS: [&str; 2] = ["rule-providers", "proxy-providers"];
+
+/// 本地还不存在的 Provider，返回 (name, url, save_path)
+/// 多个 Provider 指向同一路径时只下载一次
+pub fn provider_jobs(
+    yaml: &Value,
+    base: &Path,
+) -> Result<Vec<(String, String, PathBuf)>, ClashError> {
+    let mut jobs: Vec<(String, String, PathBuf)> = Vec::new();
+    for key in PROVIDER_KEYS {
+        let providers = match yaml.get(key).and_then(|x| x.as_mapping()) {
+            Some(x) => x,
+            None => continue,
+        };
+        for (name, value) in providers {
+            let name = name.as_str().unwrap_or_default();
+            let (url, path) = match (
+                provider_url(name, value)?,
+                value.get("path").and_then(|x| x.as_str()),
+            ) {
+                (Some(url), Some(path)) => (url, path),
+                _ => continue,
+            };
+            let save_path = provider_save_path(base, name, path)?;
+            if save_path.exists() || jobs.iter().any(|x| x.2 == save_path) {
+                continue;
+            }
+            jobs.push((name.to_string(), url.to_string(), save_path));
+        }
+    }
+    Ok(jobs)
+}
+
+/// Provider 在本地的保存路径，必须位于 `base` 下
+/// 含有 `..` 或指向 `base` 以外的绝对路径时返回错误，避免以 root 权限写入任意位置
+/// 已经由 `rewrite_provider_paths` 改写过的绝对路径保持不变
+fn provider_save_path(base: &Path, name: &str, path: &str) -> Result<PathBuf, ClashError> {
+    let unsafe_path = || {
+        ClashError::new(
+            ClashErrorKind::ConfigFormatError,
+            format!("Provider {} has unsafe path {}", name, path),
+        )
+    };
+    let save_path = Path::new(path);
+    if save_path
+        .components()
+        .any(|x| x == std::path::Component::ParentDir)
+    {
+        return Err(unsafe_path());
+    }
+    if save_path.is_absolute() {
+        if !save_path.starts_with(base) {
+            return Err(unsafe_path());
+        }
+        return Ok(save_path.to_path_buf());
+    }
+    //替换有些规则前的 ./
+    Ok(base.join(save_path.strip_prefix("./").unwrap_or(save_path)))
+}
+
+/// 把 Provider 的 `path` 改写为 `base` 下的绝对路径，核心不依赖工作目录也能找到
+/// 订阅中的路径跳出 `base` 时返回错误
+pub fn rewrite_provider_paths(yaml: &mut Value, base: &Path) -> Result<(), ClashError> {
+    for key in PROVIDER_KEYS {
+        let providers = match yaml.get_mut(key).and_then(|x| x.as_mapping_mut()) {
+            Some(x) => x,
+            None => continue,
+        };
+        for (name, value) in providers.iter_mut() {
+            let path = match value.get("path").and_then(|x| x.as_str()) {
+                Some(x) => provider_save_path(base, name.as_str().unwrap_or_default(), x)?,
+                None => continue,
+            };
+            if let Value::Mapping(x) = value {
+                x.insert(
+                    Value::String("path".to_string()),
+                    Value::String(path.to_string_lossy().to_string()),
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// 逐块读取响应内容，每读到一块就回报已接收的字节数
+fn read_with_progress(
+    mut response: minreq::ResponseLazy,
+    on_progress: &dyn Fn(u64, Option<u64>),
+) -> std::io::Result<Vec<u8>> {
+    let total = response
+        .headers
+        .get("content-length")
+        .and_then(|x| x.parse::<u64>().ok());
+    let mut body = Vec::new();
+    let mut buf = [0u8; 8192];
+    on_progress(0, total);
+    loop {
+        let n = response.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        body.extend_from_slice(&buf[..n]);
+        on_progress(body.len() as u64, total);
+    }
+    Ok(body)
+}
+
+/// 下载 Provider 到 `save_path`，返回是否写入了新内容，服务器返回 304 时为 false
+fn download_provider(
+    url: &str,
+    save_path: &Path,
+    options: &DownloadOptions,
+    on_progress: &dyn Fn(u64, Option<u64>),
+) -> Result<bool, ClashError> {
+    let mut request = helper::get_request(url, options);
+    //已有缓存时发送条件请求
+    if let Some(cache) = ProviderCache::load(save_path) {
+        if let Some(etag) = cache.etag {
+            request = request.with_header("If-None-Match", etag);
+        }
+        if let Some(last_modified) = cache.last_modified {
+            request = request.with_header("If-Modified-Since", last_modified);
+        }
+    }
+    match request.send_lazy() {
+        Ok(response) => {
+            if response.status_code == 304 {
+                log::info!("Rule-Provider {} not modified.", save_path.display());
+                //更新修改时间，避免被当作过期再次下载
+                if let Err(e) = fs::File::options()
+                    .append(true)
+                    .open(save_path)
+                    .and_then(|x| x.set_modified(SystemTime::now()))
+                {
+                    log::warn!("Failed to touch {}: {}", save_path.display(), e);
+                }
+                return Ok(false);
+            }
+            if let Err(e) = helper::check_status(response.status_code, &response.reason_phrase) {
+                log::error!(
+                    "Error occurred while downloading Rule Provder {}: {}",
+                    url,
+                    e
+                );
+                return Err(ClashError {
+                    kind: ClashErrorKind::RuleProviderDownloadError,
+                    message: format!(
+                        "Error occurred while downloading Rule Provder with error message : {}",
+                        e
+                    ),
+                });
+            }
+            let cache = ProviderCache::from_headers(&response.headers);
+            let encoding = response.headers.get("content-encoding").cloned();
+            let body = read_with_progress(response, on_progress)
+                .and_then(|x| helper::decode_body(x, encoding.as_deref()))
+                .map_err(|e| ClashError {
+                    kind: if helper::is_io_timeout(&e) {
+                        ClashErrorKind::Timeout
+                    } else {
+                        ClashErrorKind::RuleProviderDownloadError
+                    },
+                    message: format!(
+                        "Error occurred while downloading Rule Provder with error message : {}",
+                        e
+                    ),
+                })?;
+            let response = match std::str::from_utf8(&body) {
+                Ok(x) => x,
+                Err(_) => {
+                    log::error!("Error occurred while parase Rule Provder.");
+                    return Err(ClashError {
+                        kind: ClashErrorKind::RuleProviderDownloadError,
+                        message: String::from("Error occurred while parase Rule Provder."),
+                    });
+                }
+            };
+
+            //保存订阅
+            if let Some(parent) = save_path.parent() {
+                if let Err(e) = std::fs::create_dir_all(parent) {
+                    log::error!("Failed while creating sub dir.");
+                    log::error!("Error Message:{}", e);
+                    return Err(ClashError {
+                        kind: ClashErrorKind::RuleProviderDownloadError,
+                        message: "Error occurred while creating Rule Provder dir.".to_string(),
+                    });
+                }
+            }
+
+            match helper::write_atomic(save_path, response) {
+                Ok(_) => {
+                    log::info!("Rule-Provider {} downloaded.", save_path.display());
+                    cache.save(save_path);
+                }
+                Err(_) => {
+                    log::error!(
+                        "Error occurred while saving Rule Provder. path: {}",
+                        save_path.to_str().unwrap()
+                    );
+                    return Err(ClashError {
+                        kind: ClashErrorKind::RuleProviderDownloadError,
+                        message: "Error occurred while downloading Rule Provder.".to_string(),
+                    });
+                }
+            }
+        }
+        Err(e) => {
+            let in_msg = e.to_string();
+            let mut err_msg =
+                String::from("Error occurred while downloading Rule Provder with error message : ");
+            err_msg.push_str(in_msg.as_str());
+            return Err(ClashError {
+                kind: if helper::is_timeout(&e) {
+                    ClashErrorKind::Timeout
+                } else {
+                    ClashErrorKind::RuleProviderDownloadError
+                },
+                message: err_msg,
+            });
+        }
+    }
+    Ok(true)
+}
+
+/// 保存在 Rule Provider 旁边的缓存信息，用于条件请求
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct ProviderCache {
+    etag: Option<String>,
+    last_modified: Option<String>,
+}
+
+impl ProviderCache {
+    fn path(save_path: &Path) -> PathBuf {
+        let mut name = save_path.file_name().unwrap_or_default().to_os_string();
+        name.push(".meta.json");
+        save_path.with_file_name(name)
+    }
+
+    /// 缓存文件或 Rule Provider 不存在、内容损坏时返回 `None`，此时完整下载
+    fn load(save_path: &Path) -> Option<Self> {
+        if !save_path.exists() {
+            return None;
+        }
+        let content = fs::read_to_string(Self::path(save_path)).ok()?;
+        match serde_json::from_str(&content) {
+            Ok(x) => Some(x),
+            Err(e) => {
+                log::warn!("Ignore corrupt cache of {}: {}", save_path.display(), e);
+                None
+            }
+        }
+    }
+
+    fn from_headers(headers: &std::collections::HashMap<String, String>) -> Self {
+        Self {
+            etag: headers.get("etag").cloned(),
+            last_modified: headers.get("last-modified").cloned(),
+        }
+    }
+
+    fn save(&self, save_path: &Path) {
+        let path = Self::path(save_path);
+        if self.etag.is_none() && self.last_modified.is_none() {
+            let _ = fs::remove_file(path);
+            return;
+        }
+        let result = serde_json::to_string(self)
+            .map_err(|e| e.to_string())
+            .and_then(|x| helper::write_atomic(&path, x).map_err(|e| e.to_string()));
+        if let Err(e) = result {
+            log::warn!("Failed to save cache {}: {}", path.display(), e);
+        }
+    }
+}
+
+/// 下载失败时按指数退避重试，全部失败后返回最后一次的错误
+fn download_provider_with_retry(
+    url: &str,
+    save_path: &Path,
+    attempts: u32,
+    options: &DownloadOptions,
+    on_progress: &dyn Fn(u64, Option<u64>),
+) -> Result<(), ClashError> {
+    let attempts = attempts.max(1);
+    let mut delay = PROVIDER_RETRY_DELAY;
+    let mut attempt = 1;
+    loop {
+        match download_provider(url, save_path, options, on_progress) {
+            Ok(_) => return Ok(()),
+            Err(e) if attempt < attempts => {
+                log::warn!(
+                    "Download Rule Provider {} failed ({}/{}), retry in {}s: {}",
+                    url,
+                    attempt,
+                    attempts,
+                    delay.as_secs(),
+                    e
+                );
+                thread::sleep(delay);
+                delay *= 2;
+                attempt += 1;
+            }
+            Err(e) => {
+                log::error!(
+                    "Download Rule Provider {} failed after {} attempts.",
+                    url,
+                    attempts
+                );
+                return Err(e);
+            }
+        }
+    }
+}
+
+/// 等待 `delay` 后检查核心是否已经退出，退出时返回日志末尾的内容
+pub(crate) fn check_core_started(
+    child: &mut Child,
+    log_path: &Path,
+    delay: Duration,
+) -> Result<(), ClashError> {
+    thread::sleep(delay);
+    let status = match child.try_wait()? {
+        Some(x) => x,
+        None => return Ok(()),
+    };
+    let output = helper::tail_file(log_path, CORE_EXIT_LOG_LINES, 64 * 1024)
+        .map(|x| x.join("\n"))
+        .unwrap_or_default();
+    log::error!("Clash exited right after start ({}): {}", status, output);
+    Err(ClashError {
+        message: if output.is_empty() {
+            format!("Clash exited right after start ({})", status)
+        } else {
+            output
+        },
+        kind: ClashErrorKind::CoreExited,
+    })
+}
+
+/// 下载 Clash 核心
+fn download_core(url: &str, on_progress: &dyn Fn(u64, Option<u64>)) -> Result<Vec<u8>, ClashError> {
+    download_large_file(url, "Clash core", on_progress)
+}
+
+/// 下载较大的文件，`what` 用于错误信息
+fn download_large_file(
+    url: &str,
+    what: &str,
+    on_progress: &dyn Fn(u64, Option<u64>),
+) -> Result<Vec<u8>, ClashError> {
+    let network_error = |e: String| ClashError {
+        message: format!("Error occurred while downloading {}: {}", what, e),
+        kind: ClashErrorKind::NetworkError,
+    };
+    let response = minreq::get(url)
+        .with_timeout(CORE_DOWNLOAD_TIMEOUT)
+        .with_header(
+            "User-Agent",
+            format!("ToMoonClash/{}", env!("CARGO_PKG_VERSION")),
+        )
+        .send_lazy()
+        .map_err(|e| network_error(e.to_string()))?;
+    if response.status_code != 200 {
+        return Err(network_error(format!(
+            "status code {}",
+            response.status_code
+        )));
+    }
+    read_with_progress(response, on_progress).map_err(|e| network_error(e.to_string()))
+}
+
+/// 比较内容的 SHA-256 与期望值（十六进制，不区分大小写）
+pub(crate) fn verify_checksum(body: &[u8], expected_sha256: &str) -> Result<(), ClashError> {
+    let digest = Sha256::digest(body)
+        .iter()
+        .map(|x| format!("{:02x}", x))
+        .collect::<String>();
+    if digest.eq_ignore_ascii_case(expected_sha256.trim()) {
+        Ok(())
+    } else {
+        Err(ClashError {
+            message: format!(
+                "Checksum mismatch, expected {} but got {}",
+                expected_sha256, digest
+            ),
+            kind: ClashErrorKind::ChecksumMismatch,
+        })
+    }
+}
+
+/// 下载并校验所有数据库后再逐个替换，已经复制到 Clash 目录的旧文件也一起更新
+fn download_geo_databases(
+    databases: &[GeoDatabase],
+    on_progress: &dyn Fn(u64, Option<u64>),
+) -> Result<(), ClashError> {
+    let inner_error = |e: std::io::Error| ClashError {
+        message: format!("Error occurred while saving geo database: {}", e),
+        kind: ClashErrorKind::InnerError,
+    };
+    let core_dir = helper::install_root()
+        .map_err(inner_error)?
+        .join("bin/core");
+    let mut bodies = Vec::new();
+    for database in databases {
+        //文件名不能跳出 bin/core
+        let file = &database.file;
+        if file.is_empty() || file.contains('/') || file.contains("..") {
+            return Err(ClashError {
+                message: format!("Invalid geo database file name: {}", database.file),
+                kind: ClashErrorKind::ConfigFormatError,
+            });
+        }
+        log::info!("Downloading {} from {}", database.file, database.url);
+        let body = download_large_file(&database.url, &database.file, on_progress)?;
+        if let Some(sha256) = database.sha256.as_deref().filter(|x| !x.is_empty()) {
+            verify_checksum(&body, sha256)?;
+        }
+        bodies.push((database.file.as_str(), body));
+    }
+    for (file, body) in bodies {
+        helper::write_atomic(&core_dir.join(file), &body).map_err(inner_error)?;
+        let clash_copy = Path::new("/root/.config/clash").join(file);
+        if clash_copy.exists() {
+            helper::write_atomic(&clash_copy, &body).map_err(inner_error)?;
+        }
+    }
+    Ok(())
+}
+
+/// 从 `-v` 的输出中取出版本号，例如 `Clash v1.18.0 linux amd64 ...` 中的 `v1.18.0`
+pub fn parse_core_version(output: &str) -> Option<String> {
+    let line = output.lines().map(|x| x.trim()).find(|x| !x.is_empty())?;
+    let version = line.split_whitespace().find(|x| {
+        x.strip_prefix('v')
+            .and_then(|x| x.chars().next())
+            .is_some_and(|x| x.is_ascii_digit())
+    });
+    Some(version.unwrap_or(line).to_string())
+}
+
+/// 先发送 SIGTERM 让核心清理 TUN 网卡，`timeout` 内没有退出时再 SIGKILL
+pub(crate) fn terminate_core(child: &mut Child, timeout: Duration) -> std::io::Result<StopMethod> {
+    if child.try_wait()?.is_some() {
+        return Ok(StopMethod::Graceful);
+    }
+    match Command::new("kill")
+        .arg("-TERM")
+        .arg(child.id().to_string())
+        .status()
+    {
+        Ok(x) if x.success() => {
+            let deadline = Instant::now() + timeout;
+            while Instant::now() < deadline {
+                if child.try_wait()?.is_some() {
+                    return Ok(StopMethod::Graceful);
+                }
+                thread::sleep(Duration::from_millis(100));
+            }
+            log::warn!(
+                "Core did not exit within {:?} after SIGTERM, killing it.",
+                timeout
+            );
+        }
+        Ok(x) => log::warn!("kill -TERM exited with {}", x),
+        Err(e) => log::warn!("Error occurred while sending SIGTERM: {}", e),
+    }
+    if let Err(e) = child.kill() {
+        log::warn!("Error occurred while killing Clash: {}", e);
+    }
+    child.wait()?;
+    Ok(StopMethod::Forced)
+}
+
+/// 检查核心是否存在，缺少可执行权限时补上
+fn ensure_core_executable(path: &Path) -> Result<(), ClashError> {
+    let metadata = match fs::metadata(path) {
+        Ok(x) if x.is_file() => x,
+        _ => {
+            log::error!("Clash core not found: {}", path.display());
+            return Err(ClashError {
+                message: format!("Clash core not found: {}", path.display()),
+                kind: ClashErrorKind::CoreNotFound,
+            });
+        }
+    };
+    let mode = metadata.permissions().mode();
+    if mode & 0o111 != 0o111 {
+        log::warn!(
+            "Clash core {} is not executable, fixing permissions.",
+            path.display()
+        );
+        fs::set_permissions(path, fs::Permissions::from_mode(mode | 0o755)).map_err(|e| {
+            ClashError {
+                message: format!("Failed to make {} executable: {}", path.display(), e),
+                kind: ClashErrorKind::CoreNotFound,
+            }
+        })?;
+    }
+    Ok(())
+}
+
+/// 保存核心到 `save_path` 并添加可执行权限
+fn save_core(body: &[u8], save_path: &Path) -> Result<(), ClashError> {
+    let inner_error = |e: std::io::Error| ClashError {
+        message: format!("Error occurred while saving Clash core: {}", e),
+        kind: ClashErrorKind::InnerError,
+    };
+    fs::write(save_path, body).map_err(inner_error)?;
+    fs::set_permissions(save_path, fs::Permissions::from_mode(0o755)).map_err(inner_error)?;
+    Ok(())
+}
+
+/// 运行 `-v` 确认下载的文件是可以执行的 Clash 核心
+fn verify_core(path: &Path) -> Result<(), ClashError> {
+    match Command::new(path).arg("-v").output() {
+        Ok(x) if x.status.success() => {
+            log::info!(
+                "Downloaded core version: {}",
+                String::from_utf8_lossy(&x.stdout).trim()
+            );
+            Ok(())
+        }
+        Ok(x) => Err(ClashError {
+            message: format!("Downloaded core exited with {}", x.status),
+            kind: ClashErrorKind::InnerError,
+        }),
+        Err(e) => Err(ClashError {
+            message: format!("Downloaded core is not executable: {}", e),
+            kind: ClashErrorKind::InnerError,
+        }),
+    }
+}
+
+/// 重新下载运行配置中已超过 `interval` 的 Provider，返回是否有更新
+pub fn refresh_stale_providers(
+    run_config: &Path,
+    base: &Path,
+    options: &DownloadOptions,
+) -> Result<bool, ClashError> {
+    let config = fs::read_to_string(run_config)
+        .map_err(|e| ClashError::new(ClashErrorKind::ConfigNotFound, e.to_string()))?;
+    let yaml: Value = serde_yaml::from_str(config.as_str())?;
+    let providers = PROVIDER_KEYS
+        .iter()
+        .filter_map(|key| yaml.get(key).and_then(|x| x.as_mapping()))
+        .flatten();
+    let mut updated = false;
+    for (name, value) in providers {
+        let name = name.as_str().unwrap_or_default();
+        //单个 Provider 失败时继续检查其余的
+        let url = match provider_url(name, value) {
+            Ok(x) => x,
+            Err(e) => {
+                log::warn!("Skip provider {}: {}", name, e);
+                continue;
+            }
+        };
+        let (url, path, interval) = match (
+            url,
+            value.get("path").and_then(|x| x.as_str()),
+            value.get("interval").and_then(|x| x.as_u64()),
+        ) {
+            (Some(url), Some(path), Some(interval)) => (url, path, interval),
+            _ => continue,
+        };
+        //不安全的路径与生成配置时一样拒绝，不跳过
+        let save_path = provider_save_path(base, name, path)?;
+        let is_stale = match fs::metadata(&save_path).and_then(|x| x.modified()) {
+            Ok(modified) => match modified.elapsed() {
+                Ok(age) => age >= Duration::from_secs(interval),
+                Err(_) => false,
+            },
+            Err(_) => true,
+        };
+        if is_stale {
+            log::info!("Provider {} is older than {}s, updating.", name, interval);
+            match download_provider(url, &save_path, options, &|_, _| {}) {
+                Ok(changed) => updated |= changed,
+                Err(e) => log::error!("Failed to update provider {}: {}", name, e),
+            }
+        }
+    }
+    Ok(updated)
+}
+
+fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs())
+        .unwrap_or_default()
 }
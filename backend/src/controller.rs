@@ -0,0 +1,494 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::control::{self, ClashError, ClashErrorKind};
+use crate::settings::{ProxyMode, Settings};
+
+// 访问 external-controller 的超时时间（秒）
+const REQUEST_TIMEOUT: u64 = 5;
+// 健康检查的超时时间（秒），核心卡住时应尽快返回
+const HEALTH_CHECK_TIMEOUT: u64 = 1;
+// 延迟测试的默认地址与超时时间（毫秒）
+pub const DEFAULT_DELAY_TEST_URL: &str = "http://www.gstatic.com/generate_204";
+pub const DEFAULT_DELAY_TIMEOUT: u64 = 5000;
+
+/// Clash external-controller 的 REST 客户端
+#[derive(Debug, Clone)]
+pub struct Controller {
+    pub address: String,
+    pub secret: String,
+}
+
+/// `GET /proxies` 返回的单个代理或代理组
+#[derive(Debug, Clone, Deserialize)]
+struct ProxyInfo {
+    name: String,
+    #[serde(rename = "type")]
+    proxy_type: String,
+    #[serde(default)]
+    now: Option<String>,
+    #[serde(default)]
+    all: Option<Vec<String>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ProxiesResponse {
+    proxies: HashMap<String, ProxyInfo>,
+}
+
+/// 代理组及其当前选择
+#[derive(Debug, Clone, Serialize)]
+pub struct ProxyGroup {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub group_type: String,
+    pub now: Option<String>,
+    pub all: Vec<String>,
+}
+
+/// 延迟测试的结果，与访问 Clash 本身失败的 `ClashError` 区分
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DelayResult {
+    /// 延迟（毫秒）
+    Delay(u64),
+    Timeout,
+    /// 节点无法连接
+    Unreachable,
+}
+
+/// `GET /traffic` 的一次采样，单位为字节每秒
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Traffic {
+    pub up: u64,
+    pub down: u64,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct ConnectionMetadata {
+    #[serde(default)]
+    host: String,
+    #[serde(default, rename = "destinationIP")]
+    destination_ip: String,
+    #[serde(default, rename = "destinationPort")]
+    destination_port: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ConnectionInfo {
+    id: String,
+    #[serde(default)]
+    metadata: ConnectionMetadata,
+    #[serde(default)]
+    upload: u64,
+    #[serde(default)]
+    download: u64,
+    #[serde(default)]
+    chains: Vec<String>,
+    #[serde(default)]
+    rule: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ConnectionsResponse {
+    //没有连接时可能为 null
+    #[serde(default)]
+    connections: Option<Vec<ConnectionInfo>>,
+}
+
+/// 活动连接，`host` 为带端口的域名，没有域名时使用目标地址
+#[derive(Debug, Clone, Serialize)]
+pub struct Connection {
+    pub id: String,
+    pub host: String,
+    pub rule: String,
+    pub chains: Vec<String>,
+    /// 累计上传与下载的字节数
+    pub upload: u64,
+    pub download: u64,
+}
+
+/// 生效中的规则，`proxy` 为匹配后使用的代理或代理组
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Rule {
+    #[serde(rename = "type")]
+    pub rule_type: String,
+    #[serde(default)]
+    pub payload: String,
+    pub proxy: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct RulesResponse {
+    #[serde(default)]
+    rules: Vec<Rule>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DelayResponse {
+    delay: u64,
+}
+
+impl Controller {
+    pub fn new(address: String, secret: String) -> Self {
+        Self { address, secret }
+    }
+
+    /// 设置中没有密码时使用运行配置中订阅自带的 secret
+    pub fn from_settings(settings: &Settings) -> Self {
+        let secret = if settings.secret.is_empty() {
+            control::running_config_secret().unwrap_or_default()
+        } else {
+            settings.secret.clone()
+        };
+        Self::new(settings.external_controller.clone(), secret)
+    }
+
+    /// 监听在所有地址上时通过本机回环地址访问
+    fn url(&self, path: &str) -> String {
+        let address = match self.address.rsplit_once(':') {
+            Some(("0.0.0.0", port)) | Some(("", port)) | Some(("[::]", port)) => {
+                format!("127.0.0.1:{}", port)
+            }
+            _ => self.address.clone(),
+        };
+        format!("http://{}{}", address, path)
+    }
+
+    /// 内置面板的地址，设置了 secret 时放在参数中，打开后无需再输入
+    pub fn dashboard_url(&self) -> String {
+        let url = self.url("/ui/");
+        if self.secret.is_empty() {
+            url
+        } else {
+            format!("{}?secret={}", url, encode_path(&self.secret))
+        }
+    }
+
+    pub fn request(&self, method: minreq::Method, path: &str) -> minreq::Request {
+        let request = minreq::Request::new(method, self.url(path)).with_timeout(REQUEST_TIMEOUT);
+        if self.secret.is_empty() {
+            request
+        } else {
+            request.with_header("Authorization", format!("Bearer {}", self.secret))
+        }
+    }
+
+    fn send(&self, request: minreq::Request, action: &str) -> Result<minreq::Response, ClashError> {
+        match request.send() {
+            Ok(x) if (200..300).contains(&x.status_code) => Ok(x),
+            Ok(x) => Err(ClashError {
+                message: format!(
+                    "Clash rejected the {} request with status {}",
+                    action, x.status_code
+                ),
+                kind: ClashErrorKind::InnerError,
+            }),
+            Err(e) => Err(ClashError {
+                message: e.to_string(),
+                kind: ClashErrorKind::NetworkError,
+            }),
+        }
+    }
+
+    /// 读取核心版本，用于确认 external-controller 能够响应
+    pub fn version(&self) -> Result<String, ClashError> {
+        let request = self
+            .request(minreq::Method::Get, "/version")
+            .with_timeout(HEALTH_CHECK_TIMEOUT);
+        let response = self.send(request, "version")?;
+        Ok(response.as_str().unwrap_or_default().to_string())
+    }
+
+    /// 让 Clash 重新加载配置文件
+    /// `keep_connections` 为 `None` 时表示核心不支持，不带 `force` 参数
+    pub fn reload_config(
+        &self,
+        run_config: &Path,
+        keep_connections: Option<bool>,
+    ) -> Result<(), ClashError> {
+        let body = serde_json::json!({ "path": run_config.to_string_lossy() }).to_string();
+        //force=true 时 Meta 会重建监听并断开现有连接
+        let path = match keep_connections {
+            Some(keep) => format!("/configs?force={}", !keep),
+            None => "/configs".to_string(),
+        };
+        let request = self
+            .request(minreq::Method::Put, &path)
+            .with_header("Content-Type", "application/json")
+            .with_body(body);
+        self.send(request, "reload").map(|_| ())
+    }
+
+    /// 读取核心当前生效的配置，包含运行时修改过的模式和端口
+    pub fn configs(&self) -> Result<serde_yaml::Value, ClashError> {
+        let request = self.request(minreq::Method::Get, "/configs");
+        let response = self.send(request, "configs")?;
+        serde_json::from_slice(response.as_bytes()).map_err(|e| ClashError {
+            message: format!("Failed to parse configs: {}", e),
+            kind: ClashErrorKind::InnerError,
+        })
+    }
+
+    /// 运行时切换代理模式
+    pub fn set_mode(&self, mode: ProxyMode) -> Result<(), ClashError> {
+        let body = serde_json::json!({ "mode": mode.as_str() }).to_string();
+        let request = self
+            .request(minreq::Method::Patch, "/configs")
+            .with_header("Content-Type", "application/json")
+            .with_body(body);
+        self.send(request, "mode").map(|_| ())
+    }
+
+    /// 读取所有代理组，按名称排序
+    pub fn proxy_groups(&self) -> Result<Vec<ProxyGroup>, ClashError> {
+        let request = self.request(minreq::Method::Get, "/proxies");
+        let response = self.send(request, "proxies")?;
+        let proxies: ProxiesResponse =
+            serde_json::from_slice(response.as_bytes()).map_err(|e| ClashError {
+                message: format!("Failed to parse proxies: {}", e),
+                kind: ClashErrorKind::InnerError,
+            })?;
+        //只有代理组才有 all
+        let mut groups: Vec<ProxyGroup> = proxies
+            .proxies
+            .into_values()
+            .filter_map(|x| {
+                x.all.map(|all| ProxyGroup {
+                    name: x.name,
+                    group_type: x.proxy_type,
+                    now: x.now,
+                    all,
+                })
+            })
+            .collect();
+        groups.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(groups)
+    }
+
+    /// 在代理组中选择节点，配置中的 `store-selected: true` 会让选择在重载后保留
+    pub fn select_proxy(&self, group: &str, node: &str) -> Result<(), ClashError> {
+        let body = serde_json::json!({ "name": node }).to_string();
+        let request = self
+            .request(minreq::Method::Put, &format!("/proxies/{}", encode_path(group)))
+            .with_header("Content-Type", "application/json")
+            .with_body(body);
+        match request.send() {
+            Ok(x) if (200..300).contains(&x.status_code) => Ok(()),
+            Ok(x) if x.status_code == 404 => Err(ClashError {
+                message: format!("Proxy group {} not found", group),
+                kind: ClashErrorKind::ConfigNotFound,
+            }),
+            Ok(x) if x.status_code == 400 => Err(ClashError {
+                message: format!("Proxy {} not found in group {}", node, group),
+                kind: ClashErrorKind::ConfigNotFound,
+            }),
+            Ok(x) => Err(ClashError {
+                message: format!(
+                    "Clash rejected the select request with status {}",
+                    x.status_code
+                ),
+                kind: ClashErrorKind::InnerError,
+            }),
+            Err(e) => Err(ClashError {
+                message: e.to_string(),
+                kind: ClashErrorKind::NetworkError,
+            }),
+        }
+    }
+
+    /// 测试节点的延迟，`timeout` 单位为毫秒
+    pub fn proxy_delay(
+        &self,
+        node: &str,
+        test_url: &str,
+        timeout: u64,
+    ) -> Result<DelayResult, ClashError> {
+        let path = format!(
+            "/proxies/{}/delay?url={}&timeout={}",
+            encode_path(node),
+            encode_path(test_url),
+            timeout
+        );
+        //等待时间需要比测试的超时时间更长
+        let request = self
+            .request(minreq::Method::Get, &path)
+            .with_timeout(timeout / 1000 + REQUEST_TIMEOUT);
+        match request.send() {
+            Ok(x) if x.status_code == 200 => {
+                let response: DelayResponse =
+                    serde_json::from_slice(x.as_bytes()).map_err(|e| ClashError {
+                        message: format!("Failed to parse delay: {}", e),
+                        kind: ClashErrorKind::InnerError,
+                    })?;
+                Ok(DelayResult::Delay(response.delay))
+            }
+            Ok(x) if x.status_code == 408 || x.status_code == 504 => Ok(DelayResult::Timeout),
+            Ok(x) if x.status_code == 503 => Ok(DelayResult::Unreachable),
+            Ok(x) if x.status_code == 404 => Err(ClashError {
+                message: format!("Proxy {} not found", node),
+                kind: ClashErrorKind::ConfigNotFound,
+            }),
+            Ok(x) => Err(ClashError {
+                message: format!(
+                    "Clash rejected the delay request with status {}",
+                    x.status_code
+                ),
+                kind: ClashErrorKind::InnerError,
+            }),
+            Err(e) => Err(ClashError {
+                message: e.to_string(),
+                kind: ClashErrorKind::NetworkError,
+            }),
+        }
+    }
+
+    /// 读取一次实时流量，`/traffic` 是持续输出的流，读到第一行后就断开
+    pub fn traffic(&self) -> Result<Traffic, ClashError> {
+        let network_error = |e: minreq::Error| ClashError {
+            message: e.to_string(),
+            kind: ClashErrorKind::NetworkError,
+        };
+        let response = self
+            .request(minreq::Method::Get, "/traffic")
+            .send_lazy()
+            .map_err(network_error)?;
+        if !(200..300).contains(&response.status_code) {
+            return Err(ClashError {
+                message: format!(
+                    "Clash rejected the traffic request with status {}",
+                    response.status_code
+                ),
+                kind: ClashErrorKind::InnerError,
+            });
+        }
+        let mut line = Vec::new();
+        for byte in response {
+            let (byte, _) = byte.map_err(network_error)?;
+            if byte == b'\n' {
+                break;
+            }
+            line.push(byte);
+        }
+        serde_json::from_slice(&line).map_err(|e| ClashError {
+            message: format!("Failed to parse traffic: {}", e),
+            kind: ClashErrorKind::InnerError,
+        })
+    }
+
+    /// 按匹配顺序读取生效中的规则
+    pub fn rules(&self) -> Result<Vec<Rule>, ClashError> {
+        let request = self.request(minreq::Method::Get, "/rules");
+        let response = self.send(request, "rules")?;
+        let response: RulesResponse =
+            serde_json::from_slice(response.as_bytes()).map_err(|e| ClashError {
+                message: format!("Failed to parse rules: {}", e),
+                kind: ClashErrorKind::InnerError,
+            })?;
+        Ok(response.rules)
+    }
+
+    /// 读取所有活动连接
+    pub fn connections(&self) -> Result<Vec<Connection>, ClashError> {
+        let request = self.request(minreq::Method::Get, "/connections");
+        let response = self.send(request, "connections")?;
+        let response: ConnectionsResponse =
+            serde_json::from_slice(response.as_bytes()).map_err(|e| ClashError {
+                message: format!("Failed to parse connections: {}", e),
+                kind: ClashErrorKind::InnerError,
+            })?;
+        Ok(response
+            .connections
+            .unwrap_or_default()
+            .into_iter()
+            .map(|x| {
+                let metadata = x.metadata;
+                let host = if metadata.host.is_empty() {
+                    metadata.destination_ip
+                } else {
+                    metadata.host
+                };
+                let host = if metadata.destination_port.is_empty() {
+                    host
+                } else {
+                    format!("{}:{}", host, metadata.destination_port)
+                };
+                Connection {
+                    id: x.id,
+                    host,
+                    rule: x.rule,
+                    chains: x.chains,
+                    upload: x.upload,
+                    download: x.download,
+                }
+            })
+            .collect())
+    }
+
+    /// 关闭连接，Clash 对不存在的 id 也会返回成功，因此先检查连接是否存在
+    pub fn close_connection(&self, id: &str) -> Result<(), ClashError> {
+        let not_found = || ClashError {
+            message: format!("Connection {} not found", id),
+            kind: ClashErrorKind::ConfigNotFound,
+        };
+        if !self.connections()?.iter().any(|x| x.id == id) {
+            return Err(not_found());
+        }
+        let request = self.request(
+            minreq::Method::Delete,
+            &format!("/connections/{}", encode_path(id)),
+        );
+        match request.send() {
+            Ok(x) if (200..300).contains(&x.status_code) => Ok(()),
+            Ok(x) if x.status_code == 404 => Err(not_found()),
+            Ok(x) => Err(ClashError {
+                message: format!(
+                    "Clash rejected the close connection request with status {}",
+                    x.status_code
+                ),
+                kind: ClashErrorKind::InnerError,
+            }),
+            Err(e) => Err(ClashError {
+                message: e.to_string(),
+                kind: ClashErrorKind::NetworkError,
+            }),
+        }
+    }
+
+    /// 清空 fake-ip 缓存，网卡切换后旧的映射可能已失效
+    pub fn flush_fake_ip(&self) -> Result<(), ClashError> {
+        let request = self.request(minreq::Method::Post, "/cache/fakeip/flush");
+        self.send(request, "fake-ip flush").map(|_| ())
+    }
+
+    /// 等待 external-controller 可以响应请求
+    pub fn wait_ready(&self, timeout: Duration) -> bool {
+        let start = Instant::now();
+        while start.elapsed() < timeout {
+            let request = self.request(minreq::Method::Get, "/version").with_timeout(1);
+            if let Ok(x) = request.send() {
+                if x.status_code == 200 {
+                    return true;
+                }
+            }
+            thread::sleep(Duration::from_millis(200));
+        }
+        false
+    }
+}
+
+/// 代理组名经常包含中文和 emoji，放进路径前需要编码
+fn encode_path(segment: &str) -> String {
+    segment
+        .bytes()
+        .map(|x| match x {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (x as char).to_string()
+            }
+            _ => format!("%{:02X}", x),
+        })
+        .collect()
+}
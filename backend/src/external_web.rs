@@ -63,7 +63,7 @@ pub struct GetLocalIpAddressResponse {
 
 impl actix_web::ResponseError for ClashError {
     fn status_code(&self) -> actix_web::http::StatusCode {
-        if self.ErrorKind == ClashErrorKind::ConfigNotFound {
+        if self.kind == ClashErrorKind::ConfigNotFound {
             actix_web::http::StatusCode::NOT_FOUND
         } else {
             actix_web::http::StatusCode::INTERNAL_SERVER_ERROR
@@ -77,7 +77,7 @@ impl actix_web::ResponseError for ClashError {
             actix_web::http::header::CONTENT_TYPE,
             actix_web::http::header::HeaderValue::from_str(mime).unwrap(),
         );
-        res.set_body(BoxBody::new(self.Message.clone()))
+        res.set_body(BoxBody::new(self.message.clone()))
     }
 }
 
@@ -101,10 +101,7 @@ pub async fn skip_proxy(
                 Ok(x) => x,
                 Err(e) => {
                     log::error!("set_enable failed to acquire state write lock: {}", e);
-                    return Err(actix_web::Error::from(ClashError {
-                        Message: e.to_string(),
-                        ErrorKind: ClashErrorKind::InnerError,
-                    }));
+                    return Err(actix_web::Error::from(ClashError::from(e)));
                 }
             };
             state.dirty = true;
@@ -112,10 +109,10 @@ pub async fn skip_proxy(
         Err(e) => {
             log::error!("Failed while toggle skip Steam proxy.");
             log::error!("Error Message:{}", e);
-            return Err(actix_web::Error::from(ClashError {
-                Message: e.to_string(),
-                ErrorKind: ClashErrorKind::ConfigNotFound,
-            }));
+            return Err(actix_web::Error::from(ClashError::new(
+                ClashErrorKind::ConfigNotFound,
+                e.to_string(),
+            )));
         }
     }
     let r = SkipProxyResponse {
@@ -143,10 +140,10 @@ pub async fn get_skip_proxy(state: web::Data<AppState>) -> Result<HttpResponse>
         Err(e) => {
             log::error!("Failed while geting skip Steam proxy.");
             log::error!("Error Message:{}", e);
-            return Err(actix_web::Error::from(ClashError {
-                Message: e.to_string(),
-                ErrorKind: ClashErrorKind::ConfigNotFound,
-            }));
+            return Err(actix_web::Error::from(ClashError::new(
+                ClashErrorKind::ConfigNotFound,
+                e.to_string(),
+            )));
         }
     };
 }
@@ -160,10 +157,12 @@ pub async fn download_sub(
 
     let runtime_settings;
     let runtime_state;
+    let options;
     unsafe {
         let runtime = runtime.0.as_ref().unwrap();
         runtime_settings = runtime.settings_clone();
         runtime_state = runtime.state_clone();
+        options = runtime.download_options();
     }
 
     let path: PathBuf = PathBuf::from("/home/deck/.config/tomoon/subs");
@@ -177,17 +176,17 @@ pub async fn download_sub(
                 Err(e) => {
                     log::error!("Failed while creating sub dir.");
                     log::error!("Error Message:{}", e);
-                    return Err(actix_web::Error::from(ClashError {
-                        Message: e.to_string(),
-                        ErrorKind: ClashErrorKind::ConfigNotFound,
-                    }));
+                    return Err(actix_web::Error::from(ClashError::new(
+                        ClashErrorKind::ConfigNotFound,
+                        e.to_string(),
+                    )));
                 }
             };
             if !helper::check_yaml(&file_content) {
                 log::error!("The downloaded subscription is not a legal profile.");
                 return Err(actix_web::Error::from(ClashError {
-                    Message: "The downloaded subscription is not a legal profile.".to_string(),
-                    ErrorKind: ClashErrorKind::ConfigFormatError,
+                    message: "The downloaded subscription is not a legal profile.".to_string(),
+                    kind: ClashErrorKind::ConfigFormatError,
                 }));
             }
             //保存订阅
@@ -201,20 +200,14 @@ pub async fn download_sub(
                 if let Err(e) = std::fs::create_dir_all(parent) {
                     log::error!("Failed while creating sub dir.");
                     log::error!("Error Message:{}", e);
-                    return Err(actix_web::Error::from(ClashError {
-                        Message: e.to_string(),
-                        ErrorKind: ClashErrorKind::InnerError,
-                    }));
+                    return Err(actix_web::Error::from(ClashError::from(e)));
                 }
             }
             let path = path.to_str().unwrap();
             if let Err(e) = fs::write(path, file_content) {
                 log::error!("Failed while saving sub, path: {}", path);
                 log::error!("Error Message:{}", e);
-                return Err(actix_web::Error::from(ClashError {
-                    Message: e.to_string(),
-                    ErrorKind: ClashErrorKind::InnerError,
-                }));
+                return Err(actix_web::Error::from(ClashError::from(e)));
             }
             //修改下载状态
             log::info!("Download profile successfully.");
@@ -229,10 +222,7 @@ pub async fn download_sub(
                         Ok(x) => x,
                         Err(e) => {
                             log::error!("set_enable failed to acquire state write lock: {}", e);
-                            return Err(actix_web::Error::from(ClashError {
-                                Message: e.to_string(),
-                                ErrorKind: ClashErrorKind::InnerError,
-                            }));
+                            return Err(actix_web::Error::from(ClashError::from(e)));
                         }
                     };
                     state.dirty = true;
@@ -242,36 +232,42 @@ pub async fn download_sub(
                         "download_sub() faild to acquire runtime_setting write {}",
                         e
                     );
-                    return Err(actix_web::Error::from(ClashError {
-                        Message: e.to_string(),
-                        ErrorKind: ClashErrorKind::InnerError,
-                    }));
+                    return Err(actix_web::Error::from(ClashError::from(e)));
                 }
             };
         } else {
             log::error!("Cannt found file {}", local_file.to_str().unwrap());
             return Err(actix_web::Error::from(ClashError {
-                Message: format!("Cannt found file {}", local_file.to_str().unwrap()),
-                ErrorKind: ClashErrorKind::InnerError,
+                message: format!("Cannt found file {}", local_file.to_str().unwrap()),
+                kind: ClashErrorKind::InnerError,
             }));
         }
         // 是一个链接
     } else {
-        match minreq::get(url.clone())
-            .with_header(
-                "User-Agent",
-                format!("ToMoonClash/{}", env!("CARGO_PKG_VERSION")),
-            )
-            .with_timeout(15)
-            .send()
-        {
+        match helper::get_request(&url, &options).send() {
             Ok(x) => {
-                let response = x.as_str().unwrap();
-                if !helper::check_yaml(&String::from(response)) {
+                if let Err(e) = helper::check_status(x.status_code, &x.reason_phrase) {
+                    log::error!("Failed while downloading sub: {}", e);
+                    return Err(actix_web::Error::from(ClashError {
+                        message: e,
+                        kind: ClashErrorKind::NetworkError,
+                    }));
+                }
+                let response = match helper::response_text(x) {
+                    Ok(x) => x,
+                    Err(e) => {
+                        log::error!("Failed while reading sub: {}", e);
+                        return Err(actix_web::Error::from(ClashError::new(
+                            ClashErrorKind::ConfigFormatError,
+                            e,
+                        )));
+                    }
+                };
+                if !helper::check_yaml(&response) {
                     log::error!("The downloaded subscription is not a legal profile.");
                     return Err(actix_web::Error::from(ClashError {
-                        Message: "The downloaded subscription is not a legal profile.".to_string(),
-                        ErrorKind: ClashErrorKind::ConfigFormatError,
+                        message: "The downloaded subscription is not a legal profile.".to_string(),
+                        kind: ClashErrorKind::ConfigFormatError,
                     }));
                 }
                 let s: String = rand::thread_rng()
@@ -285,20 +281,14 @@ pub async fn download_sub(
                     if let Err(e) = std::fs::create_dir_all(parent) {
                         log::error!("Failed while creating sub dir.");
                         log::error!("Error Message:{}", e);
-                        return Err(actix_web::Error::from(ClashError {
-                            Message: e.to_string(),
-                            ErrorKind: ClashErrorKind::InnerError,
-                        }));
+                        return Err(actix_web::Error::from(ClashError::from(e)));
                     }
                 }
                 let path = path.to_str().unwrap();
                 if let Err(e) = fs::write(path, response) {
                     log::error!("Failed while saving sub.");
                     log::error!("Error Message:{}", e);
-                    return Err(actix_web::Error::from(ClashError {
-                        Message: e.to_string(),
-                        ErrorKind: ClashErrorKind::InnerError,
-                    }));
+                    return Err(actix_web::Error::from(ClashError::from(e)));
                 }
                 //下载成功
                 //修改下载状态
@@ -312,10 +302,7 @@ pub async fn download_sub(
                             Ok(x) => x,
                             Err(e) => {
                                 log::error!("set_enable failed to acquire state write lock: {}", e);
-                                return Err(actix_web::Error::from(ClashError {
-                                    Message: e.to_string(),
-                                    ErrorKind: ClashErrorKind::InnerError,
-                                }));
+                                return Err(actix_web::Error::from(ClashError::from(e)));
                             }
                         };
                         state.dirty = true;
@@ -325,20 +312,17 @@ pub async fn download_sub(
                             "download_sub() faild to acquire runtime_setting write {}",
                             e
                         );
-                        return Err(actix_web::Error::from(ClashError {
-                            Message: e.to_string(),
-                            ErrorKind: ClashErrorKind::InnerError,
-                        }));
+                        return Err(actix_web::Error::from(ClashError::from(e)));
                     }
                 }
             }
             Err(e) => {
                 log::error!("Failed while downloading sub.");
                 log::error!("Error Message:{}", e);
-                return Err(actix_web::Error::from(ClashError {
-                    Message: e.to_string(),
-                    ErrorKind: ClashErrorKind::NetworkError,
-                }));
+                return Err(actix_web::Error::from(ClashError::from_request(
+                    &e,
+                    ClashErrorKind::NetworkError,
+                )));
             }
         };
     }
@@ -1,33 +1,104 @@
-use std::{path::Path, process::Command};
+use std::{
+    net::{SocketAddr, TcpStream},
+    path::{Path, PathBuf},
+    process::Command,
+    sync::mpsc,
+    thread,
+    time::Duration,
+};
 
 use regex::Regex;
+use serde::Serialize;
 
 use std::fs;
+use std::io::{Read, Seek, SeekFrom, Write};
 
 use sysinfo::{ProcessExt, System, SystemExt};
 
-pub fn set_system_network() -> Result<(), Box<dyn std::error::Error>> {
+use crate::settings::Settings;
+
+// 下载订阅与 Rule Provider 时最多跟随的重定向次数，重定向循环由 minreq 检测
+const MAX_REDIRECTS: usize = 5;
+// systemd-resolved 的配置片段，停止时删除即可恢复，不修改 /etc/systemd/resolved.conf
+const RESOLVED_DROP_IN_PATH: &str = "/etc/systemd/resolved.conf.d/tomoon.conf";
+
+/// `repair_dns` 没有备份可以恢复时写入的 resolv.conf
+pub const DEFAULT_RESOLV_CONF: &str =
+    "# Generated by ToMoon repair_dns\nnameserver 223.5.5.5\nnameserver 1.1.1.1\n";
+
+/// resolv.conf 是否只指向本机（Clash 停止后无法解析），systemd-resolved 的 127.0.0.53 除外
+pub fn resolv_conf_points_to_localhost(content: &str) -> bool {
+    let servers: Vec<&str> = content
+        .lines()
+        .filter_map(|x| x.trim().strip_prefix("nameserver"))
+        .map(|x| x.trim())
+        .collect();
+    !servers.is_empty()
+        && servers
+            .iter()
+            .all(|x| (x.starts_with("127.") && *x != "127.0.0.53") || *x == "::1")
+}
+
+/// 在 `timeout` 内能否通过系统的解析器解析 `host`（`域名:端口`）
+pub fn check_dns_resolution(host: &str, timeout: Duration) -> bool {
+    let (sender, receiver) = mpsc::channel();
+    let host = host.to_string();
+    thread::spawn(move || {
+        let resolved = std::net::ToSocketAddrs::to_socket_addrs(host.as_str())
+            .is_ok_and(|mut x| x.next().is_some());
+        let _ = sender.send(resolved);
+    });
+    receiver.recv_timeout(timeout).unwrap_or(false)
+}
+
+/// `/etc/resolv.conf` 的备份位置，使用绝对路径，不依赖工作目录
+pub fn resolv_conf_backup_path() -> PathBuf {
+    usdpl_back::api::dirs::home()
+        .unwrap_or_else(|| PathBuf::from("/home/deck"))
+        .join(".config/tomoon/resolv.conf.bk")
+}
+
+/// 备份 `/etc/resolv.conf`，已有备份时跳过，避免覆盖成 ToMoon 写入的内容
+pub fn backup_resolv_conf() -> std::io::Result<()> {
+    let backup = resolv_conf_backup_path();
+    if backup.exists() {
+        log::info!("resolv.conf backup already exists, skip backing up.");
+        return Ok(());
+    }
+    if let Some(parent) = backup.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::copy("/etc/resolv.conf", &backup)?;
+    log::info!("Backed up /etc/resolv.conf to {}", backup.display());
+    Ok(())
+}
+
+/// 把本机的 DNS 指向 Clash 的 `dns_listen`，开启 allow-lan 时也不会修改其他设备的网络
+pub fn set_system_network(dns_listen: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let dns_port = dns_listen.parse::<SocketAddr>()?.port();
     let network_manager_dns_path = Path::new("/etc/NetworkManager/conf.d/dns.conf");
     if !network_manager_dns_path.exists() {
         fs::File::create(network_manager_dns_path)?;
     }
     // https://github.com/YukiCoco/ToMoon/issues/7
     // 判断 systemd-resolve 是否启动
-    if is_resolve_running() {
-        // 对于新版本，使用 Issue 7 中的方案
-        let default_config = "[Resolve]\nDNS=127.0.0.1:5354\nFallbackDNS=114.114.114.114\n";
-        let resolved_dns_path = Path::new("/etc/systemd/resolved.conf");
-        if !resolved_dns_path.exists() {
-            fs::File::create(resolved_dns_path)?;
-        }
-        fs::write(resolved_dns_path, default_config)?;
+    if uses_systemd_resolved() {
+        // 对于新版本，使用 Issue 7 中的方案，通过配置片段修改，不覆盖 resolv.conf
+        let default_config = format!(
+            "[Resolve]\nDNS=127.0.0.1:{}\nFallbackDNS=114.114.114.114\nDomains=~.\n",
+            dns_port
+        );
+        let drop_in_path = Path::new(RESOLVED_DROP_IN_PATH);
+        if let Some(parent) = drop_in_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(drop_in_path, default_config)?;
         Command::new("systemctl")
             .arg("restart")
             .arg("systemd-resolved")
-            .spawn()
-            .unwrap()
-            .wait()
-            .unwrap();
+            .spawn()?
+            .wait()?;
+        flush_resolved_caches();
         //将启用 systemd-resolved 写入 Network Manager
         let default_config = "[main]\ndns=systemd-resolved\n";
         fs::write(network_manager_dns_path, default_config)?;
@@ -42,6 +113,14 @@ pub fn set_system_network() -> Result<(), Box<dyn std::error::Error>> {
             .unwrap();
     } else {
         // 旧版本的 SteamOS 没有 systemd-resolve
+        // resolv.conf 无法指定端口，Clash 必须监听 53
+        if dns_port != 53 {
+            return Err(format!(
+                "dns_listen {} must use port 53 without systemd-resolved",
+                dns_listen
+            )
+            .into());
+        }
         // 修改 DNS 为可写
         Command::new("chattr")
             .arg("-i")
@@ -50,8 +129,9 @@ pub fn set_system_network() -> Result<(), Box<dyn std::error::Error>> {
             .unwrap()
             .wait()
             .unwrap();
+        // 修改前先备份，停止时恢复
+        backup_resolv_conf()?;
         // 修改系统 DNS 指向本地
-        //fs::copy("/etc/resolv.conf", "./resolv.conf.bk")?;
         fs::write(
             "/etc/resolv.conf",
             "# Generated by ToMoon\nnameserver 127.0.0.1\n",
@@ -134,7 +214,42 @@ pub fn is_resolve_running() -> bool {
     false
 }
 
+/// `/etc/resolv.conf` 是否为 systemd-resolved 管理的链接
+pub fn is_resolv_conf_managed_by_resolved() -> bool {
+    match fs::read_link("/etc/resolv.conf") {
+        Ok(x) => {
+            x.starts_with("/run/systemd/resolve/") || x.starts_with("../run/systemd/resolve/")
+        }
+        Err(_) => false,
+    }
+}
+
+/// systemd-resolved 正在运行或管理着 resolv.conf 时通过 resolved 修改 DNS
+pub fn uses_systemd_resolved() -> bool {
+    is_resolve_running() || is_resolv_conf_managed_by_resolved()
+}
+
+fn flush_resolved_caches() {
+    match Command::new("resolvectl").arg("flush-caches").status() {
+        Ok(x) if x.success() => (),
+        Ok(x) => log::warn!("resolvectl flush-caches exited with {}", x),
+        Err(e) => log::warn!("Error occurred while running resolvectl: {}", e),
+    }
+}
+
 pub fn reset_system_network() -> Result<(), Box<dyn std::error::Error>> {
+    //删除 systemd-resolved 的配置片段
+    let drop_in_path = Path::new(RESOLVED_DROP_IN_PATH);
+    if drop_in_path.exists() {
+        fs::remove_file(drop_in_path)?;
+        Command::new("systemctl")
+            .arg("restart")
+            .arg("systemd-resolved")
+            .spawn()?
+            .wait()?;
+        flush_resolved_caches();
+        log::info!("Removed systemd-resolved drop-in {}", RESOLVED_DROP_IN_PATH);
+    }
     //读入程序的 DNS
     let default_config = "[main]\ndns=auto";
     fs::write("/etc/NetworkManager/conf.d/dns.conf", default_config)?;
@@ -142,20 +257,16 @@ pub fn reset_system_network() -> Result<(), Box<dyn std::error::Error>> {
     Command::new("chattr")
         .arg("-i")
         .arg("/etc/resolv.conf")
-        .spawn()
-        .unwrap()
-        .wait()
-        .unwrap();
+        .spawn()?
+        .wait()?;
     //fs::copy("./resolv.conf.bk", "/etc/resolv.conf")?;
 
     // 更新 NetworkManager
     Command::new("nmcli")
         .arg("general")
         .arg("reload")
-        .spawn()
-        .unwrap()
-        .wait()
-        .unwrap();
+        .spawn()?
+        .wait()?;
     // match fs::copy("./resolv.conf.bk", "/etc/resolv.conf") {
     //     Ok(_) => (),
     //     Err(e) => {
@@ -167,12 +278,121 @@ pub fn reset_system_network() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
-pub fn get_current_working_dir() -> std::io::Result<std::path::PathBuf> {
-    std::env::current_dir()
+/// 读取默认路由所在的网卡，同时存在多个默认路由时取 metric 最小的
+pub fn get_primary_interface() -> Option<String> {
+    let routes = fs::read_to_string("/proc/net/route").ok()?;
+    routes
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 8 || fields[1] != "00000000" || fields[7] != "00000000" {
+                return None;
+            }
+            let metric = fields[6].parse::<u32>().unwrap_or(u32::MAX);
+            Some((metric, fields[0].to_string()))
+        })
+        .min()
+        .map(|(_, name)| name)
+}
+
+static INSTALL_ROOT: std::sync::OnceLock<Option<PathBuf>> = std::sync::OnceLock::new();
+
+/// 插件的安装目录，即 `<root>/bin/tomoon` 中的 `<root>`，确定后不随工作目录变化
+/// 可执行文件不在 `bin` 下时（例如测试）使用第一次调用时的工作目录
+pub fn install_root() -> std::io::Result<PathBuf> {
+    INSTALL_ROOT
+        .get_or_init(|| {
+            let exe = std::env::current_exe().and_then(|x| x.canonicalize()).ok();
+            match exe.as_deref().and_then(|x| x.parent()) {
+                Some(bin) if bin.file_name() == Some("bin".as_ref()) => {
+                    bin.parent().map(|x| x.to_path_buf())
+                }
+                _ => std::env::current_dir().ok(),
+            }
+        })
+        .clone()
+        .ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::NotFound, "Failed to resolve the install root")
+        })
+}
+
+/// 解析 `地址/前缀长度` 格式的 CIDR，前缀长度不能超过地址的位数
+pub fn parse_cidr(cidr: &str) -> Result<(std::net::IpAddr, u8), String> {
+    let (address, prefix) = cidr
+        .trim()
+        .split_once('/')
+        .ok_or_else(|| format!("{} is missing the prefix length", cidr))?;
+    let address: std::net::IpAddr = address
+        .parse()
+        .map_err(|_| format!("{} is not a valid IP address", address))?;
+    let prefix: u8 = prefix
+        .parse()
+        .map_err(|_| format!("{} is not a valid prefix length", prefix))?;
+    let max_prefix = if address.is_ipv4() { 32 } else { 128 };
+    if prefix > max_prefix {
+        return Err(format!("prefix length {} is larger than {}", prefix, max_prefix));
+    }
+    Ok((address, prefix))
+}
+
+/// 深度合并 yaml：mapping 逐键合并，sequence 追加在后面，其他情况使用 `overlay` 替换
+pub fn merge_yaml(base: &mut serde_yaml::Value, overlay: serde_yaml::Value) {
+    match (base, overlay) {
+        (serde_yaml::Value::Mapping(base), serde_yaml::Value::Mapping(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(x) => merge_yaml(x, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (serde_yaml::Value::Sequence(base), serde_yaml::Value::Sequence(overlay)) => {
+            base.extend(overlay);
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+/// 部分订阅只有节点列表，包装为只有一个 `Proxy` 分组的配置，不是节点列表时返回 `None`
+pub fn wrap_proxy_list(value: &serde_yaml::Value) -> Option<serde_yaml::Value> {
+    let proxies = value.as_sequence()?;
+    let mut names = Vec::new();
+    for proxy in proxies {
+        match (proxy.get("name"), proxy.get("type")) {
+            (Some(serde_yaml::Value::String(name)), Some(serde_yaml::Value::String(_))) => {
+                names.push(serde_yaml::Value::String(name.clone()));
+            }
+            _ => return None,
+        }
+    }
+    if names.is_empty() {
+        return None;
+    }
+    let mut group = serde_yaml::Mapping::new();
+    group.insert("name".into(), "Proxy".into());
+    group.insert("type".into(), "select".into());
+    group.insert("proxies".into(), serde_yaml::Value::Sequence(names));
+    let mut config = serde_yaml::Mapping::new();
+    config.insert("proxies".into(), value.clone());
+    config.insert(
+        "proxy-groups".into(),
+        serde_yaml::Value::Sequence(vec![serde_yaml::Value::Mapping(group)]),
+    );
+    config.insert(
+        "rules".into(),
+        serde_yaml::Value::Sequence(vec!["MATCH,Proxy".into()]),
+    );
+    Some(serde_yaml::Value::Mapping(config))
 }
 
 pub fn check_yaml(str: &String) -> bool {
     if let Ok(x) = serde_yaml::from_str::<serde_yaml::Value>(str) {
+        if wrap_proxy_list(&x).is_some() {
+            return true;
+        }
         if let Some(v) = x.as_mapping() {
             if v.contains_key("rules") {
                 return true;
@@ -187,18 +407,116 @@ pub fn check_yaml(str: &String) -> bool {
     }
 }
 
+/// 检查订阅内容是否为可用的配置，返回其中节点的数量
+pub fn check_subscription(content: &str) -> Result<usize, String> {
+    let yaml = serde_yaml::from_str::<serde_yaml::Value>(content)
+        .map_err(|e| format!("The subscription is not a valid YAML file: {}", e))?;
+    let mapping = yaml
+        .as_mapping()
+        .ok_or_else(|| "The subscription is not a Clash profile.".to_string())?;
+    if !mapping.contains_key("rules") {
+        return Err("The subscription does not contain any rules.".to_string());
+    }
+    let proxies = mapping
+        .get("proxies")
+        .and_then(|x| x.as_sequence())
+        .map(|x| x.len())
+        .unwrap_or_default();
+    let providers = mapping
+        .get("proxy-providers")
+        .and_then(|x| x.as_mapping())
+        .map(|x| x.len())
+        .unwrap_or_default();
+    if proxies == 0 && providers == 0 {
+        return Err("The subscription does not contain any proxies.".to_string());
+    }
+    Ok(proxies)
+}
+
+#[derive(Debug, Serialize)]
+pub struct SubscriptionProbe {
+    pub valid: bool,
+    pub status_code: Option<i32>,
+    pub proxies: usize,
+    pub message: String,
+}
+
+/// 在保存订阅前先尝试获取并校验内容，不会写入任何文件
+pub fn probe_subscription(url: &str, options: &DownloadOptions) -> SubscriptionProbe {
+    let mut probe = SubscriptionProbe {
+        valid: false,
+        status_code: None,
+        proxies: 0,
+        message: String::new(),
+    };
+    let content = if let Some(local_file) = get_file_path(url.to_string()) {
+        match fs::read_to_string(&local_file) {
+            Ok(x) => x,
+            Err(e) => {
+                probe.message = format!("Cannt read file {}: {}", local_file, e);
+                return probe;
+            }
+        }
+    } else {
+        let response = match get_request(url, options).send() {
+            Ok(x) => x,
+            Err(e) => {
+                probe.message = e.to_string();
+                return probe;
+            }
+        };
+        probe.status_code = Some(response.status_code);
+        if let Err(e) = check_status(response.status_code, &response.reason_phrase) {
+            probe.message = e;
+            return probe;
+        }
+        match response_text(response) {
+            Ok(x) => x,
+            Err(e) => {
+                probe.message = e;
+                return probe;
+            }
+        }
+    };
+    match check_subscription(&content) {
+        Ok(proxies) => {
+            probe.valid = true;
+            probe.proxies = proxies;
+        }
+        Err(e) => {
+            probe.message = e;
+        }
+    }
+    probe
+}
+
+// Clash 核心的进程名，Meta 核心为 mihomo
+const CORE_PROCESS_NAMES: [&str; 2] = ["clash", "mihomo"];
+
 pub fn is_clash_running() -> bool {
     //关闭 systemd-resolved
     let mut sys = System::new_all();
     sys.refresh_all();
     for (_, process) in sys.processes() {
-        if process.name() == "clash" {
+        if CORE_PROCESS_NAMES.contains(&process.name()) {
             return true;
         }
     }
     return false;
 }
 
+/// 结束不是由当前后端启动的核心，例如后端崩溃后遗留的进程
+pub fn kill_stray_cores() {
+    let mut sys = System::new_all();
+    sys.refresh_all();
+    for (pid, process) in sys.processes() {
+        if CORE_PROCESS_NAMES.contains(&process.name()) {
+            log::warn!("Killing stray Clash core {} ({})", process.name(), pid);
+            process.kill();
+        }
+    }
+}
+
 pub fn is_samrtdns_running() -> bool {
     //关闭 systemd-resolved
     let mut sys = System::new_all();
@@ -219,3 +537,267 @@ pub fn get_file_path(url: String) -> Option<String> {
     };
     return None;
 }
+
+// Clash 支持的上游 DNS 协议
+const DNS_UPSTREAM_SCHEMES: [&str; 5] = ["udp://", "tcp://", "tls://", "https://", "quic://"];
+
+/// 检查上游 DNS 是否带有支持的协议前缀和主机名
+pub fn check_dns_upstream(upstream: &str) -> Result<(), String> {
+    let rest = DNS_UPSTREAM_SCHEMES
+        .iter()
+        .find_map(|x| upstream.strip_prefix(x))
+        .ok_or_else(|| {
+            format!(
+                "{} must start with one of {}",
+                upstream,
+                DNS_UPSTREAM_SCHEMES.join(", ")
+            )
+        })?;
+    let host = rest.split(['/', '#']).next().unwrap_or_default();
+    if host.is_empty() || host.starts_with(':') || upstream.contains(char::is_whitespace) {
+        return Err(format!("{} has no valid host", upstream));
+    }
+    Ok(())
+}
+
+/// 解析 Hysteria 节点的 `up` / `down`，例如 `100 Mbps`、`10 MBps`，纯数字按 Mbps 计算
+pub fn parse_bandwidth_kbps(value: &serde_yaml::Value) -> Option<u64> {
+    let text = match value {
+        serde_yaml::Value::Number(x) => return x.as_u64()?.checked_mul(1000),
+        serde_yaml::Value::String(x) => x.trim(),
+        _ => return None,
+    };
+    let split = text.find(|x: char| !x.is_ascii_digit()).unwrap_or(text.len());
+    let number = text[..split].parse::<u64>().ok()?;
+    let unit = text[split..].trim();
+    if unit.is_empty() {
+        return number.checked_mul(1000);
+    }
+    //大写 B 表示字节
+    let (prefix, bytes) = match unit.strip_suffix("Bps") {
+        Some(x) => (x, true),
+        None => (unit.strip_suffix("bps")?, false),
+    };
+    //订阅中的数值可能很大，溢出时视为无法解析
+    let kbps = match prefix {
+        "" => Some(number / 1000),
+        "k" | "K" => Some(number),
+        "m" | "M" => number.checked_mul(1000),
+        "g" | "G" => number.checked_mul(1000 * 1000),
+        "t" | "T" => number.checked_mul(1000 * 1000 * 1000),
+        _ => return None,
+    }?;
+    if bytes {
+        kbps.checked_mul(8)
+    } else {
+        Some(kbps)
+    }
+}
+
+// 启动前检查网络时连接的地址，任意一个可以连接即认为在线
+const PREFLIGHT_HOSTS: [&str; 2] = ["223.5.5.5:53", "1.1.1.1:53"];
+
+/// 同时连接 `PREFLIGHT_HOSTS`，在 `timeout` 内有一个连接成功时返回 true
+pub fn check_connectivity(timeout: Duration) -> bool {
+    let (sender, receiver) = mpsc::channel();
+    for host in PREFLIGHT_HOSTS {
+        let sender = sender.clone();
+        thread::spawn(move || {
+            let online = host
+                .parse::<SocketAddr>()
+                .is_ok_and(|x| TcpStream::connect_timeout(&x, timeout).is_ok());
+            let _ = sender.send(online);
+        });
+    }
+    drop(sender);
+    let deadline = std::time::Instant::now() + timeout;
+    while let Some(left) = deadline.checked_duration_since(std::time::Instant::now()) {
+        match receiver.recv_timeout(left) {
+            Ok(true) => return true,
+            Ok(false) => continue,
+            Err(_) => return false,
+        }
+    }
+    false
+}
+
+/// 只接受带有主机名的 http / https 链接，订阅中的 Provider 不能指向本地文件
+pub fn is_http_url(url: &str) -> bool {
+    let rest = match url.split_once("://") {
+        Some((scheme, rest))
+            if scheme.eq_ignore_ascii_case("http") || scheme.eq_ignore_ascii_case("https") =>
+        {
+            rest
+        }
+        _ => return false,
+    };
+    let host = rest.split(['/', '?', '#']).next().unwrap_or_default();
+    let host = host.rsplit('@').next().unwrap_or_default();
+    !host.is_empty() && !host.starts_with(':') && !host.contains(char::is_whitespace)
+}
+
+/// 先写入同一目录下的临时文件再重命名，避免进程中断时留下不完整的文件
+/// 临时文件必须与目标在同一文件系统，rename 才是原子的
+pub fn write_atomic(path: &Path, contents: impl AsRef<[u8]>) -> std::io::Result<()> {
+    let file_name = path.file_name().ok_or_else(|| {
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, "path has no file name")
+    })?;
+    let mut temp_name = std::ffi::OsString::from(".");
+    temp_name.push(file_name);
+    temp_name.push(".tmp");
+    let temp_path = path.with_file_name(temp_name);
+    let result = fs::File::create(&temp_path).and_then(|mut x| {
+        x.write_all(contents.as_ref())?;
+        x.sync_all()
+    });
+    if let Err(e) = result.and_then(|_| fs::rename(&temp_path, path)) {
+        let _ = fs::remove_file(&temp_path);
+        return Err(e);
+    }
+    Ok(())
+}
+
+/// 清空并以追加模式打开日志文件，`rotate_log` 截断后写入位置会回到开头
+pub fn create_append_file(path: &Path) -> std::io::Result<fs::File> {
+    fs::File::create(path)?;
+    fs::OpenOptions::new().append(true).open(path)
+}
+
+/// 文件超过 `max_bytes` 时复制到 `<path>.1` 并截断，返回是否发生了轮转
+/// 写入的进程继续使用原来的文件句柄，因此需要以追加模式打开
+pub fn rotate_log(path: &Path, max_bytes: u64) -> std::io::Result<bool> {
+    let size = match fs::metadata(path) {
+        Ok(x) => x.len(),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e),
+    };
+    if max_bytes == 0 || size <= max_bytes {
+        return Ok(false);
+    }
+    let mut rotated = path.as_os_str().to_owned();
+    rotated.push(".1");
+    fs::copy(path, PathBuf::from(rotated))?;
+    fs::OpenOptions::new().write(true).open(path)?.set_len(0)?;
+    Ok(true)
+}
+
+/// 读取文件最后 `lines` 行，最多只读取末尾 `max_bytes` 字节，文件不存在时返回空
+/// 只读打开，不影响正在写入的日志，末尾没有换行的一行可能还没写完，不返回
+pub fn tail_file(path: &Path, lines: usize, max_bytes: u64) -> std::io::Result<Vec<String>> {
+    let mut file = match fs::File::open(path) {
+        Ok(x) => x,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let len = file.metadata()?.len();
+    let start = len.saturating_sub(max_bytes);
+    file.seek(SeekFrom::Start(start))?;
+    let mut buf = Vec::new();
+    file.read_to_end(&mut buf)?;
+    let content = String::from_utf8_lossy(&buf);
+    let mut all: Vec<&str> = content.lines().collect();
+    // 从中间开始读取时第一行可能不完整
+    if start > 0 && !all.is_empty() {
+        all.remove(0);
+    }
+    if !content.ends_with('\n') {
+        all.pop();
+    }
+    let skip = all.len().saturating_sub(lines);
+    Ok(all[skip..].iter().map(|x| x.to_string()).collect())
+}
+
+/// 下载订阅与 Rule Provider 时的请求参数，来自 `Settings`
+#[derive(Debug, Clone)]
+pub struct DownloadOptions {
+    pub user_agent: String,
+    pub timeout: u64,
+    /// 通过正在运行的 Clash 下载时的 HTTP 代理，例如 `127.0.0.1:7890`
+    pub proxy: Option<String>,
+}
+
+impl DownloadOptions {
+    /// 不经过代理，需要代理时由 `ControlRuntime::download_options` 填写 `proxy`
+    pub fn from_settings(settings: &Settings) -> Self {
+        Self {
+            user_agent: settings.user_agent.clone(),
+            timeout: settings.download_timeout_secs,
+            proxy: None,
+        }
+    }
+}
+
+/// 下载订阅与 Rule Provider 使用的 GET 请求，会跟随有限次数的重定向
+pub fn get_request(url: &str, options: &DownloadOptions) -> minreq::Request {
+    let request = minreq::get(url)
+        .with_header("User-Agent", options.user_agent.as_str())
+        .with_timeout(options.timeout)
+        .with_max_redirects(MAX_REDIRECTS);
+    match options.proxy.as_deref().map(minreq::Proxy::new) {
+        Some(Ok(x)) => request.with_proxy(x),
+        Some(Err(e)) => {
+            log::warn!("Invalid download proxy, downloading directly: {}", e);
+            request
+        }
+        None => request,
+    }
+}
+
+/// 按 `Content-Encoding` 解压响应内容，没有或为 identity 时原样返回
+pub fn decode_body(body: Vec<u8>, encoding: Option<&str>) -> std::io::Result<Vec<u8>> {
+    let encoding = encoding.map(|x| x.trim().to_ascii_lowercase());
+    let mut output = Vec::new();
+    match encoding.as_deref() {
+        None | Some("") | Some("identity") => return Ok(body),
+        Some("gzip") | Some("x-gzip") => {
+            flate2::read::GzDecoder::new(body.as_slice()).read_to_end(&mut output)?;
+        }
+        Some("deflate") => {
+            //标准的 deflate 带有 zlib 头，部分服务器直接返回原始的 deflate 数据
+            if flate2::read::ZlibDecoder::new(body.as_slice())
+                .read_to_end(&mut output)
+                .is_err()
+            {
+                output.clear();
+                flate2::read::DeflateDecoder::new(body.as_slice()).read_to_end(&mut output)?;
+            }
+        }
+        Some(x) => {
+            log::warn!("Unsupported Content-Encoding {}, using the body as is.", x);
+            return Ok(body);
+        }
+    }
+    Ok(output)
+}
+
+/// 解压并按 UTF-8 读取响应内容
+pub fn response_text(response: minreq::Response) -> Result<String, String> {
+    let encoding = response.headers.get("content-encoding").cloned();
+    let body = decode_body(response.into_bytes(), encoding.as_deref())
+        .map_err(|e| format!("Failed to decompress response: {}", e))?;
+    String::from_utf8(body).map_err(|e| e.to_string())
+}
+
+/// 请求是否因为超时失败
+pub fn is_timeout(e: &minreq::Error) -> bool {
+    match e {
+        minreq::Error::IoError(x) => is_io_timeout(x),
+        _ => false,
+    }
+}
+
+pub fn is_io_timeout(e: &std::io::Error) -> bool {
+    matches!(
+        e.kind(),
+        std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock
+    )
+}
+
+/// 重定向后的最终状态码不是 2xx 时返回错误信息
+pub fn check_status(status_code: i32, reason_phrase: &str) -> Result<(), String> {
+    if (200..300).contains(&status_code) {
+        Ok(())
+    } else {
+        Err(format!("Server responded with {} {}", status_code, reason_phrase))
+    }
+}
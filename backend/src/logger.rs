@@ -0,0 +1,247 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::Write;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::{LevelFilter, Log, Metadata, Record};
+use serde::Serialize;
+use simplelog::{Config, SharedLogger};
+
+use crate::helper;
+use crate::settings::Settings;
+
+pub const LOG_BUFFER_CAPACITY: usize = 500;
+pub const DEFAULT_LOG_PATH: &str = "/tmp/tomoon.log";
+// 环境变量优先于设置中的 log_level / log_path / log_format
+const LOG_LEVEL_ENV: &str = "TOMOON_LOG_LEVEL";
+const LOG_PATH_ENV: &str = "TOMOON_LOG_PATH";
+const LOG_FORMAT_ENV: &str = "TOMOON_LOG_FORMAT";
+
+// 实际使用的日志文件，启动时确定
+static LOG_PATH: OnceLock<PathBuf> = OnceLock::new();
+
+/// 启动时决定日志级别与路径，此时 logger 还没有初始化，无法解析的级别会被忽略
+pub fn log_options(
+    settings: Option<&Settings>,
+    default_level: LevelFilter,
+) -> (LevelFilter, PathBuf) {
+    let level = std::env::var(LOG_LEVEL_ENV)
+        .ok()
+        .or_else(|| settings.and_then(|x| x.log_level.clone()))
+        .and_then(|x| LevelFilter::from_str(&x).ok())
+        .unwrap_or(default_level);
+    let path = std::env::var(LOG_PATH_ENV)
+        .ok()
+        .or_else(|| settings.and_then(|x| x.log_path.clone()))
+        .filter(|x| !x.is_empty())
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_LOG_PATH));
+    (level, path)
+}
+
+/// 日志文件的格式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// simplelog 的默认格式
+    Text,
+    /// 每行一个 json 编码的 `LogRecord`，方便外部工具读取
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err(()),
+        }
+    }
+}
+
+/// 启动时决定日志格式，无法解析时使用 text
+pub fn log_format(settings: Option<&Settings>) -> LogFormat {
+    std::env::var(LOG_FORMAT_ENV)
+        .ok()
+        .or_else(|| settings.and_then(|x| x.log_format.clone()))
+        .and_then(|x| LogFormat::from_str(&x).ok())
+        .unwrap_or(LogFormat::Text)
+}
+
+/// 创建日志文件，失败时使用默认路径
+pub fn open_log_file(path: PathBuf) -> File {
+    //追加模式，轮转时可以直接截断
+    let (path, file) = match helper::create_append_file(&path) {
+        Ok(x) => (path, x),
+        Err(e) => {
+            eprintln!("Failed to create log file {}: {}", path.display(), e);
+            let path = PathBuf::from(DEFAULT_LOG_PATH);
+            let file = helper::create_append_file(&path).unwrap();
+            (path, file)
+        }
+    };
+    let _ = LOG_PATH.set(path);
+    file
+}
+
+/// 当前使用的日志文件路径
+pub fn log_path() -> PathBuf {
+    LOG_PATH
+        .get()
+        .cloned()
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_LOG_PATH))
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LogRecord {
+    /// Unix 时间戳，毫秒
+    pub time: u128,
+    pub level: String,
+    pub target: String,
+    pub message: String,
+}
+
+impl LogRecord {
+    pub fn new(record: &Record) -> Self {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|x| x.as_millis())
+            .unwrap_or_default();
+        Self {
+            time,
+            level: record.level().to_string(),
+            target: record.target().to_string(),
+            message: record.args().to_string(),
+        }
+    }
+}
+
+/// 保存最近若干条后端日志，供前端直接读取
+#[derive(Debug)]
+pub struct LogBuffer {
+    capacity: usize,
+    records: VecDeque<LogRecord>,
+}
+
+impl LogBuffer {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            records: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    pub fn push(&mut self, record: LogRecord) {
+        if self.records.len() >= self.capacity {
+            self.records.pop_front();
+        }
+        self.records.push_back(record);
+    }
+
+    pub fn records(&self) -> Vec<LogRecord> {
+        self.records.iter().cloned().collect()
+    }
+}
+
+/// 与 `WriteLogger` 一起注册，把日志同时写入内存中的 `LogBuffer`
+pub struct BufferLogger {
+    level: LevelFilter,
+    buffer: Arc<RwLock<LogBuffer>>,
+}
+
+impl BufferLogger {
+    pub fn new(level: LevelFilter, buffer: Arc<RwLock<LogBuffer>>) -> Box<BufferLogger> {
+        Box::new(BufferLogger { level, buffer })
+    }
+}
+
+impl Log for BufferLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        // 这里不能再打印日志，否则会递归写入
+        if let Ok(mut x) = self.buffer.write() {
+            x.push(LogRecord::new(record));
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+impl SharedLogger for BufferLogger {
+    fn level(&self) -> LevelFilter {
+        self.level
+    }
+
+    fn config(&self) -> Option<&Config> {
+        None
+    }
+
+    fn as_log(self: Box<Self>) -> Box<dyn Log> {
+        Box::new(*self)
+    }
+}
+
+/// `log_format` 为 json 时代替 `WriteLogger`，每条日志写为一行 json
+pub struct JsonLogger {
+    level: LevelFilter,
+    file: Mutex<File>,
+}
+
+impl JsonLogger {
+    pub fn new(level: LevelFilter, file: File) -> Box<JsonLogger> {
+        Box::new(JsonLogger {
+            level,
+            file: Mutex::new(file),
+        })
+    }
+}
+
+impl Log for JsonLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        // 与 BufferLogger 相同，出错时不能再打印日志
+        let line = match serde_json::to_string(&LogRecord::new(record)) {
+            Ok(x) => x,
+            Err(_) => return,
+        };
+        if let Ok(mut file) = self.file.lock() {
+            let _ = writeln!(file, "{}", line);
+        }
+    }
+
+    fn flush(&self) {
+        if let Ok(mut file) = self.file.lock() {
+            let _ = file.flush();
+        }
+    }
+}
+
+impl SharedLogger for JsonLogger {
+    fn level(&self) -> LevelFilter {
+        self.level
+    }
+
+    fn config(&self) -> Option<&Config> {
+        None
+    }
+
+    fn as_log(self: Box<Self>) -> Box<dyn Log> {
+        Box::new(*self)
+    }
+}
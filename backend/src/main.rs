@@ -1,67 +1,188 @@
 mod api;
 mod control;
+mod controller;
 mod external_web;
 mod helper;
+mod logger;
+mod profile;
 mod settings;
+mod subscription;
 mod test;
 
-use std::{collections::HashMap, sync::Mutex, thread};
+use std::{
+    collections::HashMap,
+    net::TcpListener,
+    sync::{Arc, Mutex, RwLock},
+    thread,
+};
 
 use actix_cors::Cors;
 use actix_files as fs;
 use actix_web::{middleware, web, App, HttpServer};
-use simplelog::{LevelFilter, WriteLogger};
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+use simplelog::{CombinedLogger, LevelFilter, SharedLogger, WriteLogger};
 use usdpl_back::Instance;
 
 use crate::{
     control::{ControlRuntime, RunningStatus},
     external_web::Runtime,
+    logger::{BufferLogger, JsonLogger, LogBuffer, LogFormat},
 };
 
 const PORT: u16 = 55555;
 const WEB_PORT: u16 = 55556;
+// 覆盖 usdpl 端口的环境变量，前端的 USDPL_PORT 需要同步修改
+const PORT_ENV: &str = "TOMOON_USDPL_PORT";
+
+/// usdpl 使用的端口，环境变量无法解析时使用默认端口
+fn usdpl_port() -> u16 {
+    match std::env::var(PORT_ENV) {
+        Ok(x) => x.trim().parse().unwrap_or_else(|_| {
+            log::warn!("Invalid {} \"{}\", using port {}.", PORT_ENV, x, PORT);
+            PORT
+        }),
+        Err(_) => PORT,
+    }
+}
 
 #[actix_web::main]
 async fn main() -> Result<(), std::io::Error> {
-    WriteLogger::init(
+    let log_level = {
         #[cfg(debug_assertions)]
         {
             LevelFilter::Debug
-        },
+        }
         #[cfg(not(debug_assertions))]
         {
             LevelFilter::Info
-        },
-        Default::default(),
-        std::fs::File::create("/tmp/tomoon.log").unwrap(),
-    )
+        }
+    };
+    //logger 需要在 ControlRuntime 之前初始化，这里先单独读取设置
+    let early_settings =
+        settings::Settings::open(control::settings_path(settings::State::new().home)).ok();
+    let (log_level, log_path) = logger::log_options(early_settings.as_ref(), log_level);
+    let log_buffer = Arc::new(RwLock::new(LogBuffer::new(logger::LOG_BUFFER_CAPACITY)));
+    let log_file = logger::open_log_file(log_path);
+    let file_logger: Box<dyn SharedLogger> = match logger::log_format(early_settings.as_ref()) {
+        LogFormat::Text => WriteLogger::new(log_level, Default::default(), log_file),
+        LogFormat::Json => JsonLogger::new(log_level, log_file),
+    };
+    CombinedLogger::init(vec![
+        file_logger,
+        BufferLogger::new(log_level, log_buffer.clone()),
+    ])
     .unwrap();
 
     log::info!("Starting back-end ({} v{})", api::NAME, api::VERSION);
-    log::info!("{}", std::env::current_dir().unwrap().to_str().unwrap());
+    match helper::install_root() {
+        Ok(x) => log::info!("Install root: {}", x.display()),
+        Err(e) => log::error!("{}", e),
+    }
     println!("Starting back-end ({} v{})", api::NAME, api::VERSION);
 
-    let runtime: ControlRuntime = control::ControlRuntime::new();
+    //warp 绑定失败时只会 panic，在启动 Clash 之前先检查端口是否可用
+    let port = usdpl_port();
+    if let Err(e) = TcpListener::bind(("0.0.0.0", port)) {
+        log::error!("Failed to bind usdpl port {}: {}", port, e);
+        eprintln!("Failed to bind usdpl port {}: {}", port, e);
+        std::process::exit(1);
+    }
+    log::info!("usdpl listening on port {}", port);
+
+    let runtime: ControlRuntime = control::ControlRuntime::new(log_buffer);
     runtime.run();
 
+    // 被结束时复原 DNS，避免 resolv.conf 一直被锁定
+    let signal_runtime = runtime.clone();
+    match Signals::new([SIGTERM, SIGINT]) {
+        Ok(mut signals) => {
+            thread::spawn(move || {
+                if let Some(signal) = signals.forever().next() {
+                    log::info!("Received signal {}, shutting down.", signal);
+                    signal_runtime.shutdown();
+                    signal_runtime.flush();
+                    std::process::exit(0);
+                }
+            });
+        }
+        Err(e) => {
+            log::error!("Failed to register signal handler: {}", e);
+        }
+    }
+
     let runtime_pr = Runtime(&runtime as *const ControlRuntime);
 
     thread::spawn(move || {
-        Instance::new(PORT)
+        Instance::new(port)
             .register("set_clash_status", api::set_clash_status(&runtime))
             .register("get_clash_status", api::get_clash_status(&runtime))
+            .register("get_clash_health", api::get_clash_health(&runtime))
+            .register("get_dashboard_url", api::get_dashboard_url(&runtime))
+            .register("get_core_version", api::get_core_version(&runtime))
+            .register("get_active_config_info", api::get_active_config_info(&runtime))
+            .register("get_rules", api::get_rules(&runtime))
+            .register("get_settings", api::get_settings(&runtime))
+            .register("get_settings_schema", api::get_settings_schema())
+            .register("update_settings", api::update_settings(&runtime))
+            .register("start_clash_verbose", api::start_clash_verbose(&runtime))
+            .register("get_start_progress", api::get_start_progress(&runtime))
+            .register("reload_clash_config", api::reload_clash_config(&runtime))
+            .register("restart_clash", api::restart_clash(&runtime))
+            .register("set_proxy_mode", api::set_proxy_mode(&runtime))
+            .register("get_proxies", api::get_proxies(&runtime))
+            .register("select_proxy", api::select_proxy(&runtime))
+            .register("get_traffic", api::get_traffic(&runtime))
+            .register("get_connections", api::get_connections(&runtime))
+            .register("close_connection", api::close_connection(&runtime))
+            .register("test_proxy_delay", api::test_proxy_delay(&runtime))
             .register("reset_network", api::reset_network())
+            .register("repair_dns", api::repair_dns(&runtime))
+            .register("get_runtime_info", api::get_runtime_info(&runtime))
             .register("download_sub", api::download_sub(&runtime))
-            .register("get_download_status", api::get_download_status(&runtime))
+            .register("probe_sub", api::probe_sub(&runtime))
+            .register(
+                "get_download_status",
+                api::get_subscription_download_status(&runtime),
+            )
+            .register(
+                "get_subscription_download_status",
+                api::get_subscription_download_status(&runtime),
+            )
+            .register("get_download_progress", api::get_download_progress(&runtime))
             .register("get_sub_list", api::get_sub_list(&runtime))
+            .register("add_subscription", api::add_subscription(&runtime))
+            .register("add_config_from_text", api::add_config_from_text(&runtime))
+            .register("list_subscriptions", api::list_subscriptions(&runtime))
+            .register("delete_subscription", api::delete_subscription(&runtime))
+            .register("set_subscription_interval", api::set_subscription_interval(&runtime))
+            .register("reset_all", api::reset_all(&runtime))
+            .register("export_profile", api::export_profile(&runtime))
+            .register("import_profile", api::import_profile(&runtime))
             .register("delete_sub", api::delete_sub(&runtime))
             .register("set_sub", api::set_sub(&runtime))
             .register("update_subs", api::update_subs(&runtime))
             .register("get_update_status", api::get_update_status(&runtime))
-            .register("create_debug_log", api::create_debug_log())
+            .register("get_core_update_status", api::get_core_update_status(&runtime))
+            .register("update_clash_core", api::update_clash_core(&runtime))
+            .register("update_geo_database", api::update_geo_database(&runtime))
+            .register("get_geo_update_status", api::get_geo_update_status(&runtime))
+            .register("create_debug_log", api::create_debug_log(&runtime))
+            .register("get_backend_log", api::get_backend_log(&runtime))
+            .register("get_clash_logs", api::get_clash_logs())
+            .register("get_backend_logs", api::get_backend_logs())
             .register("get_running_status", api::get_running_status(&runtime))
+            .register("get_providers_status", api::get_providers_status(&runtime))
+            .register("get_active_interface", api::get_active_interface(&runtime))
+            .register("get_mixed_port", api::get_mixed_port(&runtime))
+            .register("preview_config", api::preview_config(&runtime))
             .run_blocking()
-            .unwrap();
+            .unwrap_or_else(|_| {
+                log::error!("usdpl instance on port {} stopped unexpectedly", port);
+                runtime.shutdown();
+                runtime.flush();
+                std::process::exit(1);
+            });
     });
 
     let app_state = web::Data::new(external_web::AppState {
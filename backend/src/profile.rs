@@ -0,0 +1,247 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::control::{ClashError, ClashErrorKind};
+use crate::helper;
+use crate::settings::{Settings, Subscription};
+use crate::subscription;
+
+const SETTINGS_ENTRY: &str = "tomoon.json";
+const SUBS_ENTRY: &str = "subs";
+
+/// 导入时与现有设置的处理方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImportMode {
+    /// 保留现有设置，只添加新的订阅
+    Merge,
+    /// 使用导入的设置和订阅替换现有的
+    Replace,
+}
+
+fn inner_error(e: impl ToString) -> ClashError {
+    ClashError {
+        message: e.to_string(),
+        kind: ClashErrorKind::InnerError,
+    }
+}
+
+fn format_error(message: String) -> ClashError {
+    ClashError {
+        message,
+        kind: ClashErrorKind::ConfigFormatError,
+    }
+}
+
+/// 压缩包中只允许 `tomoon.json` 和 `subs/` 下的文件，防止解压到配置目录以外
+pub fn is_safe_entry(name: &str) -> bool {
+    let name = name.strip_prefix("./").unwrap_or(name);
+    let name = name.strip_suffix('/').unwrap_or(name);
+    if name.is_empty() || name == SETTINGS_ENTRY || name == SUBS_ENTRY {
+        return true;
+    }
+    match name.strip_prefix("subs/") {
+        Some(file) => !file.is_empty() && !file.contains('/') && file != "." && file != "..",
+        None => false,
+    }
+}
+
+fn staging_dir(kind: &str) -> Result<PathBuf, ClashError> {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_nanos())
+        .unwrap_or_default();
+    let dir = std::env::temp_dir().join(format!("tomoon-{}-{}", kind, timestamp));
+    fs::create_dir_all(dir.join(SUBS_ENTRY)).map_err(inner_error)?;
+    Ok(dir)
+}
+
+fn run_tar(args: &[&std::ffi::OsStr]) -> Result<Vec<u8>, ClashError> {
+    let output = Command::new("tar").args(args).output().map_err(inner_error)?;
+    if !output.status.success() {
+        return Err(format_error(format!(
+            "tar exited with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(output.stdout)
+}
+
+fn file_name_of(path: &str) -> Option<String> {
+    Path::new(path)
+        .file_name()
+        .map(|x| x.to_string_lossy().to_string())
+}
+
+/// 把设置和订阅文件打包为 `home` 下的 tar.gz，返回压缩包路径
+pub fn export_profile(settings: &Settings, home: &Path) -> Result<PathBuf, ClashError> {
+    let staging = staging_dir("export")?;
+    let result = write_archive(settings, home, &staging);
+    if let Err(e) = fs::remove_dir_all(&staging) {
+        log::warn!("Error occurred while removing {}: {}", staging.display(), e);
+    }
+    result
+}
+
+fn write_archive(settings: &Settings, home: &Path, staging: &Path) -> Result<PathBuf, ClashError> {
+    let content = serde_json::to_string_pretty(settings).map_err(inner_error)?;
+    fs::write(staging.join(SETTINGS_ENTRY), content).map_err(inner_error)?;
+    for sub in &settings.subscriptions {
+        let path = Path::new(&sub.path);
+        match file_name_of(&sub.path) {
+            Some(name) if path.is_file() => {
+                fs::copy(path, staging.join(SUBS_ENTRY).join(name)).map_err(inner_error)?;
+            }
+            _ => log::warn!("Subscription file {} not found, skip exporting.", sub.path),
+        }
+    }
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs())
+        .unwrap_or_default();
+    let archive = home.join(format!("tomoon-profile-{}.tar.gz", timestamp));
+    run_tar(&[
+        "-czf".as_ref(),
+        archive.as_os_str(),
+        "-C".as_ref(),
+        staging.as_os_str(),
+        SETTINGS_ENTRY.as_ref(),
+        SUBS_ENTRY.as_ref(),
+    ])?;
+    log::info!("Exported profile to {}", archive.display());
+    Ok(archive)
+}
+
+/// 检查并解压 `export_profile` 生成的压缩包，订阅文件写入 `home` 下的订阅目录
+/// 返回需要使用的新设置，由调用方决定保留哪些运行时字段
+pub fn import_profile(
+    archive: &Path,
+    home: &Path,
+    current: &Settings,
+    mode: ImportMode,
+) -> Result<Settings, ClashError> {
+    if !archive.is_file() {
+        return Err(ClashError {
+            message: format!("Profile archive {} not found", archive.display()),
+            kind: ClashErrorKind::ConfigNotFound,
+        });
+    }
+    let list = run_tar(&["-tzf".as_ref(), archive.as_os_str()])?;
+    let list = String::from_utf8_lossy(&list);
+    if let Some(x) = list.lines().find(|x| !is_safe_entry(x)) {
+        return Err(format_error(format!("Unexpected entry in profile archive: {}", x)));
+    }
+    let staging = staging_dir("import")?;
+    let result = run_tar(&[
+        "-xzf".as_ref(),
+        archive.as_os_str(),
+        "-C".as_ref(),
+        staging.as_os_str(),
+        "--no-same-owner".as_ref(),
+    ])
+    .and_then(|_| apply_archive(&staging, home, current, mode));
+    if let Err(e) = fs::remove_dir_all(&staging) {
+        log::warn!("Error occurred while removing {}: {}", staging.display(), e);
+    }
+    result
+}
+
+fn apply_archive(
+    staging: &Path,
+    home: &Path,
+    current: &Settings,
+    mode: ImportMode,
+) -> Result<Settings, ClashError> {
+    //只接受普通文件，链接可能指向配置目录以外
+    let mut entries = vec![staging.join(SETTINGS_ENTRY)];
+    for entry in fs::read_dir(staging.join(SUBS_ENTRY)).map_err(inner_error)? {
+        entries.push(entry.map_err(inner_error)?.path());
+    }
+    for entry in &entries {
+        match fs::symlink_metadata(entry) {
+            Ok(x) if x.is_file() => {}
+            _ => {
+                return Err(format_error(format!(
+                    "{} in profile archive is not a regular file",
+                    entry.display()
+                )))
+            }
+        }
+    }
+
+    let imported = Settings::open(staging.join(SETTINGS_ENTRY))
+        .map_err(|e| format_error(format!("Invalid {}: {}", SETTINGS_ENTRY, e)))?;
+    imported.validate().map_err(format_error)?;
+
+    let subs_dir = subscription::subs_dir(home);
+    // (订阅, 临时文件)，路径已改写到本机的订阅目录
+    let mut subs: Vec<(Subscription, PathBuf)> = Vec::new();
+    for sub in &imported.subscriptions {
+        let name = match file_name_of(&sub.path) {
+            Some(x) => x,
+            None => continue,
+        };
+        let source = staging.join(SUBS_ENTRY).join(&name);
+        let content = match fs::read_to_string(&source) {
+            Ok(x) => x,
+            Err(_) => {
+                log::warn!("Subscription {} not found in profile archive, skip.", name);
+                continue;
+            }
+        };
+        if !helper::check_yaml(&content) {
+            return Err(format_error(format!("Subscription {} is not a legal profile", name)));
+        }
+        let path = subs_dir.join(&name).to_string_lossy().to_string();
+        //直接导入的配置指向原来的文件
+        let url = if sub.url == format!("file://{}", sub.path) {
+            format!("file://{}", path)
+        } else {
+            sub.url.clone()
+        };
+        subs.push((Subscription::new(path, url), source));
+    }
+
+    let mut settings = match mode {
+        ImportMode::Replace => {
+            let current_sub = file_name_of(&imported.current_sub);
+            let mut settings = imported;
+            settings.current_sub = subs
+                .iter()
+                .map(|x| &x.0.path)
+                .find(|x| current_sub.is_some() && file_name_of(x) == current_sub)
+                .cloned()
+                .unwrap_or_default();
+            settings.subscriptions = Vec::new();
+            settings
+        }
+        ImportMode::Merge => {
+            //链接或文件已存在的订阅不覆盖
+            subs.retain(|(sub, _)| {
+                !current
+                    .subscriptions
+                    .iter()
+                    .any(|x| x.url == sub.url || x.path == sub.path)
+            });
+            current.clone()
+        }
+    };
+
+    fs::create_dir_all(&subs_dir).map_err(inner_error)?;
+    for (sub, source) in subs {
+        fs::copy(&source, &sub.path).map_err(inner_error)?;
+        settings.subscriptions.push(sub);
+    }
+    if mode == ImportMode::Replace {
+        for sub in &current.subscriptions {
+            if !settings.subscriptions.iter().any(|x| x.path == sub.path) {
+                if let Err(e) = fs::remove_file(&sub.path) {
+                    log::warn!("Error occurred while removing {}: {}", sub.path, e);
+                }
+            }
+        }
+    }
+    Ok(settings)
+}
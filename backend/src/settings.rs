@@ -1,20 +1,238 @@
 use serde::{Deserialize, Serialize};
-use std::{path::PathBuf, fmt::Display};
+use std::{path::PathBuf, fmt::Display, str::FromStr};
 
 use crate::helper;
+use crate::logger::LogFormat;
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Settings {
     #[serde(default = "default_enable")]
     pub enable: bool,
-    #[serde(default = "default_tun_mode")]
-    pub tun_mode: bool,
+    /// 是否开启 TUN，旧版本的设置文件中名为 `tun_mode`
+    #[serde(default = "default_tun_enabled", alias = "tun_mode")]
+    pub tun_enabled: bool,
     #[serde(default = "default_skip_proxy")]
     pub skip_proxy: bool,
+    /// 局域网地址直连，避免开启 TUN 后无法访问路由器和局域网设备
+    #[serde(default = "default_bypass_lan")]
+    pub bypass_lan: bool,
+    /// 热重载时保留现有连接，只有 Meta 核心支持，其他核心使用默认行为
+    #[serde(default = "default_keep_connections_on_reload")]
+    pub keep_connections_on_reload: bool,
+    /// 使用插件自带的面板，关闭时保留订阅中的 `external-ui`
+    #[serde(default = "default_use_bundled_dashboard")]
+    pub use_bundled_dashboard: bool,
+    /// 启动 Clash 前检查网络是否可用，离线时提示用户
+    #[serde(default = "default_preflight_check")]
+    pub preflight_check: bool,
     #[serde(default = "default_current_sub")]
     pub current_sub: String,
     #[serde(default = "default_subscriptions")]
     pub subscriptions: Vec<Subscription>,
+    /// 上次成功启动 Clash 时使用的配置文件
+    #[serde(default = "default_current_config")]
+    pub current_config: PathBuf,
+    /// 后台检查 Rule Provider 是否过期的间隔（秒），0 表示关闭
+    #[serde(default = "default_provider_check_interval")]
+    pub provider_check_interval: u64,
+    /// 订阅自动更新的默认间隔（秒），0 表示关闭，可以被单个订阅的设置覆盖
+    #[serde(default = "default_subscription_update_interval")]
+    pub subscription_update_interval: u64,
+    /// 写入 `unified-delay: true`，仅 Clash.Meta 支持，默认关闭
+    #[serde(default)]
+    pub unified_delay: bool,
+    /// 写入 `tcp-concurrent: true`，仅 Clash.Meta 支持，默认关闭
+    #[serde(default)]
+    pub tcp_concurrent: bool,
+    /// Clash external-controller 监听地址
+    #[serde(default = "default_external_controller")]
+    pub external_controller: String,
+    /// external-controller 的密码，为空时不写入配置
+    #[serde(default)]
+    pub secret: String,
+    /// 如何处理订阅中的 dns 配置
+    #[serde(default)]
+    pub dns_override: DnsOverride,
+    /// 总是直连的域名，按顺序插入到 rules 最前面
+    #[serde(default = "default_direct_domains")]
+    pub direct_domains: Vec<String>,
+    /// 下载 Rule Provider 失败时最多尝试的次数
+    #[serde(default = "default_provider_download_attempts")]
+    pub provider_download_attempts: u32,
+    /// 写入配置的 `mode`，运行中也可以通过 external-controller 切换
+    #[serde(default)]
+    pub proxy_mode: ProxyMode,
+    /// Clash DNS 监听地址，systemd-resolved 运行时默认值会改为 5354 端口
+    #[serde(default = "default_dns_listen")]
+    pub dns_listen: String,
+    #[serde(default = "default_dns_enhanced_mode")]
+    pub dns_enhanced_mode: String,
+    #[serde(default = "default_fake_ip_range")]
+    pub fake_ip_range: String,
+    /// 开启 IPv6，同时写入 DNS 与 TUN 的 IPv6 配置，默认关闭
+    #[serde(default)]
+    pub ipv6: bool,
+    /// 开启 IPv6 时使用的 fake-ip 地址段
+    #[serde(default = "default_fake_ip_range6")]
+    pub fake_ip_range6: String,
+    /// 上游 DNS，默认为 SmartDNS，需要带协议前缀，例如 `https://dns.google/dns-query`
+    #[serde(default = "default_dns_nameserver")]
+    pub dns_nameserver: Vec<String>,
+    /// 不经过 TUN 的网卡，例如其他 VPN 的 `tun0`，只有 Meta 核心支持
+    #[serde(default)]
+    pub excluded_interfaces: Vec<String>,
+    /// 核心在运行中意外退出时自动重启，连续多次退出后不再重启
+    #[serde(default)]
+    pub restart_on_crash: bool,
+    /// 是否修改系统 DNS（resolv.conf、systemd-resolved 与 NetworkManager），默认开启
+    /// 关闭后 Clash 仍然使用自己的 DNS 配置，本机的解析器保持不变，
+    /// 只有通过 TUN 或代理端口的流量会经过 Clash 的 DNS
+    #[serde(default = "default_manage_system_dns")]
+    pub manage_system_dns: bool,
+    /// 下载订阅与 Rule Provider 时使用的 User-Agent，部分订阅会据此返回 Clash 格式
+    #[serde(default = "default_user_agent")]
+    pub user_agent: String,
+    /// Clash 正在运行时通过它的 mixed-port / port 下载订阅与 Rule Provider，未运行时直接下载
+    #[serde(default)]
+    pub download_via_proxy: bool,
+    /// 下载订阅与 Rule Provider 的超时时间（秒）
+    #[serde(default = "default_download_timeout_secs")]
+    pub download_timeout_secs: u64,
+    /// 允许局域网内的其他设备通过本机代理，默认关闭
+    /// 只影响 Clash 的监听，`set_system_network` 仍然只修改本机的网络设置
+    #[serde(default)]
+    pub allow_lan: bool,
+    /// 设置后写入 `mixed-port`，供需要显式 HTTP/SOCKS 代理的应用使用，可与 TUN 同时开启
+    #[serde(default)]
+    pub mixed_port: Option<u16>,
+    /// 下载与上传的限速（Kbps），为空时不限速
+    /// 只有 Meta 核心支持，写入 Hysteria / Hysteria2 节点的 `down` / `up`，
+    /// 其余类型的节点和 DIRECT 连接不受限制，TUN 开启与否都一样
+    #[serde(default)]
+    pub max_download_kbps: Option<u32>,
+    #[serde(default)]
+    pub max_upload_kbps: Option<u32>,
+    /// 合并到每个运行配置中的 yaml 片段，在 ToMoon 的修改之后深度合并
+    #[serde(default)]
+    pub config_override: Option<String>,
+    /// GeoIP / GeoSite 数据库的下载地址，可以改为较近的镜像
+    #[serde(default = "default_geo_databases")]
+    pub geo_databases: Vec<GeoDatabase>,
+    /// 后端日志级别（error / warn / info / debug / trace），为空时按编译类型选择
+    #[serde(default)]
+    pub log_level: Option<String>,
+    /// 后端日志路径，为空时使用 `/tmp/tomoon.log`，修改后重启插件生效
+    #[serde(default)]
+    pub log_path: Option<String>,
+    /// 后端日志文件的格式（text / json），json 时每行一条记录，为空时使用 text，修改后重启插件生效
+    #[serde(default)]
+    pub log_format: Option<String>,
+    /// 后端与 Clash 日志超过该大小（字节）时轮转到 `.1`，为 0 时不轮转
+    #[serde(default = "default_log_rotate_bytes")]
+    pub log_rotate_bytes: u64,
+    /// 使用的核心类型，决定核心路径、TUN 协议栈和额外写入的配置
+    #[serde(default)]
+    pub core_type: CoreType,
+    /// TUN 协议栈，为空时使用核心类型的默认值
+    #[serde(default)]
+    pub tun_stack: Option<TunStack>,
+    /// 开机后自动使用 `current_config` 启动 Clash
+    #[serde(default)]
+    pub auto_start: bool,
+    /// 连续自动启动失败的次数，手动启动成功后清零
+    #[serde(default)]
+    pub auto_start_failures: u32,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum CoreType {
+    /// `bin/core/clash`，保持原有的配置
+    #[default]
+    ClashPremium,
+    /// `bin/core/mihomo`，使用 gvisor 协议栈并开启 sniffer
+    Meta,
+}
+
+impl CoreType {
+    /// 相对于插件目录的核心路径
+    pub fn core_path(&self) -> &'static str {
+        match self {
+            Self::ClashPremium => "bin/core/clash",
+            Self::Meta => "bin/core/mihomo",
+        }
+    }
+
+    pub fn tun_stack(&self) -> &'static str {
+        match self {
+            Self::ClashPremium => "system",
+            Self::Meta => "gvisor",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TunStack {
+    System,
+    Gvisor,
+    /// TCP 使用 system，UDP 使用 gvisor，只有 Clash.Meta 支持
+    Mixed,
+}
+
+impl TunStack {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::System => "system",
+            Self::Gvisor => "gvisor",
+            Self::Mixed => "mixed",
+        }
+    }
+
+    pub fn supported_by(&self, meta_core: bool) -> bool {
+        meta_core || *self != Self::Mixed
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum DnsOverride {
+    /// 使用 ToMoon 的 DNS 配置替换订阅中的配置
+    #[default]
+    Force,
+    /// 保留订阅的 nameserver 等配置，只确保 enable、enhanced-mode 和 listen 正确
+    Merge,
+    /// 完全不修改订阅中的 dns 配置
+    Keep,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ProxyMode {
+    #[default]
+    Rule,
+    Global,
+    Direct,
+}
+
+impl ProxyMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Rule => "rule",
+            Self::Global => "global",
+            Self::Direct => "direct",
+        }
+    }
+}
+
+impl FromStr for ProxyMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "rule" => Ok(Self::Rule),
+            "global" => Ok(Self::Global),
+            "direct" => Ok(Self::Direct),
+            _ => Err(format!("unknown proxy mode: {}", s)),
+        }
+    }
 }
 
 fn default_skip_proxy() -> bool {
@@ -25,23 +243,130 @@ fn default_enable() -> bool {
     false
 }
 
-fn default_tun_mode() -> bool {
+fn default_tun_enabled() -> bool {
     true
 }
 
-fn default_current_sub() -> String {
-    let default_profile = helper::get_current_working_dir().unwrap().join("bin/core/config.yaml");
+pub fn default_current_sub() -> String {
+    let default_profile = helper::install_root().unwrap_or_default().join("bin/core/config.yaml");
     default_profile.to_string_lossy().to_string()
 }
 
+fn default_current_config() -> PathBuf {
+    helper::install_root().unwrap_or_default().join("bin/core/config.yaml")
+}
+
 fn default_subscriptions() -> Vec<Subscription> {
     Vec::new()
 }
 
+fn default_bypass_lan() -> bool {
+    true
+}
+
+fn default_keep_connections_on_reload() -> bool {
+    true
+}
+
+fn default_preflight_check() -> bool {
+    true
+}
+
+fn default_manage_system_dns() -> bool {
+    true
+}
+
+fn default_use_bundled_dashboard() -> bool {
+    true
+}
+
+fn default_provider_check_interval() -> u64 {
+    3600
+}
+
+fn default_subscription_update_interval() -> u64 {
+    86400
+}
+
+fn default_external_controller() -> String {
+    String::from("127.0.0.1:9090")
+}
+
+fn default_download_timeout_secs() -> u64 {
+    15
+}
+
+pub fn default_user_agent() -> String {
+    String::from("clash-verge/tomoon")
+}
+
+pub fn default_dns_listen() -> String {
+    String::from("0.0.0.0:53")
+}
+
+fn default_dns_enhanced_mode() -> String {
+    String::from("fake-ip")
+}
+
+fn default_fake_ip_range() -> String {
+    String::from("198.18.0.1/16")
+}
+
+fn default_geo_databases() -> Vec<GeoDatabase> {
+    let base = "https://github.com/MetaCubeX/meta-rules-dat/releases/download/latest";
+    [
+        ("Country.mmdb", "country.mmdb"),
+        ("geoip.dat", "geoip.dat"),
+        ("geosite.dat", "geosite.dat"),
+    ]
+    .iter()
+    .map(|(file, name)| GeoDatabase {
+        file: file.to_string(),
+        url: format!("{}/{}", base, name),
+        sha256: None,
+    })
+    .collect()
+}
+
+fn default_log_rotate_bytes() -> u64 {
+    5 * 1024 * 1024
+}
+
+fn default_fake_ip_range6() -> String {
+    String::from("fdfe:dcba:9876::1/64")
+}
+
+fn default_dns_nameserver() -> Vec<String> {
+    vec![String::from("tcp://127.0.0.1:5353")]
+}
+
+fn default_provider_download_attempts() -> u32 {
+    3
+}
+
+fn default_direct_domains() -> Vec<String> {
+    //这个域名用于 Steam Deck 网络连接验证，可以直连
+    vec![String::from("test.steampowered.com")]
+}
+
+/// 下载到 `bin/core/` 下的数据库文件
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct GeoDatabase {
+    /// 保存的文件名，例如 `Country.mmdb`
+    pub file: String,
+    pub url: String,
+    /// 不为空时校验下载内容
+    #[serde(default)]
+    pub sha256: Option<String>,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Subscription {
     pub path : String,
-    pub url : String
+    pub url : String,
+    /// 自动更新间隔（秒），为空时使用 `Settings.subscription_update_interval`，0 表示不更新
+    #[serde(default)]
+    pub update_interval: Option<u64>,
 }
 
 #[derive(Debug)]
@@ -62,7 +387,7 @@ impl Display for JsonError {
 impl Subscription {
     pub fn new(path: String, url: String) -> Self
     {
-        Self { path: path, url: url }
+        Self { path: path, url: url, update_interval: None }
     }
 }
 
@@ -71,6 +396,8 @@ impl Subscription {
 pub struct State {
     pub home: PathBuf,
     pub dirty: bool,
+    /// 上次写入设置文件的内容，没有变化时跳过写入
+    pub saved: Option<String>,
 }
 
 impl State {
@@ -80,11 +407,13 @@ impl State {
             return Self {
             home: "./tmp".into(),
             dirty: true,
+            saved: None,
         }
         }
         Self {
             home: usdpl_back::api::dirs::home().unwrap_or(def.home),
             dirty: true,
+            saved: None,
         }
     }
 }
@@ -94,36 +423,306 @@ impl Default for State {
         Self {
             home: "/home/deck".into(),
             dirty: true,
+            saved: None,
         }
     }
 }
 
 
 impl Settings {
-    pub fn save<P: AsRef<std::path::Path>>(&self, path: P) -> Result<(), JsonError> {
+    /// 检查前端提交的设置，返回第一个无效的字段
+    pub fn validate(&self) -> Result<(), String> {
+        helper::parse_cidr(&self.fake_ip_range).map_err(|e| format!("fake_ip_range: {}", e))?;
+        helper::parse_cidr(&self.fake_ip_range6).map_err(|e| format!("fake_ip_range6: {}", e))?;
+        let dns_listen = self
+            .dns_listen
+            .parse::<std::net::SocketAddr>()
+            .map_err(|e| format!("dns_listen: {}", e))?;
+        // 没有 systemd-resolved 时写入 resolv.conf，无法指定端口
+        if self.manage_system_dns && dns_listen.port() != 53 && !helper::uses_systemd_resolved() {
+            return Err(format!(
+                "dns_listen: {} must use port 53 when systemd-resolved is not running",
+                self.dns_listen
+            ));
+        }
+        match self.external_controller.rsplit_once(':') {
+            Some((_, port)) if port.parse::<u16>().is_ok() => {}
+            _ => {
+                return Err(format!(
+                    "external_controller: {} has no port",
+                    self.external_controller
+                ));
+            }
+        }
+        if self.provider_download_attempts == 0 {
+            return Err("provider_download_attempts must be at least 1".to_string());
+        }
+        if self.download_timeout_secs == 0 {
+            return Err("download_timeout_secs must be at least 1".to_string());
+        }
+        if self.max_download_kbps == Some(0) || self.max_upload_kbps == Some(0) {
+            return Err("bandwidth limit must be at least 1 Kbps".to_string());
+        }
+        if self.direct_domains.iter().any(|x| x.trim().is_empty() || x.contains(',')) {
+            return Err("direct_domains contains an invalid domain".to_string());
+        }
+        if self
+            .excluded_interfaces
+            .iter()
+            .any(|x| x.trim().is_empty() || x.contains(char::is_whitespace))
+        {
+            return Err("excluded_interfaces contains an invalid interface name".to_string());
+        }
+        if self.dns_nameserver.is_empty() {
+            return Err("dns_nameserver: at least one upstream is required".to_string());
+        }
+        for upstream in &self.dns_nameserver {
+            helper::check_dns_upstream(upstream).map_err(|e| format!("dns_nameserver: {}", e))?;
+        }
+        if let Some(level) = &self.log_level {
+            log::LevelFilter::from_str(level)
+                .map_err(|_| format!("log_level: unknown level {}", level))?;
+        }
+        if let Some(format) = &self.log_format {
+            LogFormat::from_str(format)
+                .map_err(|_| format!("log_format: unknown format {}", format))?;
+        }
+        if let Some(config_override) = &self.config_override {
+            if !config_override.trim().is_empty() {
+                match serde_yaml::from_str::<serde_yaml::Value>(config_override) {
+                    Ok(x) if x.is_mapping() => {}
+                    Ok(_) => return Err("config_override must be a yaml mapping".to_string()),
+                    Err(e) => return Err(format!("config_override: {}", e)),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn open<P: AsRef<std::path::Path>>(path: P) -> Result<Settings, JsonError> {
+        let mut file = std::fs::File::open(path).map_err(JsonError::Io)?;
+        let mut value: serde_json::Value =
+            serde_json::from_reader(&mut file).map_err(JsonError::Serde)?;
+        migrate_legacy_fields(&mut value);
+        serde_json::from_value(value).map_err(JsonError::Serde)
+    }
+
+    /// 内容与 `saved` 相同时不写入，返回是否写入了文件
+    pub fn save_if_changed<P: AsRef<std::path::Path>>(
+        &self,
+        path: P,
+        saved: &mut Option<String>,
+    ) -> Result<bool, JsonError> {
+        let content = serde_json::to_string_pretty(&self).map_err(JsonError::Serde)?;
+        if saved.as_deref() == Some(content.as_str()) {
+            return Ok(false);
+        }
         let path = path.as_ref();
         if let Some(parent) = path.parent() {
             std::fs::create_dir_all(parent).map_err(JsonError::Io)?;
         }
-        let mut file = std::fs::File::create(path).map_err(JsonError::Io)?;
-        serde_json::to_writer_pretty(&mut file, &self).map_err(JsonError::Serde)
+        helper::write_atomic(path, &content).map_err(JsonError::Io)?;
+        *saved = Some(content);
+        Ok(true)
     }
+}
 
-    pub fn open<P: AsRef<std::path::Path>>(path: P) -> Result<Settings, JsonError> {
-        let mut file = std::fs::File::open(path).map_err(JsonError::Io)?;
-        serde_json::from_reader(&mut file).map_err(JsonError::Serde)
+/// 旧版本的 `dns_upstreams` 已合并到 `dns_nameserver`，不为空时替换
+fn migrate_legacy_fields(value: &mut serde_json::Value) {
+    let fields = match value.as_object_mut() {
+        Some(x) => x,
+        None => return,
+    };
+    if let Some(upstreams) = fields.remove("dns_upstreams") {
+        if upstreams.as_array().is_some_and(|x| !x.is_empty()) {
+            fields.insert("dns_nameserver".to_string(), upstreams);
+        }
     }
 }
 
 impl Default for Settings {
     fn default() -> Self {
-        let default_profile = helper::get_current_working_dir().unwrap().join("bin/core/config.yaml");
+        let default_profile = helper::install_root().unwrap_or_default().join("bin/core/config.yaml");
         Self {
             enable: false,
-            tun_mode: true,
+            tun_enabled: true,
             skip_proxy: true,
+            bypass_lan: default_bypass_lan(),
+            keep_connections_on_reload: default_keep_connections_on_reload(),
+            preflight_check: default_preflight_check(),
+            use_bundled_dashboard: default_use_bundled_dashboard(),
             current_sub: default_profile.to_string_lossy().to_string(),
-            subscriptions: Vec::new()
+            subscriptions: Vec::new(),
+            current_config: default_current_config(),
+            provider_check_interval: default_provider_check_interval(),
+            subscription_update_interval: default_subscription_update_interval(),
+            unified_delay: false,
+            tcp_concurrent: false,
+            external_controller: default_external_controller(),
+            secret: String::new(),
+            dns_override: DnsOverride::Force,
+            direct_domains: default_direct_domains(),
+            provider_download_attempts: default_provider_download_attempts(),
+            proxy_mode: ProxyMode::Rule,
+            dns_listen: default_dns_listen(),
+            dns_enhanced_mode: default_dns_enhanced_mode(),
+            fake_ip_range: default_fake_ip_range(),
+            ipv6: false,
+            fake_ip_range6: default_fake_ip_range6(),
+            dns_nameserver: default_dns_nameserver(),
+            excluded_interfaces: Vec::new(),
+            restart_on_crash: false,
+            manage_system_dns: default_manage_system_dns(),
+            user_agent: default_user_agent(),
+            download_via_proxy: false,
+            download_timeout_secs: default_download_timeout_secs(),
+            allow_lan: false,
+            mixed_port: None,
+            max_download_kbps: None,
+            max_upload_kbps: None,
+            config_override: None,
+            geo_databases: default_geo_databases(),
+            log_level: None,
+            log_path: None,
+            log_format: None,
+            log_rotate_bytes: default_log_rotate_bytes(),
+            core_type: CoreType::ClashPremium,
+            tun_stack: None,
+            auto_start: false,
+            auto_start_failures: 0,
         }
     }
-}
\ No newline at end of file
+}
+/// 设置字段的值类型，前端据此选择控件
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FieldKind {
+    Bool,
+    Integer,
+    Text,
+    TextList,
+    /// 取值为 `options` 之一
+    Enum,
+    /// 结构化的列表，例如 `geo_databases`
+    ObjectList,
+}
+
+/// `get_settings_schema` 中的一个字段，默认值来自 `Settings::default`
+#[derive(Serialize, Debug)]
+pub struct SettingField {
+    pub name: &'static str,
+    pub kind: FieldKind,
+    /// 可以为 `null`
+    pub optional: bool,
+    pub options: Vec<String>,
+    pub default: serde_json::Value,
+    /// 由后端或专门的接口维护，通用的设置界面不应显示
+    pub internal: bool,
+}
+
+fn enum_options<T: Serialize>(values: &[T]) -> Vec<String> {
+    values
+        .iter()
+        .filter_map(|x| match serde_json::to_value(x) {
+            Ok(serde_json::Value::String(x)) => Some(x),
+            _ => None,
+        })
+        .collect()
+}
+
+/// 描述全部设置字段，新增字段时需要同时加到这里
+pub fn settings_schema() -> Vec<SettingField> {
+    use FieldKind::*;
+    let log_levels = ["error", "warn", "info", "debug", "trace"].map(String::from).to_vec();
+    // (字段, 类型, 可以为空, 枚举的可选值, 内部字段)
+    let fields: Vec<(&'static str, FieldKind, bool, Vec<String>, bool)> = vec![
+        ("enable", Bool, false, vec![], true),
+        ("tun_enabled", Bool, false, vec![], false),
+        ("skip_proxy", Bool, false, vec![], false),
+        ("bypass_lan", Bool, false, vec![], false),
+        ("keep_connections_on_reload", Bool, false, vec![], false),
+        ("use_bundled_dashboard", Bool, false, vec![], false),
+        ("preflight_check", Bool, false, vec![], false),
+        ("current_sub", Text, false, vec![], true),
+        ("subscriptions", ObjectList, false, vec![], true),
+        ("current_config", Text, false, vec![], true),
+        ("provider_check_interval", Integer, false, vec![], false),
+        ("subscription_update_interval", Integer, false, vec![], false),
+        ("unified_delay", Bool, false, vec![], false),
+        ("tcp_concurrent", Bool, false, vec![], false),
+        ("external_controller", Text, false, vec![], false),
+        ("secret", Text, false, vec![], false),
+        (
+            "dns_override",
+            Enum,
+            false,
+            enum_options(&[DnsOverride::Force, DnsOverride::Merge, DnsOverride::Keep]),
+            false,
+        ),
+        ("direct_domains", TextList, false, vec![], false),
+        ("provider_download_attempts", Integer, false, vec![], false),
+        (
+            "proxy_mode",
+            Enum,
+            false,
+            enum_options(&[ProxyMode::Rule, ProxyMode::Global, ProxyMode::Direct]),
+            false,
+        ),
+        ("dns_listen", Text, false, vec![], false),
+        ("dns_enhanced_mode", Text, false, vec![], false),
+        ("fake_ip_range", Text, false, vec![], false),
+        ("ipv6", Bool, false, vec![], false),
+        ("fake_ip_range6", Text, false, vec![], false),
+        ("dns_nameserver", TextList, false, vec![], false),
+        ("excluded_interfaces", TextList, false, vec![], false),
+        ("restart_on_crash", Bool, false, vec![], false),
+        ("manage_system_dns", Bool, false, vec![], false),
+        ("user_agent", Text, false, vec![], false),
+        ("download_via_proxy", Bool, false, vec![], false),
+        ("download_timeout_secs", Integer, false, vec![], false),
+        ("allow_lan", Bool, false, vec![], false),
+        ("mixed_port", Integer, true, vec![], false),
+        ("max_download_kbps", Integer, true, vec![], false),
+        ("max_upload_kbps", Integer, true, vec![], false),
+        ("config_override", Text, true, vec![], false),
+        ("geo_databases", ObjectList, false, vec![], false),
+        ("log_level", Enum, true, log_levels, false),
+        ("log_path", Text, true, vec![], false),
+        (
+            "log_format",
+            Enum,
+            true,
+            ["text", "json"].map(String::from).to_vec(),
+            false,
+        ),
+        ("log_rotate_bytes", Integer, false, vec![], false),
+        (
+            "core_type",
+            Enum,
+            false,
+            enum_options(&[CoreType::ClashPremium, CoreType::Meta]),
+            false,
+        ),
+        (
+            "tun_stack",
+            Enum,
+            true,
+            enum_options(&[TunStack::System, TunStack::Gvisor, TunStack::Mixed]),
+            false,
+        ),
+        ("auto_start", Bool, false, vec![], false),
+        ("auto_start_failures", Integer, false, vec![], true),
+    ];
+    let defaults = serde_json::to_value(Settings::default()).unwrap_or_default();
+    fields
+        .into_iter()
+        .map(|(name, kind, optional, options, internal)| SettingField {
+            name,
+            kind,
+            optional,
+            options,
+            default: defaults.get(name).cloned().unwrap_or_default(),
+            internal,
+        })
+        .collect()
+}
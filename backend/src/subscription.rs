@@ -0,0 +1,178 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::control::{ClashError, ClashErrorKind};
+use crate::helper::{self, DownloadOptions};
+use crate::settings::Subscription;
+
+/// 返回给前端的订阅信息，`id` 为保存文件名（不含扩展名）
+#[derive(Debug, Clone, Serialize)]
+pub struct SubscriptionEntry {
+    pub id: String,
+    pub path: String,
+    pub url: String,
+    pub update_interval: Option<u64>,
+}
+
+impl From<&Subscription> for SubscriptionEntry {
+    fn from(sub: &Subscription) -> Self {
+        Self {
+            id: subscription_id_of(&sub.path),
+            path: sub.path.clone(),
+            url: sub.url.clone(),
+            update_interval: sub.update_interval,
+        }
+    }
+}
+
+/// 订阅保存的目录
+pub fn subs_dir(home: &Path) -> PathBuf {
+    home.join(".config/tomoon/subs/")
+}
+
+/// 同一个链接总是对应同一个文件，重复添加时直接覆盖
+pub fn subscription_id(url: &str) -> String {
+    Sha256::digest(url.as_bytes())
+        .iter()
+        .take(8)
+        .map(|x| format!("{:02x}", x))
+        .collect()
+}
+
+fn subscription_id_of(path: &str) -> String {
+    Path::new(path)
+        .file_stem()
+        .map(|x| x.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+/// 获取订阅内容，支持 `file://` 本地文件，并检查是否为合法的 Clash 配置
+pub fn fetch_subscription(url: &str, options: &DownloadOptions) -> Result<String, ClashError> {
+    let content = match helper::get_file_path(url.to_string()) {
+        Some(local_file) => fs::read_to_string(&local_file).map_err(|e| ClashError {
+            message: format!("Failed to read {}: {}", local_file, e),
+            kind: ClashErrorKind::ConfigNotFound,
+        })?,
+        None => {
+            let response = helper::get_request(url, options).send()?;
+            helper::check_status(response.status_code, &response.reason_phrase).map_err(|e| {
+                ClashError {
+                    message: e,
+                    kind: ClashErrorKind::NetworkError,
+                }
+            })?;
+            helper::response_text(response).map_err(|e| ClashError {
+                message: e,
+                kind: ClashErrorKind::ConfigFormatError,
+            })?
+        }
+    };
+    if !helper::check_yaml(&content) {
+        return Err(ClashError {
+            message: "The downloaded subscription is not a legal profile.".to_string(),
+            kind: ClashErrorKind::ConfigFormatError,
+        });
+    }
+    Ok(content)
+}
+
+/// 下载订阅到 `subs_dir` 下，返回保存的路径
+pub fn save_subscription(
+    home: &Path,
+    url: &str,
+    options: &DownloadOptions,
+) -> Result<PathBuf, ClashError> {
+    let content = fetch_subscription(url, options)?;
+    let dir = subs_dir(home);
+    fs::create_dir_all(&dir)?;
+    let path = dir.join(subscription_id(url) + ".yaml");
+    helper::write_atomic(&path, content)?;
+    Ok(path)
+}
+
+/// 检查文本是否为 yaml mapping，出错时返回所在的行列
+pub fn parse_config_text(text: &str) -> Result<serde_yaml::Mapping, ClashError> {
+    match serde_yaml::from_str::<serde_yaml::Value>(text) {
+        Ok(serde_yaml::Value::Mapping(x)) => Ok(x),
+        Ok(_) => Err(ClashError {
+            message: "The config is not a yaml mapping.".to_string(),
+            kind: ClashErrorKind::ConfigFormatError,
+        }),
+        Err(e) => {
+            let message = match e.location() {
+                Some(x) => format!("line {}, column {}: {}", x.line(), x.column(), e),
+                None => e.to_string(),
+            };
+            Err(ClashError {
+                message,
+                kind: ClashErrorKind::ConfigFormatError,
+            })
+        }
+    }
+}
+
+/// 保存直接导入的配置文本，文件名来自 `name`，同名时覆盖
+pub fn save_config_text(home: &Path, name: &str, text: &str) -> Result<PathBuf, ClashError> {
+    parse_config_text(text)?;
+    let file_name: String = name
+        .trim()
+        .chars()
+        .map(|x| {
+            if x.is_alphanumeric() || x == '-' || x == '_' {
+                x
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    if file_name.is_empty() {
+        return Err(ClashError {
+            message: "config name is empty".to_string(),
+            kind: ClashErrorKind::ConfigFormatError,
+        });
+    }
+    let dir = subs_dir(home);
+    fs::create_dir_all(&dir)?;
+    let path = dir.join(file_name + ".yaml");
+    helper::write_atomic(&path, text)?;
+    Ok(path)
+}
+
+/// 订阅是否已到更新时间，本地文件和关闭更新的订阅不会更新
+pub fn is_update_due(sub: &Subscription, default_interval: u64) -> bool {
+    if helper::get_file_path(sub.url.clone()).is_some() {
+        return false;
+    }
+    let interval = sub.update_interval.unwrap_or(default_interval);
+    if interval == 0 {
+        return false;
+    }
+    match fs::metadata(&sub.path).and_then(|x| x.modified()) {
+        Ok(modified) => match modified.elapsed() {
+            Ok(age) => age.as_secs() >= interval,
+            Err(_) => false,
+        },
+        Err(_) => true,
+    }
+}
+
+/// 重新下载订阅，内容有变化时返回 true
+pub fn refresh_subscription(
+    sub: &Subscription,
+    options: &DownloadOptions,
+) -> Result<bool, ClashError> {
+    let content = fetch_subscription(&sub.url, options)?;
+    if fs::read_to_string(&sub.path).ok().as_deref() == Some(content.as_str()) {
+        return Ok(false);
+    }
+    helper::write_atomic(Path::new(&sub.path), content)?;
+    Ok(true)
+}
+
+/// 按 id 查找订阅在列表中的位置
+pub fn find_subscription(subs: &[Subscription], id: &str) -> Option<usize> {
+    subs.iter().position(|x| subscription_id_of(&x.path) == id)
+}
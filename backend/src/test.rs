@@ -1,11 +1,12 @@
 mod tests {
 
-    use crate::{control, helper};
+    use crate::{control, helper, settings::Settings};
     use regex::Regex;
     use serde_yaml::{Mapping, Number, Value};
     use std::{
         fs,
         path::PathBuf,
+        sync::Arc,
         process::{Command, Stdio},
         thread,
         time::Duration,
@@ -88,7 +89,7 @@ mod tests {
     fn test_yaml() {
         println!("{}", std::env::current_dir().unwrap().to_str().unwrap());
         let mut clash = control::Clash::default();
-        clash.change_config(true);
+        clash.change_config(control::ConfigOptions::from(&Settings::default()), &|_| {});
     }
 
     #[test]
@@ -323,4 +324,1205 @@ mod tests {
         );
         fs::write("/tmp/tomoon.debug.log", log).unwrap();
     }
+
+    #[test]
+    fn test_tun_disabled() {
+        let config_path = std::env::temp_dir().join("tomoon_test_tun_disabled.yaml");
+        fs::write(
+            &config_path,
+            "tun:\n  enable: true\n  stack: gvisor\nrules:\n  - MATCH,DIRECT\n",
+        )
+        .unwrap();
+        let run_config_path = control::running_config_path().unwrap();
+        fs::create_dir_all(run_config_path.parent().unwrap()).unwrap();
+        let mut clash = control::Clash::default();
+        clash.update_config_path(&config_path.to_string_lossy().to_string());
+        let settings = Settings {
+            tun_enabled: false,
+            ..Default::default()
+        };
+        clash
+            .change_config(control::ConfigOptions::from(&settings), &|_| {})
+            .unwrap();
+
+        let run_config = fs::read_to_string(run_config_path).unwrap();
+        let yaml: Value = serde_yaml::from_str(run_config.as_str()).unwrap();
+        // 订阅自带的 tun 配置被关闭，DNS 依然由 ToMoon 接管
+        assert_eq!(yaml["tun"]["enable"], Value::Bool(false));
+        assert!(yaml["tun"].get("stack").is_none());
+        assert_eq!(yaml["dns"]["enable"], Value::Bool(true));
+        assert_eq!(
+            yaml["dns"]["enhanced-mode"],
+            Value::String("fake-ip".to_string())
+        );
+    }
+
+    #[test]
+    fn test_stop_without_run() {
+        assert!(!helper::resolv_conf_backup_path().exists());
+        let mut clash = control::Clash::default();
+        assert!(clash.stop().is_ok());
+        // 第二次调用同样不应出错
+        assert!(clash.stop().is_ok());
+    }
+
+    #[test]
+    fn test_verify_checksum() {
+        let body = b"hello";
+        let hash = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+        assert!(control::verify_checksum(body, hash).is_ok());
+        assert!(control::verify_checksum(body, &hash.to_uppercase()).is_ok());
+        let err = control::verify_checksum(body, &"0".repeat(64)).unwrap_err();
+        assert_eq!(err.kind, control::ClashErrorKind::ChecksumMismatch);
+    }
+
+    #[test]
+    fn test_transform_config_overwrite() {
+        let options = control::ConfigOptions::from_settings(&Settings::default());
+        let input: Value = serde_yaml::from_str(
+            "
+external-controller: 0.0.0.0:1234
+tun:
+  enable: false
+  stack: gvisor
+dns:
+  enable: false
+  nameserver: [8.8.8.8]
+profile:
+  store-selected: false
+rules:
+  - MATCH,Proxy
+",
+        )
+        .unwrap();
+        let output = control::transform_config(input, &options).unwrap();
+        assert_eq!(
+            output["external-controller"].as_str(),
+            Some(options.external_controller.as_str())
+        );
+        assert_eq!(output["tun"]["enable"], Value::Bool(true));
+        assert_eq!(output["tun"]["stack"].as_str(), Some("system"));
+        assert_eq!(output["dns"], serde_yaml::to_value(&options.dns).unwrap());
+        assert_eq!(output["profile"]["store-selected"], Value::Bool(true));
+        assert_eq!(output["profile"]["store-fake-ip"], Value::Bool(false));
+        let rules: Vec<&str> = output["rules"]
+            .as_sequence()
+            .unwrap()
+            .iter()
+            .map(|x| x.as_str().unwrap())
+            .collect();
+        assert_eq!(
+            rules,
+            vec![
+                "IP-CIDR,192.168.0.0/16,DIRECT,no-resolve",
+                "IP-CIDR,10.0.0.0/8,DIRECT,no-resolve",
+                "IP-CIDR,172.16.0.0/12,DIRECT,no-resolve",
+                "IP-CIDR,127.0.0.0/8,DIRECT,no-resolve",
+                "DOMAIN-SUFFIX,cm.steampowered.com,DIRECT",
+                "DOMAIN,test.steampowered.com,DIRECT",
+                "MATCH,Proxy",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_transform_config_without_rules() {
+        let mut options = control::ConfigOptions::from_settings(&Settings::default());
+        options.tun_enabled = false;
+        let input: Value = serde_yaml::from_str("proxies: []").unwrap();
+        let output = control::transform_config(input, &options).unwrap();
+        assert_eq!(
+            output["external-controller"].as_str(),
+            Some(options.external_controller.as_str())
+        );
+        assert_eq!(output["tun"]["enable"], Value::Bool(false));
+        assert!(output["dns"].is_mapping());
+        assert!(output["profile"].is_mapping());
+        assert_eq!(output["rules"].as_sequence().unwrap().len(), 6);
+
+        let input: Value = serde_yaml::from_str("rules: MATCH,DIRECT").unwrap();
+        let err = control::transform_config(input, &options).unwrap_err();
+        assert_eq!(err.kind, control::ClashErrorKind::ConfigFormatError);
+    }
+
+    #[test]
+    fn test_transform_config_proxy_list() {
+        let options = control::ConfigOptions::from_settings(&Settings::default());
+        let input: Value = serde_yaml::from_str(
+            "
+- name: a
+  type: ss
+  server: 1.1.1.1
+- name: b
+  type: vmess
+  server: 2.2.2.2
+",
+        )
+        .unwrap();
+        let output = control::transform_config(input, &options).unwrap();
+        assert_eq!(output["proxies"].as_sequence().unwrap().len(), 2);
+        assert_eq!(output["proxy-groups"][0]["name"].as_str(), Some("Proxy"));
+        assert_eq!(output["proxy-groups"][0]["proxies"][1].as_str(), Some("b"));
+        assert_eq!(
+            output["rules"].as_sequence().unwrap().last().unwrap().as_str(),
+            Some("MATCH,Proxy")
+        );
+
+        let input: Value = serde_yaml::from_str("[1, 2]").unwrap();
+        let err = control::transform_config(input, &options).unwrap_err();
+        assert_eq!(err.kind, control::ClashErrorKind::ConfigFormatError);
+    }
+
+    #[test]
+    fn test_merge_yaml() {
+        let mut base: Value = serde_yaml::from_str(
+            "
+dns:
+  enable: true
+  nameserver: [1.1.1.1]
+rules: [MATCH,Proxy]
+mode: rule
+",
+        )
+        .unwrap();
+        let overlay: Value = serde_yaml::from_str(
+            "
+dns:
+  nameserver: [8.8.8.8]
+  fallback: [9.9.9.9]
+rules: [DOMAIN,example.com,DIRECT]
+mode: global
+hosts:
+  a.com: 1.2.3.4
+",
+        )
+        .unwrap();
+        helper::merge_yaml(&mut base, overlay);
+        let expected: Value = serde_yaml::from_str(
+            "
+dns:
+  enable: true
+  nameserver: [1.1.1.1, 8.8.8.8]
+  fallback: [9.9.9.9]
+rules: [MATCH,Proxy, DOMAIN,example.com,DIRECT]
+mode: global
+hosts:
+  a.com: 1.2.3.4
+",
+        )
+        .unwrap();
+        assert_eq!(base, expected);
+    }
+
+    #[test]
+    fn test_transform_config_override() {
+        let mut options = control::ConfigOptions::from_settings(&Settings::default());
+        options.config_override = Some(String::from("profile:\n  store-fake-ip: true\n"));
+        let input: Value = serde_yaml::from_str("rules: []").unwrap();
+        let output = control::transform_config(input, &options).unwrap();
+        assert_eq!(output["profile"]["store-selected"], Value::Bool(true));
+        assert_eq!(output["profile"]["store-fake-ip"], Value::Bool(true));
+
+        options.config_override = Some(String::from("- a"));
+        let input: Value = serde_yaml::from_str("rules: []").unwrap();
+        assert!(control::transform_config(input, &options).is_err());
+    }
+
+    #[test]
+    fn test_fake_ip_range() {
+        assert!(helper::parse_cidr("198.18.0.1/16").is_ok());
+        assert!(helper::parse_cidr("fdfe:dcba:9876::1/64").is_ok());
+        assert!(helper::parse_cidr("198.18.0.1").is_err());
+        assert!(helper::parse_cidr("198.18.0.256/16").is_err());
+        assert!(helper::parse_cidr("198.18.0.1/33").is_err());
+
+        let mut options = control::ConfigOptions::from_settings(&Settings::default());
+        assert_eq!(options.dns.fake_ip_range, "198.18.0.1/16");
+        let input: Value = serde_yaml::from_str("rules: []").unwrap();
+        let output = control::transform_config(input, &options).unwrap();
+        assert_eq!(output["dns"]["fake-ip-range"].as_str(), Some("198.18.0.1/16"));
+
+        options.dns.fake_ip_range = String::from("10.0.0.0/abc");
+        let input: Value = serde_yaml::from_str("rules: []").unwrap();
+        let err = control::transform_config(input, &options).unwrap_err();
+        assert_eq!(err.kind, control::ClashErrorKind::ConfigFormatError);
+        assert!(err.message.contains("fake_ip_range"));
+    }
+
+    #[test]
+    fn test_dns_config_ipv6() {
+        let mut settings = Settings::default();
+        let yaml = serde_yaml::to_string(&control::DnsConfig::from_settings(&settings)).unwrap();
+        let dns: Mapping = serde_yaml::from_str(&yaml).unwrap();
+        assert!(dns.get("ipv6").is_none());
+        assert!(dns.get("fake-ip-range6").is_none());
+
+        settings.ipv6 = true;
+        let yaml = serde_yaml::to_string(&control::DnsConfig::from_settings(&settings)).unwrap();
+        let dns: Mapping = serde_yaml::from_str(&yaml).unwrap();
+        assert_eq!(dns.get("ipv6"), Some(&Value::Bool(true)));
+        assert_eq!(
+            dns.get("fake-ip-range6").and_then(|x| x.as_str()),
+            Some(settings.fake_ip_range6.as_str())
+        );
+        assert_eq!(serde_yaml::to_string(&dns).unwrap(), yaml);
+    }
+
+    #[test]
+    fn test_profile_archive_entries() {
+        use crate::profile::is_safe_entry;
+
+        assert!(is_safe_entry("tomoon.json"));
+        assert!(is_safe_entry("subs/"));
+        assert!(is_safe_entry("subs/abc.yaml"));
+        assert!(is_safe_entry("./subs/abc.yaml"));
+        assert!(!is_safe_entry("../tomoon.json"));
+        assert!(!is_safe_entry("subs/../../.bashrc"));
+        assert!(!is_safe_entry("/etc/resolv.conf"));
+        assert!(!is_safe_entry("subs/nested/abc.yaml"));
+        assert!(!is_safe_entry("other.json"));
+    }
+
+    #[test]
+    fn test_transform_config_idempotent() {
+        let settings = Settings {
+            skip_proxy: true,
+            direct_domains: vec!["test.steampowered.com".to_string()],
+            ..Settings::default()
+        };
+        let options = control::ConfigOptions::from_settings(&settings);
+        let input: Value = serde_yaml::from_str(
+            "rules:\n  - DOMAIN, Test.SteamPowered.com ,direct\n  - MATCH,DIRECT",
+        )
+        .unwrap();
+        let once = control::transform_config(input, &options).unwrap();
+        let rules = once["rules"].as_sequence().unwrap();
+        assert_eq!(rules.len(), 7);
+        assert_eq!(rules[4].as_str(), Some("DOMAIN-SUFFIX,cm.steampowered.com,DIRECT"));
+        let twice = control::transform_config(once.clone(), &options).unwrap();
+        assert_eq!(once, twice);
+    }
+
+    #[test]
+    fn test_dashboard_url() {
+        use crate::controller::Controller;
+
+        let controller = Controller::new("127.0.0.1:9090".to_string(), String::new());
+        assert_eq!(controller.dashboard_url(), "http://127.0.0.1:9090/ui/");
+        let controller = Controller::new("0.0.0.0:9091".to_string(), "a b&c".to_string());
+        assert_eq!(controller.dashboard_url(), "http://127.0.0.1:9091/ui/?secret=a%20b%26c");
+    }
+
+    #[test]
+    fn test_terminate_core_timeout() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join("tomoon_test_terminate_core");
+        fs::create_dir_all(&dir).unwrap();
+        let core = dir.join("clash");
+        // 忽略 SIGTERM 的核心
+        fs::write(&core, "#!/bin/sh\ntrap '' TERM\nwhile true; do sleep 0.1; done\n").unwrap();
+        fs::set_permissions(&core, fs::Permissions::from_mode(0o755)).unwrap();
+        let mut child = Command::new(&core).spawn().unwrap();
+        thread::sleep(Duration::from_millis(200));
+        let method = control::terminate_core(&mut child, Duration::from_millis(500)).unwrap();
+        assert_eq!(method, control::StopMethod::Forced);
+        assert!(child.try_wait().unwrap().is_some());
+
+        let mut child = Command::new("sleep").arg("10").spawn().unwrap();
+        let method = control::terminate_core(&mut child, Duration::from_secs(2)).unwrap();
+        assert_eq!(method, control::StopMethod::Graceful);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_tun_stack() {
+        use crate::settings::TunStack;
+
+        let mut options = control::ConfigOptions::from_settings(&Settings::default());
+        options.tun_stack = Some(TunStack::Gvisor);
+        let output = control::transform_config(Value::Mapping(Mapping::new()), &options).unwrap();
+        assert_eq!(output["tun"]["stack"].as_str(), Some("gvisor"));
+        assert_eq!(output["tun"]["auto-route"], Value::Bool(true));
+        assert_eq!(output["tun"]["auto-detect-interface"], Value::Bool(true));
+
+        // Clash Premium 不支持 mixed，使用默认的 system
+        options.tun_stack = Some(TunStack::Mixed);
+        let output = control::transform_config(Value::Mapping(Mapping::new()), &options).unwrap();
+        assert_eq!(output["tun"]["stack"].as_str(), Some("system"));
+
+        options.meta_core = true;
+        let output = control::transform_config(Value::Mapping(Mapping::new()), &options).unwrap();
+        assert_eq!(output["tun"]["stack"].as_str(), Some("mixed"));
+    }
+
+    #[test]
+    fn test_subscription_update_due() {
+        use crate::settings::Subscription;
+        use crate::subscription::is_update_due;
+
+        let path = std::env::temp_dir().join("tomoon_test_update_due.yaml");
+        let _ = fs::remove_file(&path);
+        let path = path.to_string_lossy().to_string();
+        let mut sub = Subscription::new(path.clone(), "https://example.com/sub".to_string());
+        // 文件还不存在
+        assert!(is_update_due(&sub, 3600));
+        assert!(!is_update_due(&sub, 0));
+        sub.update_interval = Some(0);
+        assert!(!is_update_due(&sub, 3600));
+        sub.update_interval = None;
+        fs::write(&path, "proxies: []").unwrap();
+        assert!(!is_update_due(&sub, 3600));
+        sub.url = format!("file://{}", path);
+        assert!(!is_update_due(&sub, 1));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_parse_core_version() {
+        use crate::control::parse_core_version;
+
+        assert_eq!(
+            parse_core_version("Clash v1.18.0 linux amd64 with go1.20.4 Thu May 4 2023\n"),
+            Some("v1.18.0".to_string())
+        );
+        assert_eq!(
+            parse_core_version("Mihomo Meta v1.18.1 linux amd64 with go1.21.5\nUse tags: gvisor"),
+            Some("v1.18.1".to_string())
+        );
+        assert_eq!(parse_core_version("\n  unknown-build \n"), Some("unknown-build".to_string()));
+        assert_eq!(parse_core_version(""), None);
+    }
+
+    #[test]
+    fn test_is_http_url() {
+        assert!(helper::is_http_url("https://example.com/rules.yaml"));
+        assert!(helper::is_http_url("HTTP://user@10.0.0.1:8080/a?b=c"));
+        assert!(!helper::is_http_url("file:///etc/passwd"));
+        assert!(!helper::is_http_url("https:///missing-host"));
+        assert!(!helper::is_http_url("./rules.yaml"));
+        assert!(!helper::is_http_url("ftp://example.com/a"));
+    }
+
+    #[test]
+    fn test_transform_config_secret() {
+        let mut input = Mapping::new();
+        input.insert(Value::from("secret"), Value::from("from-sub"));
+
+        // 设置了密码时覆盖订阅中的
+        let mut options = control::ConfigOptions::from_settings(&Settings::default());
+        options.secret = "ours".to_string();
+        let output = control::transform_config(Value::Mapping(input.clone()), &options).unwrap();
+        assert_eq!(control::config_secret(&output).as_deref(), Some("ours"));
+
+        // 没有设置密码时保留订阅自带的
+        options.secret = String::new();
+        let output = control::transform_config(Value::Mapping(input), &options).unwrap();
+        assert_eq!(control::config_secret(&output).as_deref(), Some("from-sub"));
+
+        // 都没有时不写入 secret
+        let output = control::transform_config(Value::Mapping(Mapping::new()), &options).unwrap();
+        assert!(output.get("secret").is_none());
+        assert_eq!(control::config_secret(&output), None);
+    }
+
+    #[test]
+    fn test_clash_error_from() {
+        use crate::control::{ClashError, ClashErrorKind};
+
+        let err = ClashError::new(ClashErrorKind::NotRunning, "not running");
+        assert_eq!(err.kind, ClashErrorKind::NotRunning);
+        assert_eq!(err.message, "not running");
+
+        let io = std::io::Error::new(std::io::ErrorKind::NotFound, "missing");
+        let err = ClashError::from(io);
+        assert_eq!(err.kind, ClashErrorKind::InnerError);
+        assert_eq!(err.message, "missing");
+
+        let yaml = serde_yaml::from_str::<Value>("a: [").unwrap_err();
+        assert_eq!(ClashError::from(yaml).kind, ClashErrorKind::ConfigFormatError);
+    }
+
+    #[test]
+    fn test_tail_file_partial_line() {
+        let path = std::env::temp_dir().join("tomoon_test_tail_file.log");
+        fs::write(&path, "first\nsecond\nthird\nwrit").unwrap();
+        let lines = helper::tail_file(&path, 2, 1024).unwrap();
+        assert_eq!(lines, vec!["second".to_string(), "third".to_string()]);
+
+        // 只读取末尾时丢弃不完整的第一行
+        let lines = helper::tail_file(&path, 10, 14).unwrap();
+        assert_eq!(lines, vec!["third".to_string()]);
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_transform_config_external_ui() {
+        use std::path::PathBuf;
+
+        let mut input = Mapping::new();
+        input.insert(Value::from("external-ui"), Value::from("./my-dashboard"));
+
+        let mut options = control::ConfigOptions::from_settings(&Settings::default());
+        options.webui_dir = Some(PathBuf::from("/opt/tomoon/bin/core/web"));
+        let output = control::transform_config(Value::Mapping(input.clone()), &options).unwrap();
+        assert_eq!(output["external-ui"].as_str(), Some("/opt/tomoon/bin/core/web"));
+
+        // 不使用内置面板时保留订阅中的
+        options.webui_dir = None;
+        let output = control::transform_config(Value::Mapping(input), &options).unwrap();
+        assert_eq!(output["external-ui"].as_str(), Some("./my-dashboard"));
+        let output = control::transform_config(Value::Mapping(Mapping::new()), &options).unwrap();
+        assert!(output.get("external-ui").is_none());
+    }
+
+    #[test]
+    fn test_lock_poison_recovery() {
+        use std::sync::{Arc, RwLock};
+
+        let lock = Arc::new(RwLock::new(1));
+        let poison = lock.clone();
+        let _ = std::thread::spawn(move || {
+            let _guard = poison.write().unwrap();
+            panic!("poison the lock");
+        })
+        .join();
+        assert!(lock.is_poisoned());
+
+        *control::write_lock(&lock, "test") += 1;
+        assert!(!lock.is_poisoned());
+        assert_eq!(*control::read_lock(&lock, "test"), 2);
+    }
+
+    #[test]
+    fn test_rule_and_proxy_providers() {
+        let base = std::env::temp_dir().join("tomoon_test_providers");
+        let _ = fs::remove_dir_all(&base);
+        let config = r#"
+rule-providers:
+  reject:
+    type: http
+    behavior: domain
+    url: https://example.com/reject.yaml
+    path: ./ruleset/reject.yaml
+proxy-providers:
+  airport:
+    type: http
+    url: https://example.com/sub
+    path: ./proxies/airport.yaml
+  local:
+    type: file
+    path: ./proxies/local.yaml
+"#;
+        let mut yaml: Value = serde_yaml::from_str(config).unwrap();
+        control::rewrite_provider_paths(&mut yaml, &base).unwrap();
+        assert_eq!(
+            yaml["rule-providers"]["reject"]["path"].as_str(),
+            base.join("ruleset/reject.yaml").to_str()
+        );
+        assert_eq!(
+            yaml["proxy-providers"]["airport"]["path"].as_str(),
+            base.join("proxies/airport.yaml").to_str()
+        );
+
+        let jobs = control::provider_jobs(&yaml, &base).unwrap();
+        let names: Vec<&str> = jobs.iter().map(|x| x.0.as_str()).collect();
+        assert_eq!(names, vec!["reject", "airport"]);
+        assert_eq!(jobs[1].2, base.join("proxies/airport.yaml"));
+
+        // 已经下载过的不再下载
+        fs::create_dir_all(base.join("proxies")).unwrap();
+        fs::write(base.join("proxies/airport.yaml"), "proxies: []").unwrap();
+        let jobs = control::provider_jobs(&yaml, &base).unwrap();
+        assert_eq!(jobs.len(), 1);
+        let _ = fs::remove_dir_all(&base);
+    }
+
+    #[test]
+    fn test_dns_upstreams() {
+        assert!(helper::check_dns_upstream("https://dns.google/dns-query").is_ok());
+        assert!(helper::check_dns_upstream("tls://1.1.1.1").is_ok());
+        assert!(helper::check_dns_upstream("quic://dns.adguard.com:784").is_ok());
+        assert!(helper::check_dns_upstream("8.8.8.8").is_err());
+        assert!(helper::check_dns_upstream("tls://").is_err());
+        assert!(helper::check_dns_upstream("https:///dns-query").is_err());
+
+        let settings = Settings {
+            dns_nameserver: vec!["tls://1.1.1.1".to_string(), "dns.google".to_string()],
+            ..Default::default()
+        };
+        assert!(settings.validate().unwrap_err().contains("dns.google"));
+        let settings = Settings {
+            dns_nameserver: vec![],
+            ..Default::default()
+        };
+        assert!(settings.validate().is_err());
+
+        // 默认使用本地的 SmartDNS
+        let options = control::ConfigOptions::from_settings(&Settings::default());
+        assert_eq!(options.dns.nameserver, vec!["tcp://127.0.0.1:5353".to_string()]);
+        assert!(Settings::default().validate().is_ok());
+        let settings = Settings {
+            dns_nameserver: vec!["https://dns.google/dns-query".to_string()],
+            ..Default::default()
+        };
+        let options = control::ConfigOptions::from_settings(&settings);
+        assert_eq!(options.dns.nameserver, settings.dns_nameserver);
+
+        // 没有 systemd-resolved 时 resolv.conf 只能指向 53 端口
+        let settings = Settings {
+            dns_listen: "0.0.0.0:5353".to_string(),
+            ..Default::default()
+        };
+        if !helper::uses_systemd_resolved() {
+            assert!(settings.validate().unwrap_err().contains("dns_listen"));
+        }
+        let settings = Settings {
+            manage_system_dns: false,
+            ..settings
+        };
+        assert!(settings.validate().is_ok());
+
+        // 旧版本的 dns_upstreams 迁移到 dns_nameserver
+        let path =
+            std::env::temp_dir().join(format!("tomoon-dns-migrate-{}.json", std::process::id()));
+        fs::write(&path, r#"{"dns_upstreams": ["tls://1.1.1.1"]}"#).unwrap();
+        let settings = Settings::open(&path).unwrap();
+        assert_eq!(settings.dns_nameserver, vec!["tls://1.1.1.1".to_string()]);
+        fs::write(&path, r#"{"dns_upstreams": []}"#).unwrap();
+        let settings = Settings::open(&path).unwrap();
+        assert_eq!(settings.dns_nameserver, vec!["tcp://127.0.0.1:5353".to_string()]);
+        let _ = fs::remove_file(&path);
+    }
+
+    // 只记录调用，不修改系统网络
+    #[cfg(test)]
+    #[derive(Default)]
+    struct FakeHost(std::sync::Mutex<Vec<&'static str>>);
+
+    #[cfg(test)]
+    impl control::HostEnv for FakeHost {
+        fn prepare_geo_db(&self) -> Result<(), control::ClashError> {
+            self.0.lock().unwrap().push("prepare_geo_db");
+            Ok(())
+        }
+        fn set_network(&self, _dns_listen: &str) -> Result<(), control::ClashError> {
+            self.0.lock().unwrap().push("set_network");
+            Ok(())
+        }
+        fn reset_network(&self) -> Result<(), control::ClashError> {
+            self.0.lock().unwrap().push("reset_network");
+            Ok(())
+        }
+    }
+
+    /// 在临时目录 `name` 下用脚本代替核心和 SmartDNS，运行配置也写在该目录
+    /// `start_script` 为核心使用 `-f` 启动时执行的命令，返回的目录中有 `config.yaml`
+    #[cfg(test)]
+    fn fake_clash(name: &str, start_script: &str) -> (control::Clash, Arc<FakeHost>, PathBuf) {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(name);
+        fs::create_dir_all(&dir).unwrap();
+        // -v 和 -t 直接成功
+        let core = dir.join("clash");
+        let script = format!(
+            "#!/bin/sh\ncase \"$1\" in\n-v) echo 'Clash v1.18.0 linux amd64'; exit 0;;\n\
+             -t) exit 0;;\nesac\n{}",
+            start_script
+        );
+        fs::write(&core, script).unwrap();
+        fs::set_permissions(&core, fs::Permissions::from_mode(0o755)).unwrap();
+        let smartdns = dir.join("smartdns");
+        fs::write(&smartdns, "#!/bin/sh\nsleep 30\n").unwrap();
+        fs::set_permissions(&smartdns, fs::Permissions::from_mode(0o755)).unwrap();
+        fs::write(dir.join("config.yaml"), "proxies: []\nrules:\n  - MATCH,DIRECT\n").unwrap();
+
+        let host = Arc::new(FakeHost::default());
+        let clash = control::Clash {
+            host: host.clone(),
+            core_override: Some(core),
+            smartdns_override: Some(smartdns),
+            run_config_override: Some(dir.join("running_config.yaml")),
+            provider_dir: dir.join("providers"),
+            ..Default::default()
+        };
+        (clash, host, dir)
+    }
+
+    #[test]
+    fn test_fake_core_start() {
+        let (mut clash, host, dir) = fake_clash("tomoon_test_fake_core_start", "sleep 30\n");
+        let settings = Settings {
+            tun_enabled: false,
+            ..Default::default()
+        };
+        let config = dir.join("config.yaml").to_string_lossy().to_string();
+
+        clash.run(&config, &settings, &|_| {}).unwrap();
+        assert!(clash.instence.is_some());
+        assert_eq!(*host.0.lock().unwrap(), vec!["prepare_geo_db", "set_network"]);
+        clash.stop().unwrap();
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_fake_core_exits_immediately() {
+        let (mut clash, host, dir) = fake_clash("tomoon_test_fake_core_exit", "echo 'listen tcp :7890: bind: address already in use' >&2; exit 1\n");
+        let settings = Settings {
+            tun_enabled: false,
+            ..Default::default()
+        };
+        let config = dir.join("config.yaml").to_string_lossy().to_string();
+
+        let err = clash.run(&config, &settings, &|_| {}).unwrap_err();
+        assert_eq!(err.kind, control::ClashErrorKind::CoreExited);
+        // 错误中附带核心日志的最后几行
+        assert!(err.message.contains("address already in use"));
+        // 核心没有运行，也就不会有需要停止的进程，DNS 在启动失败时已经复原
+        assert!(clash.instence.is_none());
+        assert_eq!(
+            *host.0.lock().unwrap(),
+            vec!["prepare_geo_db", "set_network", "reset_network"]
+        );
+        assert_eq!(clash.stop().unwrap(), control::StopMethod::NotRunning);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_fake_core_stop_resets_network() {
+        let (mut clash, host, dir) = fake_clash("tomoon_test_fake_core_stop", "sleep 30\n");
+        let settings = Settings {
+            tun_enabled: false,
+            ..Default::default()
+        };
+        let config = dir.join("config.yaml").to_string_lossy().to_string();
+
+        clash.run(&config, &settings, &|_| {}).unwrap();
+        host.0.lock().unwrap().clear();
+        assert_eq!(clash.stop().unwrap(), control::StopMethod::Graceful);
+        assert!(clash.instence.is_none());
+        assert!(clash.smartdns_instence.is_none());
+        assert_eq!(*host.0.lock().unwrap(), vec!["reset_network"]);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_fake_smartdns_missing() {
+        let (mut clash, host, dir) = fake_clash("tomoon_test_fake_smartdns_missing", "sleep 30\n");
+        clash.smartdns_override = Some(dir.join("missing-smartdns"));
+        let settings = Settings {
+            tun_enabled: false,
+            ..Default::default()
+        };
+        let config = dir.join("config.yaml").to_string_lossy().to_string();
+
+        //SmartDNS 无法启动时不启动核心，并复原 DNS
+        assert!(clash.run(&config, &settings, &|_| {}).is_err());
+        assert!(clash.instence.is_none());
+        assert!(clash.smartdns_instence.is_none());
+        assert_eq!(
+            *host.0.lock().unwrap(),
+            vec!["prepare_geo_db", "set_network", "reset_network"]
+        );
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_bandwidth_limit() {
+        let parse = |x: &str| helper::parse_bandwidth_kbps(&serde_yaml::from_str(x).unwrap());
+        assert_eq!(parse("100 Mbps"), Some(100_000));
+        assert_eq!(parse("500Kbps"), Some(500));
+        assert_eq!(parse("10 MBps"), Some(80_000));
+        assert_eq!(parse("50"), Some(50_000));
+        assert_eq!(parse("fast"), None);
+        // 溢出时视为无法解析
+        assert_eq!(parse("99999999999999999 Tbps"), None);
+        assert_eq!(parse("18446744073709551615"), None);
+
+        let input: Value = serde_yaml::from_str(
+            "proxies:\n\
+             - {name: a, type: hysteria2, server: a.com, port: 443, up: 10 Mbps}\n\
+             - {name: b, type: hysteria, server: b.com, port: 443, down: 100 Kbps}\n\
+             - {name: c, type: ss, server: c.com, port: 443}\n\
+             rules: [MATCH,DIRECT]\n",
+        )
+        .unwrap();
+        let settings = Settings {
+            core_type: crate::settings::CoreType::Meta,
+            max_download_kbps: Some(2000),
+            max_upload_kbps: Some(20000),
+            ..Default::default()
+        };
+        let output =
+            control::transform_config(input.clone(), &control::ConfigOptions::from(&settings))
+                .unwrap();
+        let proxies = &output["proxies"];
+        assert_eq!(proxies[0]["down"].as_str(), Some("2000 Kbps"));
+        // 订阅中更低的限速保持不变
+        assert_eq!(proxies[0]["up"].as_str(), Some("10 Mbps"));
+        assert_eq!(proxies[1]["down"].as_str(), Some("100 Kbps"));
+        assert_eq!(proxies[1]["up"].as_str(), Some("20000 Kbps"));
+        assert!(proxies[2].get("down").is_none());
+
+        // Premium 不支持，配置不变
+        let settings = Settings {
+            core_type: crate::settings::CoreType::ClashPremium,
+            ..settings
+        };
+        let output =
+            control::transform_config(input.clone(), &control::ConfigOptions::from(&settings))
+                .unwrap();
+        assert_eq!(output["proxies"], input["proxies"]);
+        // 按检测到的核心判断，设置为 Premium 但实际是 Meta 时同样限速
+        let mut options = control::ConfigOptions::from(&settings);
+        options.meta_core = true;
+        let output = control::transform_config(input.clone(), &options).unwrap();
+        assert_eq!(output["proxies"][0]["down"].as_str(), Some("2000 Kbps"));
+
+        let settings = Settings {
+            max_download_kbps: Some(0),
+            ..Default::default()
+        };
+        assert!(settings.validate().is_err());
+    }
+
+    #[test]
+    fn test_transform_config_keeps_unknown_keys() {
+        let input: Value = serde_yaml::from_str(
+            "hosts:\n  router.lan: 192.168.1.1\n\
+             proxies:\n  - {name: a, type: ss, server: a.com, port: 443, cipher: aes-128-gcm, password: x}\n\
+             tun:\n  enable: true\n\
+             script:\n  shortcuts:\n    quic: network == 'udp' and dst_port == 443\n\
+             dns:\n  enable: true\n\
+             proxy-groups:\n  - {name: Proxy, type: select, proxies: [a]}\n\
+             profile:\n  store-selected: false\n\
+             x-custom: [1, two, {three: 3}]\n\
+             rules:\n  - MATCH,Proxy\n",
+        )
+        .unwrap();
+        let output = control::transform_config(
+            input.clone(),
+            &control::ConfigOptions::from(&Settings::default()),
+        )
+        .unwrap();
+
+        // ToMoon 不修改的键，值和相对顺序都保持不变
+        let unknown = |x: &Value| -> Vec<(Value, Value)> {
+            x.as_mapping()
+                .unwrap()
+                .iter()
+                .filter(|(k, _)| {
+                    ["hosts", "proxies", "script", "proxy-groups", "x-custom"]
+                        .contains(&k.as_str().unwrap())
+                })
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect()
+        };
+        assert_eq!(unknown(&output), unknown(&input));
+        assert_eq!(unknown(&input).len(), 5);
+        // 被覆盖的键留在原来的位置
+        let keys: Vec<&str> = output
+            .as_mapping()
+            .unwrap()
+            .keys()
+            .filter_map(|x| x.as_str())
+            .collect();
+        assert_eq!(
+            &keys[..9],
+            &["hosts", "proxies", "tun", "script", "dns", "proxy-groups", "profile", "x-custom", "rules"]
+        );
+    }
+
+    #[test]
+    fn test_fake_core_unmanaged_dns() {
+        let (mut clash, host, dir) = fake_clash("tomoon_test_fake_core_dns", "sleep 30\n");
+        let settings = Settings {
+            tun_enabled: false,
+            manage_system_dns: false,
+            ..Default::default()
+        };
+        let config = dir.join("config.yaml").to_string_lossy().to_string();
+
+        clash.run(&config, &settings, &|_| {}).unwrap();
+        assert!(clash.instence.is_some());
+        assert_eq!(clash.stop().unwrap(), control::StopMethod::Graceful);
+        // 不修改也不复原系统 DNS
+        assert_eq!(*host.0.lock().unwrap(), vec!["prepare_geo_db"]);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_excluded_interfaces() {
+        let input: Value = serde_yaml::from_str("proxies: []\nrules: [MATCH,DIRECT]\n").unwrap();
+        let settings = Settings {
+            core_type: crate::settings::CoreType::Meta,
+            excluded_interfaces: vec!["tun0".to_string(), "wg0".to_string()],
+            ..Default::default()
+        };
+        let output =
+            control::transform_config(input.clone(), &control::ConfigOptions::from(&settings))
+                .unwrap();
+        let excluded: Vec<&str> = output["tun"]["exclude-interface"]
+            .as_sequence()
+            .unwrap()
+            .iter()
+            .filter_map(|x| x.as_str())
+            .collect();
+        assert_eq!(excluded, vec!["tun0", "wg0"]);
+
+        // Premium 和关闭 TUN 时不写入
+        let premium = Settings {
+            core_type: crate::settings::CoreType::ClashPremium,
+            ..settings.clone()
+        };
+        let output =
+            control::transform_config(input.clone(), &control::ConfigOptions::from(&premium))
+                .unwrap();
+        assert!(output["tun"].get("exclude-interface").is_none());
+        let no_tun = Settings {
+            tun_enabled: false,
+            ..settings.clone()
+        };
+        let output =
+            control::transform_config(input, &control::ConfigOptions::from(&no_tun)).unwrap();
+        assert!(output["tun"].get("exclude-interface").is_none());
+
+        let settings = Settings {
+            excluded_interfaces: vec![" ".to_string()],
+            ..Default::default()
+        };
+        assert!(settings.validate().is_err());
+    }
+
+    #[test]
+    fn test_fake_core_exit_status() {
+        let (mut clash, host, dir) = fake_clash("tomoon_test_fake_core_crash", "sleep 1\nexit 3\n");
+        let settings = Settings {
+            tun_enabled: false,
+            ..Default::default()
+        };
+        let config = dir.join("config.yaml").to_string_lossy().to_string();
+
+        clash.run(&config, &settings, &|_| {}).unwrap();
+        assert!(clash.exit_status().is_none());
+        // 启动检查之后退出，视为运行中意外退出
+        thread::sleep(Duration::from_millis(1500));
+        assert_eq!(clash.exit_status().and_then(|x| x.code()), Some(3));
+        // 进程已经退出时仍然可以清理
+        assert_eq!(clash.stop().unwrap(), control::StopMethod::Graceful);
+        assert!(clash.exit_status().is_none());
+        assert_eq!(
+            *host.0.lock().unwrap(),
+            vec!["prepare_geo_db", "set_network", "reset_network"]
+        );
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_settings_schema() {
+        let schema = crate::settings::settings_schema();
+        // 与 Settings 序列化后的字段一一对应
+        let defaults = serde_json::to_value(Settings::default()).unwrap();
+        let mut keys: Vec<&str> = defaults.as_object().unwrap().keys().map(|x| x.as_str()).collect();
+        let mut names: Vec<&str> = schema.iter().map(|x| x.name).collect();
+        keys.sort();
+        names.sort();
+        assert_eq!(names, keys);
+
+        let field = |name: &str| schema.iter().find(|x| x.name == name).unwrap();
+        assert_eq!(field("proxy_mode").options, vec!["rule", "global", "direct"]);
+        assert_eq!(field("core_type").default, serde_json::json!("ClashPremium"));
+        assert!(field("mixed_port").optional);
+        assert_eq!(field("mixed_port").default, serde_json::Value::Null);
+        assert!(field("subscriptions").internal);
+        for x in schema.iter().filter(|x| x.kind == crate::settings::FieldKind::Enum) {
+            if let Some(default) = x.default.as_str() {
+                assert!(x.options.iter().any(|o| o == default), "{}", x.name);
+            }
+        }
+    }
+
+    #[test]
+    fn test_decode_gzip_body() {
+        use std::io::Write;
+
+        let yaml = "proxies: []\nrules:\n  - MATCH,DIRECT\n";
+        let mut encoder =
+            flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(yaml.as_bytes()).unwrap();
+        let gzip = encoder.finish().unwrap();
+        let body = helper::decode_body(gzip, Some("gzip")).unwrap();
+        assert_eq!(String::from_utf8(body).unwrap(), yaml);
+
+        // zlib 和原始 deflate 都可以解压
+        let mut encoder =
+            flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(yaml.as_bytes()).unwrap();
+        let body = helper::decode_body(encoder.finish().unwrap(), Some("Deflate")).unwrap();
+        assert_eq!(String::from_utf8(body).unwrap(), yaml);
+        let mut encoder =
+            flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(yaml.as_bytes()).unwrap();
+        let body = helper::decode_body(encoder.finish().unwrap(), Some("deflate")).unwrap();
+        assert_eq!(String::from_utf8(body).unwrap(), yaml);
+
+        // 没有编码时原样返回
+        let body = helper::decode_body(yaml.as_bytes().to_vec(), None).unwrap();
+        assert_eq!(body, yaml.as_bytes());
+        assert!(helper::decode_body(yaml.as_bytes().to_vec(), Some("gzip")).is_err());
+    }
+
+    #[test]
+    fn test_select_profile_fallback() {
+        let home = std::env::temp_dir().join("tomoon_test_fallback_profile");
+        let _ = fs::remove_dir_all(&home);
+
+        // 没有订阅时使用内置的直连配置
+        let mut settings = Settings {
+            current_sub: String::new(),
+            ..Default::default()
+        };
+        let profile = control::select_profile(&mut settings, &home).unwrap();
+        assert_eq!(PathBuf::from(&profile), control::fallback_config_path(&home));
+        assert!(settings.current_sub.is_empty());
+        let input: Value = serde_yaml::from_str(&fs::read_to_string(&profile).unwrap()).unwrap();
+        let output =
+            control::transform_config(input, &control::ConfigOptions::from(&settings)).unwrap();
+        assert_eq!(output["tun"]["enable"].as_bool(), Some(true));
+        let rules = output["rules"].as_sequence().unwrap();
+        assert_eq!(rules.last().unwrap().as_str(), Some("MATCH,DIRECT"));
+
+        // 有订阅时使用第一个订阅
+        let sub = home.join("sub.yaml").to_string_lossy().to_string();
+        fs::write(&sub, "proxies: []\n").unwrap();
+        settings
+            .subscriptions
+            .push(crate::settings::Subscription::new(sub.clone(), String::new()));
+        assert_eq!(control::select_profile(&mut settings, &home).unwrap(), sub);
+        assert_eq!(settings.current_sub, sub);
+
+        // 选择的订阅丢失时报错
+        fs::remove_file(&sub).unwrap();
+        let err = control::select_profile(&mut settings, &home).unwrap_err();
+        assert_eq!(err.kind, control::ClashErrorKind::ConfigNotFound);
+        let _ = fs::remove_dir_all(&home);
+    }
+
+    #[test]
+    fn test_download_proxy() {
+        let yaml: Value = serde_yaml::from_str("port: 7891\nmixed-port: 7890\n").unwrap();
+        assert_eq!(control::proxy_port(&yaml), Some(7890));
+        let yaml: Value = serde_yaml::from_str("port: 7891\nmixed-port: 0\n").unwrap();
+        assert_eq!(control::proxy_port(&yaml), Some(7891));
+        let yaml: Value = serde_yaml::from_str("socks-port: 7892\n").unwrap();
+        assert_eq!(control::proxy_port(&yaml), None);
+
+        // 默认直接下载
+        let settings = Settings {
+            download_via_proxy: true,
+            ..Default::default()
+        };
+        assert!(helper::DownloadOptions::from_settings(&settings).proxy.is_none());
+
+        // 代理不可用时请求失败，而不是绕过代理直连
+        let options = helper::DownloadOptions {
+            proxy: Some("127.0.0.1:1".to_string()),
+            timeout: 2,
+            ..helper::DownloadOptions::from_settings(&settings)
+        };
+        assert!(helper::get_request("http://example.com/", &options).send().is_err());
+    }
+
+    #[test]
+    fn test_resolv_conf_points_to_localhost() {
+        assert!(helper::resolv_conf_points_to_localhost("nameserver 127.0.0.1\n"));
+        assert!(helper::resolv_conf_points_to_localhost(
+            "# comment\nnameserver 127.0.0.1\nnameserver ::1\n"
+        ));
+        assert!(!helper::resolv_conf_points_to_localhost("nameserver 127.0.0.53\n"));
+        assert!(!helper::resolv_conf_points_to_localhost(
+            "nameserver 127.0.0.1\nnameserver 223.5.5.5\n"
+        ));
+        assert!(!helper::resolv_conf_points_to_localhost("nameserver 1.1.1.1\n"));
+        assert!(!helper::resolv_conf_points_to_localhost(""));
+        assert!(!helper::resolv_conf_points_to_localhost(helper::DEFAULT_RESOLV_CONF));
+    }
+
+    #[test]
+    fn test_runtime_info() {
+        let generated: serde_yaml::Value = serde_yaml::from_str(
+            "mode: rule\nmixed-port: 7890\ntun:\n  enable: true\n  stack: system\ndns:\n  enable: true\n  listen: 0.0.0.0:5353\n",
+        )
+        .unwrap();
+        // 模式在运行时切换过，以 /configs 为准
+        let running: serde_yaml::Value = serde_json::from_str(
+            r#"{"mode": "global", "mixed-port": 7891, "port": 0, "tun": {"enable": true, "stack": "gVisor"}}"#,
+        )
+        .unwrap();
+        let info = control::RuntimeInfo::new(&running, &generated);
+        assert_eq!(info.mode.as_deref(), Some("global"));
+        assert_eq!(info.mixed_port, Some(7891));
+        assert!(info.tun_enable);
+        assert_eq!(info.tun_stack.as_deref(), Some("gVisor"));
+        assert_eq!(info.dns_listen.as_deref(), Some("0.0.0.0:5353"));
+
+        // /configs 中没有 tun 时使用运行配置
+        let running: serde_yaml::Value = serde_json::from_str(r#"{"mode": "rule"}"#).unwrap();
+        let info = control::RuntimeInfo::new(&running, &generated);
+        assert_eq!(info.tun_stack.as_deref(), Some("system"));
+        assert_eq!(info.mixed_port, None);
+
+        //关闭的 DNS 不返回监听地址，缺少的字段为空
+        let generated: serde_yaml::Value =
+            serde_yaml::from_str("dns:\n  enable: false\n  listen: 0.0.0.0:5353\n").unwrap();
+        let empty = serde_yaml::Value::Mapping(Default::default());
+        assert_eq!(
+            control::RuntimeInfo::new(&empty, &generated),
+            control::RuntimeInfo::default()
+        );
+    }
+
+    #[test]
+    fn test_json_logger() {
+        use crate::logger::{JsonLogger, LogFormat};
+        use log::Log;
+        use std::str::FromStr;
+
+        assert_eq!(LogFormat::from_str("JSON"), Ok(LogFormat::Json));
+        assert_eq!(LogFormat::from_str("text"), Ok(LogFormat::Text));
+        assert!(LogFormat::from_str("xml").is_err());
+
+        let dir = std::env::temp_dir().join(format!("tomoon-json-log-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("tomoon.log");
+        let file = helper::create_append_file(&path).unwrap();
+        let json_logger = JsonLogger::new(log::LevelFilter::Info, file);
+        let records = [
+            (log::Level::Info, "first \"line\"\n"),
+            (log::Level::Debug, "hidden"),
+        ];
+        for (level, message) in records {
+            json_logger.log(
+                &log::Record::builder()
+                    .level(level)
+                    .target("tomoon::test")
+                    .args(format_args!("{}", message))
+                    .build(),
+            );
+        }
+        json_logger.flush();
+
+        //低于级别的日志不写入，换行被转义，每条日志只占一行
+        let content = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = content.lines().collect();
+        assert_eq!(lines.len(), 1);
+        let record: serde_json::Value = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!(record["level"], "INFO");
+        assert_eq!(record["target"], "tomoon::test");
+        assert_eq!(record["message"], "first \"line\"\n");
+        assert!(record["time"].as_u64().unwrap() > 0);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_swap_core_restores_backup() {
+        let dir = std::env::temp_dir().join(format!("tomoon-swap-core-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let core = dir.join("clash");
+        std::fs::write(&core, "old").unwrap();
+
+        //新核心不存在时替换失败，旧的核心放回原处
+        assert!(control::swap_core(&dir.join("clash.download"), &core).is_err());
+        assert_eq!(std::fs::read_to_string(&core).unwrap(), "old");
+        assert!(!dir.join("clash.bak").exists());
+
+        std::fs::write(dir.join("clash.download"), "new").unwrap();
+        control::swap_core(&dir.join("clash.download"), &core).unwrap();
+        assert_eq!(std::fs::read_to_string(&core).unwrap(), "new");
+        assert_eq!(std::fs::read_to_string(dir.join("clash.bak")).unwrap(), "old");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_preview_config_skips_providers() {
+        let dir = std::env::temp_dir().join(format!("tomoon-preview-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = dir.join("config.yaml");
+        std::fs::write(
+            &config,
+            "proxies: []\nrule-providers:\n  ads:\n    type: http\n    behavior: domain\n    \
+             url: http://127.0.0.1:1/ads.yaml\n    path: ./ruleset/ads.yaml\n    interval: 86400\n\
+             rules:\n  - MATCH,DIRECT\n",
+        )
+        .unwrap();
+        let mut clash = control::Clash {
+            core_override: Some(dir.join("clash")),
+            provider_dir: dir.join("providers"),
+            ..Default::default()
+        };
+
+        //预览不下载 Provider，地址无法访问也能返回改写后的配置
+        let yaml = clash
+            .preview_config(&config.to_string_lossy().to_string(), &Settings::default())
+            .unwrap();
+        assert!(yaml.contains("http://127.0.0.1:1/ads.yaml"));
+        assert!(!dir.join("providers").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_provider_path_outside_base() {
+        let base = std::env::temp_dir().join("tomoon_test_provider_paths");
+        for path in ["/etc/cron.d/evil", "./../../etc/passwd", "ruleset/../../x.yaml"] {
+            let config = format!(
+                "rule-providers:\n  evil:\n    type: http\n    behavior: domain\n    \
+                 url: https://example.com/evil.yaml\n    path: {}\n",
+                path
+            );
+            let mut yaml: Value = serde_yaml::from_str(&config).unwrap();
+            let err = control::rewrite_provider_paths(&mut yaml, &base).unwrap_err();
+            assert_eq!(err.kind, control::ClashErrorKind::ConfigFormatError);
+            //没有改写的配置中同样拒绝，不会下载
+            let err = control::provider_jobs(&yaml, &base).unwrap_err();
+            assert_eq!(err.kind, control::ClashErrorKind::ConfigFormatError);
+        }
+    }
+
+    #[test]
+    fn test_refresh_provider_not_modified() {
+        use std::io::{Read, Write};
+
+        let base = std::env::temp_dir().join(format!("tomoon-refresh-{}", std::process::id()));
+        fs::create_dir_all(base.join("ruleset")).unwrap();
+        fs::write(base.join("ruleset/ads.yaml"), "payload: []\n").unwrap();
+        // 只返回 304 的服务器
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            if let Ok((mut stream, _)) = listener.accept() {
+                let mut buf = [0u8; 1024];
+                let _ = stream.read(&mut buf);
+                let _ = stream.write_all(b"HTTP/1.1 304 Not Modified\r\nContent-Length: 0\r\n\r\n");
+            }
+        });
+        let run_config = base.join("running_config.yaml");
+        fs::write(
+            &run_config,
+            format!(
+                "rule-providers:\n  ads:\n    type: http\n    behavior: domain\n    \
+                 url: http://127.0.0.1:{}/ads.yaml\n    path: {}\n    interval: 0\n",
+                port,
+                base.join("ruleset/ads.yaml").display()
+            ),
+        )
+        .unwrap();
+
+        //内容没有变化时不需要重新加载
+        let options = helper::DownloadOptions::from_settings(&Settings::default());
+        let updated = control::refresh_stale_providers(&run_config, &base, &options).unwrap();
+        assert!(!updated);
+        assert_eq!(fs::read_to_string(base.join("ruleset/ads.yaml")).unwrap(), "payload: []\n");
+        let _ = fs::remove_dir_all(&base);
+    }
 }
@@ -36,10 +36,122 @@ export async function setEnabled(value: boolean): Promise<boolean> {
     return (await call_backend("set_clash_status", [value]))[0];
 }
 
+// 离线时返回 [false, ApiError]，kind 为 offline，skipPreflight 为 true 时跳过网络检查
+export async function startClash(skipPreflight?: boolean): Promise<any[]> {
+    return await call_backend("set_clash_status", [true, skipPreflight ?? false]);
+}
+
 export async function getEnabled(): Promise<boolean> {
     return (await call_backend("get_clash_status", []))[0];
 }
 
+export async function getCoreCrash(): Promise<any> {
+    return JSON.parse((await call_backend("get_clash_status", []))[1]);
+}
+
+export async function getSettings(): Promise<any> {
+    return JSON.parse((await call_backend("get_settings", []))[0]);
+}
+
+export async function getSettingsSchema(): Promise<any[]> {
+    return JSON.parse((await call_backend("get_settings_schema", []))[0]);
+}
+
+export async function updateSettings(settings: any): Promise<any[]> {
+    return await call_backend("update_settings", [JSON.stringify(settings)]);
+}
+
+// NotRunning / Unresponsive / Healthy
+export async function getClashHealth(): Promise<String> {
+    return (await call_backend("get_clash_health", []))[0];
+}
+
+// { core: string | null, backend: string }
+export async function getCoreVersion(): Promise<any> {
+    return JSON.parse((await call_backend("get_core_version", []))[0]);
+}
+
+export async function getDashboardUrl(): Promise<string> {
+    return (await call_backend("get_dashboard_url", []))[0];
+}
+
+export async function getActiveConfigInfo(): Promise<any[]> {
+    return await call_backend("get_active_config_info", []);
+}
+
+export async function getRuntimeInfo(): Promise<any[]> {
+    return await call_backend("get_runtime_info", []);
+}
+
+export async function repairDns(): Promise<any[]> {
+    return await call_backend("repair_dns", []);
+}
+
+export async function startClashVerbose(): Promise<number> {
+    return (await call_backend("start_clash_verbose", []))[0];
+}
+
+export async function getStartProgress(id: number): Promise<String> {
+    return (await call_backend("get_start_progress", [id]))[0];
+}
+
+export async function reloadClashConfig(value?: String): Promise<any[]> {
+    return (await call_backend("reload_clash_config", value === undefined ? [] : [value]));
+}
+
+// 失败时后端返回的错误，kind 为 snake_case 的 ClashErrorKind
+export type ApiError = {
+    kind: string,
+    message: string,
+};
+
+export function parseApiError(value?: String): ApiError | undefined {
+    if (!value) {
+        return undefined;
+    }
+    try {
+        return JSON.parse(value as string) as ApiError;
+    } catch {
+        return undefined;
+    }
+}
+
+export async function setProxyMode(mode: string): Promise<boolean> {
+    return (await call_backend("set_proxy_mode", [mode]))[0];
+}
+
+export async function getProxies(): Promise<any[]> {
+    return await call_backend("get_proxies", []);
+}
+
+export async function getTraffic(): Promise<any[]> {
+    return await call_backend("get_traffic", []);
+}
+
+export async function getRules(): Promise<any[]> {
+    return await call_backend("get_rules", []);
+}
+
+export async function getConnections(): Promise<any[]> {
+    return await call_backend("get_connections", []);
+}
+
+export async function closeConnection(id: string): Promise<any[]> {
+    return await call_backend("close_connection", [id]);
+}
+
+export async function selectProxy(group: string, node: string): Promise<boolean> {
+    return (await call_backend("select_proxy", [group, node]))[0];
+}
+
+export async function testProxyDelay(node: string, testUrl?: string, timeout?: number): Promise<any[]> {
+    return await call_backend("test_proxy_delay", [node, testUrl ?? "", timeout ?? 0]);
+}
+
+export async function restartClash(): Promise<boolean> {
+    return (await call_backend("restart_clash", []))[0];
+}
+
 export async function resetNetwork(): Promise<any[]> {
     return (await call_backend("reset_network", []));
 }
@@ -48,8 +160,50 @@ export async function downloadSub(value: String): Promise<any[]> {
     return (await call_backend("download_sub", [value]))[0];
 }
 
-export async function getDownloadStatus(): Promise<String> {
-    return (await call_backend("get_download_status", []))[0];
+export async function probeSub(value: String): Promise<String> {
+    return (await call_backend("probe_sub", [value]))[0];
+}
+
+export async function addSubscription(url: string): Promise<boolean> {
+    return (await call_backend("add_subscription", [url]))[0];
+}
+
+export async function addConfigFromText(name: string, text: string): Promise<any[]> {
+    return await call_backend("add_config_from_text", [name, text]);
+}
+
+export async function listSubscriptions(): Promise<String> {
+    return (await call_backend("list_subscriptions", []))[0];
+}
+
+export async function deleteSubscription(id: string): Promise<boolean> {
+    return (await call_backend("delete_subscription", [id]))[0];
+}
+
+// interval 为秒，不传时使用全局默认值
+export async function setSubscriptionInterval(id: string, interval?: number): Promise<boolean> {
+    const args: any[] = interval === undefined ? [id] : [id, interval];
+    return (await call_backend("set_subscription_interval", args))[0];
+}
+
+export async function resetAll(deleteSubscriptions: boolean = false): Promise<boolean> {
+    return (await call_backend("reset_all", [deleteSubscriptions]))[0];
+}
+
+export async function exportProfile(): Promise<any[]> {
+    return await call_backend("export_profile", []);
+}
+
+export async function importProfile(path: string, replace: boolean = false): Promise<boolean> {
+    return (await call_backend("import_profile", [path, replace]))[0];
+}
+
+export async function getSubscriptionDownloadStatus(): Promise<String> {
+    return (await call_backend("get_subscription_download_status", []))[0];
+}
+
+export async function getDownloadProgress(): Promise<String> {
+    return (await call_backend("get_download_progress", []))[0];
 }
 
 export async function getSubList(): Promise<String> {
@@ -68,14 +222,63 @@ export async function updateSubs(): Promise<any> {
     return (await call_backend("update_subs", []))[0];
 }
 
+export async function updateClashCore(url: string, sha256: string): Promise<boolean> {
+    return (await call_backend("update_clash_core", [url, sha256]))[0];
+}
+
 export async function getUpdateStatus(): Promise<String> {
     return (await call_backend("get_update_status", []))[0];
 }
 
+export async function getCoreUpdateStatus(): Promise<String> {
+    return (await call_backend("get_core_update_status", []))[0];
+}
+
+export async function updateGeoDatabase(): Promise<boolean> {
+    return (await call_backend("update_geo_database", []))[0];
+}
+
+export async function getGeoUpdateStatus(): Promise<String> {
+    return (await call_backend("get_geo_update_status", []))[0];
+}
+
 export async function createDebugLog(): Promise<boolean> {
     return (await call_backend("create_debug_log", []))[0];
 }
 
+export async function getClashLogs(lines?: number): Promise<String> {
+    return (await call_backend("get_clash_logs", lines === undefined ? [] : [lines]))[0];
+}
+
+// 后端日志文件的最后几行，json 字符串数组
+export async function getBackendLogs(lines?: number): Promise<String> {
+    return (await call_backend("get_backend_logs", lines === undefined ? [] : [lines]))[0];
+}
+
+export async function getBackendLog(): Promise<String> {
+    return (await call_backend("get_backend_log", []))[0];
+}
+
 export async function getRunningStatus(): Promise<String> {
     return (await call_backend("get_running_status", []))[0];
+}
+
+export async function getLastError(): Promise<String | undefined> {
+    return (await call_backend("get_running_status", []))[1];
+}
+
+export async function previewConfig(configPath?: string): Promise<any[]> {
+    return await call_backend("preview_config", [configPath ?? ""]);
+}
+
+export async function getMixedPort(): Promise<number | undefined> {
+    return (await call_backend("get_mixed_port", []))[0];
+}
+
+export async function getProvidersStatus(): Promise<String> {
+    return (await call_backend("get_providers_status", []))[0];
+}
+
+export async function getActiveInterface(): Promise<String> {
+    return (await call_backend("get_active_interface", []))[0];
 }
\ No newline at end of file
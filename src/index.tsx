@@ -13,7 +13,7 @@ import {
   Navigation,
   DropdownItem,
 } from "decky-frontend-lib";
-import { VFC, useState } from "react";
+import { VFC, useEffect, useState } from "react";
 import { GiEgyptianBird } from "react-icons/gi";
 
 import {
@@ -27,6 +27,8 @@ import axios from "axios";
 
 let enabledGlobal = false;
 let enabledSkipProxy = false;
+// 离线提示后再次开启时跳过网络检查
+let offlineConfirmed = false;
 let usdplReady = false;
 let subs: any[];
 let subs_option: any[];
@@ -57,6 +59,15 @@ const Content: VFC<{ serverAPI: ServerAPI }> = ({ }) => {
   const [isSelectionDisabled, setIsSelectionDisabled] = useState(false);
   const [SelectionTips, setSelectionTips] = useState("Run Clash in background");
   const [skipProxyState, setSkipProxyState] = useState(enabledSkipProxy);
+  // 核心在运行中意外退出时显示原因，只在打开面板时检查一次
+  useEffect(() => {
+    backend.resolve(backend.getCoreCrash(), (v: any) => {
+      if (v && !v.restarted) {
+        let reason = v.code != null ? `exit code ${v.code}` : `signal ${v.signal}`;
+        setSelectionTips(`Clash crashed (${reason}), please check /tmp/tomoon.clash.log`);
+      }
+    });
+  }, []);
 
   const update_subs = () => {
     backend.resolve(backend.getSubList(), (v: String) => {
@@ -98,10 +109,27 @@ const Content: VFC<{ serverAPI: ServerAPI }> = ({ }) => {
             onChange={(value: boolean) => {
               setIsSelectionDisabled(true);
               setSelectionTips("Loading ...");
-              backend.resolve(backend.setEnabled(value), (v: boolean) => {
-                enabledGlobal = v;
-                setIsSelectionDisabled(false);
-              });
+              if (value) {
+                //离线时第一次只提示，再次开启时跳过检查
+                backend.resolve(backend.startClash(offlineConfirmed), (v: any[]) => {
+                  setIsSelectionDisabled(false);
+                  if (!v[0] && backend.parseApiError(v[1])?.kind == "offline") {
+                    offlineConfirmed = true;
+                    setSelectionTips("No network connection, enable again to start anyway");
+                    setClashState(false);
+                    setOptionDropdownDisabled(false);
+                    setOpenDashboardDisabled(true);
+                    return;
+                  }
+                  offlineConfirmed = false;
+                  enabledGlobal = v[0];
+                });
+              } else {
+                backend.resolve(backend.setEnabled(value), (v: boolean) => {
+                  enabledGlobal = v;
+                  setIsSelectionDisabled(false);
+                });
+              }
               //获取 Clash 启动状态
               if (!clashState) {
                 let check_running_handle = setInterval(() => {
@@ -166,7 +194,9 @@ const Content: VFC<{ serverAPI: ServerAPI }> = ({ }) => {
             layout="below"
             onClick={() => {
               Router.CloseSideMenus()
-              Navigation.NavigateToExternalWeb("http://127.0.0.1:9090/ui")
+              backend.resolve(backend.getDashboardUrl(), (url: string) => {
+                Navigation.NavigateToExternalWeb(url)
+              });
               //Router.NavigateToExternalWeb("http://127.0.0.1:9090/ui")
             }}
             disabled={openDashboardDisabled}
@@ -25,7 +25,7 @@ export const Subscriptions: VFC<SubProp> = ({ Subscriptions }) => {
     let checkUpdateStatusHandler: any;
 
     const refreshDownloadStatus = () => {
-        backend.resolve(backend.getDownloadStatus(), (v: any) => {
+        backend.resolve(backend.getSubscriptionDownloadStatus(), (v: any) => {
             let response = v.toString();
             switch (response) {
                 case "Downloading":